@@ -0,0 +1,123 @@
+//! Pluggable framings, i.e. ways of delimiting and encoding messages on a byte stream.
+//!
+//! A [`Framing`] can be passed to [`write_queue_with_framing()`](crate::write_queue_with_framing)
+//! and to `capnp_rpc::twoparty::VatNetwork::new_with_framing()` to change how messages are
+//! written to and read from a connection, e.g. to compress them.
+
+use std::future::Future;
+
+use capnp::serialize::OwnedSegments;
+use capnp::{message, Result};
+use futures_util::{AsyncRead, AsyncWrite};
+
+use crate::serialize::AsOutputSegments;
+
+/// A way of reading and writing messages on a byte stream.
+///
+/// Both ends of a connection must use the same framing.
+pub trait Framing {
+    /// Reads the next message from `reader`, returning `None` on a clean end-of-file.
+    fn try_read_message<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin;
+
+    /// Like `try_read_message()`, but charges the message's memory against `quota`, returning
+    /// an `Overloaded` error if the quota is exhausted.
+    ///
+    /// The default implementation charges the message after reading it. Framings that learn a
+    /// message's size before allocating its buffer should override this to charge up front.
+    fn try_read_message_with_quota<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+        quota: &message::MemoryQuota,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        let quota = quota.clone();
+        let read = self.try_read_message(reader, options);
+        async move {
+            let Some(message) = read.await? else {
+                return Ok(None);
+            };
+            let mut segments = message.into_segments();
+            segments.charge_to(&quota)?;
+            Ok(Some(message::Reader::new(segments, options)))
+        }
+    }
+
+    /// Writes `message` to `writer`. Does not call `flush()`.
+    fn write_message<W, M>(&self, writer: W, message: M) -> impl Future<Output = Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+        M: AsOutputSegments;
+}
+
+/// The [standard stream framing](https://capnproto.org/encoding.html#serialization-over-a-stream),
+/// as implemented by [`crate::serialize`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Standard;
+
+impl Framing for Standard {
+    fn try_read_message<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        crate::serialize::try_read_message(reader, options)
+    }
+
+    fn try_read_message_with_quota<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+        quota: &message::MemoryQuota,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        crate::serialize::try_read_message_with_quota(reader, options, quota)
+    }
+
+    fn write_message<W, M>(&self, writer: W, message: M) -> impl Future<Output = Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+        M: AsOutputSegments,
+    {
+        crate::serialize::write_message(writer, message)
+    }
+}
+
+/// The [packed stream encoding](https://capnproto.org/encoding.html#packing),
+/// as implemented by [`crate::serialize_packed`].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Packed;
+
+impl Framing for Packed {
+    fn try_read_message<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        crate::serialize_packed::try_read_message(reader, options)
+    }
+
+    fn write_message<W, M>(&self, writer: W, message: M) -> impl Future<Output = Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+        M: AsOutputSegments,
+    {
+        crate::serialize_packed::write_message(writer, message)
+    }
+}
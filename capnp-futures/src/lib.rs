@@ -19,9 +19,14 @@
 // THE SOFTWARE.
 
 pub use read_stream::ReadStream;
-pub use write_queue::{write_queue, Sender};
+pub use write_queue::{
+    bounded_write_queue, write_queue, write_queue_with_framing, QueueLimits, Sender,
+};
 
+pub mod framing;
 mod read_stream;
 pub mod serialize;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod serialize_compressed;
 pub mod serialize_packed;
 mod write_queue;
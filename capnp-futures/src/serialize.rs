@@ -26,6 +26,8 @@
 use capnp::serialize::{OwnedSegments, SegmentLengthsBuilder};
 use capnp::{message, Error, OutputSegments, Result};
 use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use std::io::IoSlice;
+use std::pin::Pin;
 
 /// Asynchronously reads a message from `reader`.
 pub async fn read_message<R>(
@@ -47,8 +49,49 @@ where
 /// To read a stream containing an unknown number of messages, you could call
 /// this function repeatedly until it returns `None`.
 pub async fn try_read_message<R>(
+    reader: R,
+    options: message::ReaderOptions,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: AsyncRead + Unpin,
+{
+    try_read_message_internal(reader, options, None).await
+}
+
+/// Like `read_message()`, but charges the message's buffer against `quota` before allocating it.
+/// The charge is released when the message is dropped. If the quota does not have room for the
+/// message, returns an `Overloaded` error without reading the message's segments.
+pub async fn read_message_with_quota<R>(
+    reader: R,
+    options: message::ReaderOptions,
+    quota: &message::MemoryQuota,
+) -> Result<message::Reader<OwnedSegments>>
+where
+    R: AsyncRead + Unpin,
+{
+    match try_read_message_with_quota(reader, options, quota).await? {
+        Some(s) => Ok(s),
+        None => Err(Error::from_kind(capnp::ErrorKind::PrematureEndOfFile)),
+    }
+}
+
+/// Like `try_read_message()`, but charges the message's buffer against `quota`. See
+/// [`read_message_with_quota()`].
+pub async fn try_read_message_with_quota<R>(
+    reader: R,
+    options: message::ReaderOptions,
+    quota: &message::MemoryQuota,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: AsyncRead + Unpin,
+{
+    try_read_message_internal(reader, options, Some(quota)).await
+}
+
+async fn try_read_message_internal<R>(
     mut reader: R,
     options: message::ReaderOptions,
+    quota: Option<&message::MemoryQuota>,
 ) -> Result<Option<message::Reader<OwnedSegments>>>
 where
     R: AsyncRead + Unpin,
@@ -56,14 +99,11 @@ where
     let Some(segment_lengths_builder) = read_segment_table(&mut reader, options).await? else {
         return Ok(None);
     };
-    Ok(Some(
-        read_segments(
-            reader,
-            segment_lengths_builder.into_owned_segments(),
-            options,
-        )
-        .await?,
-    ))
+    let owned_segments = match quota {
+        Some(quota) => segment_lengths_builder.into_owned_segments_with_quota(quota)?,
+        None => segment_lengths_builder.into_owned_segments(),
+    };
+    Ok(Some(read_segments(reader, owned_segments, options).await?))
 }
 
 async fn read_segment_table<R>(
@@ -208,6 +248,31 @@ where
     Ok(())
 }
 
+/// Like `write_message()`, but writes the segment table and all of the segments with
+/// `poll_write_vectored()`, so that a writer that supports vectored I/O can send the whole
+/// message in a single system call, without copying it. Does not call `flush()`.
+pub async fn write_message_vectored<W, M>(mut writer: W, message: M) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    M: AsOutputSegments,
+{
+    let segments = message.as_output_segments();
+    let table = segments.segment_table();
+    let mut slices: Vec<IoSlice> = segments.io_slices(&table).collect();
+    let mut slices = &mut slices[..];
+    while !slices.is_empty() {
+        let n = futures_util::future::poll_fn(|cx| {
+            Pin::new(&mut writer).poll_write_vectored(cx, slices)
+        })
+        .await?;
+        if n == 0 {
+            return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into());
+        }
+        IoSlice::advance_slices(&mut slices, n);
+    }
+    Ok(())
+}
+
 async fn write_segment_table<W>(mut write: W, segments: &[&[u8]]) -> ::std::io::Result<()>
 where
     W: AsyncWrite + Unpin,
@@ -278,7 +343,10 @@ pub mod test {
     use capnp::message::ReaderSegments;
     use capnp::{message, OutputSegments};
 
-    use super::{read_segment_table, try_read_message, write_message, AsOutputSegments};
+    use super::{
+        read_segment_table, try_read_message, try_read_message_with_quota, write_message,
+        write_message_vectored, AsOutputSegments,
+    };
 
     #[test]
     fn test_read_segment_table() {
@@ -431,6 +499,48 @@ pub mod test {
         buf.clear();
     }
 
+    #[test]
+    fn read_message_with_quota() {
+        let mut message = message::Builder::new_default();
+        message.set_root::<capnp::text::Owned>("hello").unwrap();
+        let mut buf = vec![];
+        capnp::serialize::write_message(&mut buf, &message).unwrap();
+        capnp::serialize::write_message(&mut buf, &message).unwrap();
+        let message_bytes = message.get_segments_for_output()[0].len();
+
+        let quota = message::MemoryQuota::new(message_bytes + 4);
+        let mut read = &buf[..];
+        let first = futures::executor::block_on(try_read_message_with_quota(
+            &mut read,
+            message::ReaderOptions::new(),
+            &quota,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(quota.used(), message_bytes);
+
+        // The second message does not fit while the first one is alive.
+        let err = futures::executor::block_on(try_read_message_with_quota(
+            &mut &read[..],
+            message::ReaderOptions::new(),
+            &quota,
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.kind, capnp::ErrorKind::Overloaded);
+
+        drop(first);
+        assert_eq!(quota.used(), 0);
+        let second = futures::executor::block_on(try_read_message_with_quota(
+            &mut read,
+            message::ReaderOptions::new(),
+            &quota,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(second.get_root::<capnp::text::Reader>().unwrap(), "hello");
+    }
+
     fn construct_segment_table(segments: &[&[u8]]) -> Vec<u8> {
         let mut exec = futures::executor::LocalPool::new();
         let mut buf = vec![];
@@ -686,4 +796,29 @@ pub mod test {
 
         quickcheck(round_trip as fn(usize, usize, Vec<Vec<capnp::Word>>) -> TestResult);
     }
+
+    #[cfg_attr(miri, ignore)] // Miri takes a long time with quickcheck
+    #[test]
+    fn check_write_message_vectored() {
+        fn write_vectored(
+            write_blocking_period: usize,
+            segments: Vec<Vec<capnp::Word>>,
+        ) -> TestResult {
+            if segments.is_empty() || write_blocking_period == 0 {
+                return TestResult::discard();
+            }
+            let mut expected = Vec::new();
+            futures::executor::block_on(Box::pin(write_message(&mut expected, &segments)))
+                .expect("writing");
+
+            let cursor = std::io::Cursor::new(Vec::new());
+            let mut writer = BlockingWrite::new(cursor, write_blocking_period);
+            futures::executor::block_on(Box::pin(write_message_vectored(&mut writer, &segments)))
+                .expect("writing vectored");
+
+            TestResult::from_bool(writer.into_writer().into_inner() == expected)
+        }
+
+        quickcheck(write_vectored as fn(usize, Vec<Vec<capnp::Word>>) -> TestResult);
+    }
 }
@@ -0,0 +1,245 @@
+//! Asynchronous reading and writing of messages using the compressed framing
+//! defined in [`capnp::serialize_compressed`].
+
+use std::future::Future;
+
+use capnp::serialize::OwnedSegments;
+use capnp::serialize_compressed::{Compression, FrameHeader, FRAME_HEADER_BYTES};
+use capnp::{message, Result};
+use futures_util::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::framing::Framing;
+use crate::serialize::AsOutputSegments;
+
+/// Asynchronously reads a compressed message from `reader`.
+///
+/// Returns `None` if `reader` has zero bytes left (i.e. is at end-of-file).
+pub async fn try_read_message<R>(
+    mut reader: R,
+    options: message::ReaderOptions,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(header) = try_read_header(&mut reader, options).await? else {
+        return Ok(None);
+    };
+    Ok(Some(read_payload(reader, &header, options).await?))
+}
+
+/// Like `try_read_message()`, but charges the message against `quota`. The decompressed size
+/// is taken from the frame header and charged before any buffer is allocated, so a frame that
+/// does not fit in the quota fails with an `Overloaded` error without its payload being read.
+pub async fn try_read_message_with_quota<R>(
+    mut reader: R,
+    options: message::ReaderOptions,
+    quota: &message::MemoryQuota,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: AsyncRead + Unpin,
+{
+    let Some(header) = try_read_header(&mut reader, options).await? else {
+        return Ok(None);
+    };
+    let reservation = quota.try_reserve(header.uncompressed_len)?;
+    let mut segments = read_payload(reader, &header, options)
+        .await?
+        .into_segments();
+    segments.set_reservation(reservation);
+    Ok(Some(message::Reader::new(segments, options)))
+}
+
+async fn try_read_header<R>(
+    mut reader: R,
+    options: message::ReaderOptions,
+) -> Result<Option<FrameHeader>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut header = [0; FRAME_HEADER_BYTES];
+    {
+        let n = reader.read(&mut header[..]).await?;
+        if n == 0 {
+            return Ok(None);
+        } else if n < FRAME_HEADER_BYTES {
+            reader.read_exact(&mut header[n..]).await?;
+        }
+    }
+    Ok(Some(FrameHeader::parse(&header, options)?))
+}
+
+async fn read_payload<R>(
+    mut reader: R,
+    header: &FrameHeader,
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut payload = vec![0u8; header.compressed_len];
+    reader.read_exact(&mut payload).await?;
+    capnp::serialize_compressed::decode_message(header, &payload, options)
+}
+
+/// Asynchronously reads a compressed message from `reader`.
+pub async fn read_message<R>(
+    reader: R,
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>>
+where
+    R: AsyncRead + Unpin,
+{
+    match try_read_message(reader, options).await? {
+        Some(s) => Ok(s),
+        None => Err(capnp::Error::from_kind(
+            capnp::ErrorKind::PrematureEndOfFile,
+        )),
+    }
+}
+
+/// Writes the provided message to `writer`, compressed with `compression`.
+/// Does not call `flush()`.
+pub async fn write_message<W, M>(mut writer: W, message: M, compression: Compression) -> Result<()>
+where
+    W: AsyncWrite + Unpin,
+    M: AsOutputSegments,
+{
+    let frame =
+        capnp::serialize_compressed::encode_message(&message.as_output_segments(), compression)?;
+    writer.write_all(&frame).await?;
+    Ok(())
+}
+
+/// A [`Framing`] that compresses each message with the given algorithm.
+/// The reading side accepts any algorithm enabled in the `capnp` crate.
+#[derive(Clone, Copy, Debug)]
+pub struct Compressed(pub Compression);
+
+impl Framing for Compressed {
+    fn try_read_message<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        try_read_message(reader, options)
+    }
+
+    fn try_read_message_with_quota<R>(
+        &self,
+        reader: R,
+        options: message::ReaderOptions,
+        quota: &message::MemoryQuota,
+    ) -> impl Future<Output = Result<Option<message::Reader<OwnedSegments>>>>
+    where
+        R: AsyncRead + Unpin,
+    {
+        try_read_message_with_quota(reader, options, quota)
+    }
+
+    fn write_message<W, M>(&self, writer: W, message: M) -> impl Future<Output = Result<()>>
+    where
+        W: AsyncWrite + Unpin,
+        M: AsOutputSegments,
+    {
+        write_message(writer, message, self.0)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use capnp::message;
+    use capnp::message::ReaderSegments;
+    use capnp::serialize_compressed::Compression;
+
+    use super::{read_message, try_read_message, try_read_message_with_quota, write_message};
+    use crate::serialize::test::{BlockingRead, BlockingWrite};
+
+    #[test]
+    fn round_trip() {
+        let mut message = message::Builder::new_default();
+        {
+            let root: capnp::any_pointer::Builder = message.init_root();
+            let mut list: capnp::text_list::Builder = root.initn_as(10);
+            for i in 0..10 {
+                list.set(i, "some text that compresses well");
+            }
+        }
+        let compressions = [
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 1 },
+        ];
+        for compression in compressions {
+            let mut writer = BlockingWrite::new(std::io::Cursor::new(Vec::new()), 7);
+            futures::executor::block_on(write_message(&mut writer, &message, compression)).unwrap();
+            let mut cursor = writer.into_writer();
+            cursor.set_position(0);
+
+            let mut reader = BlockingRead::new(cursor, 5);
+            let result =
+                futures::executor::block_on(read_message(&mut reader, Default::default())).unwrap();
+            let segments = result.into_segments();
+            let expected = message.get_segments_for_output();
+            assert_eq!(segments.len(), expected.len());
+            for (i, segment) in expected.iter().enumerate() {
+                assert_eq!(segments.get_segment(i as u32).unwrap(), *segment);
+            }
+            assert!(
+                futures::executor::block_on(try_read_message(&mut reader, Default::default()))
+                    .unwrap()
+                    .is_none()
+            );
+        }
+    }
+
+    #[cfg(feature = "lz4")]
+    #[test]
+    fn quota_is_charged_before_reading_the_payload() {
+        use capnp::serialize_compressed::{FrameHeader, FRAME_HEADER_BYTES};
+
+        let mut message = message::Builder::new_default();
+        message
+            .set_root::<capnp::text::Owned>(&"x".repeat(1000))
+            .unwrap();
+        let mut frame = Vec::new();
+        futures::executor::block_on(write_message(&mut frame, &message, Compression::Lz4)).unwrap();
+        let header = FrameHeader::parse(
+            &frame[..FRAME_HEADER_BYTES].try_into().unwrap(),
+            Default::default(),
+        )
+        .unwrap();
+
+        let quota = message::MemoryQuota::new(header.uncompressed_len - 1);
+        let mut reader = futures::io::Cursor::new(&frame[..]);
+        let err = futures::executor::block_on(try_read_message_with_quota(
+            &mut reader,
+            Default::default(),
+            &quota,
+        ))
+        .err()
+        .unwrap();
+        assert_eq!(err.kind, capnp::ErrorKind::Overloaded);
+        assert_eq!(reader.position(), FRAME_HEADER_BYTES as u64);
+        assert_eq!(quota.used(), 0);
+
+        let quota = message::MemoryQuota::new(header.uncompressed_len);
+        let result = futures::executor::block_on(try_read_message_with_quota(
+            &frame[..],
+            Default::default(),
+            &quota,
+        ))
+        .unwrap()
+        .unwrap();
+        assert_eq!(quota.used(), header.uncompressed_len);
+        assert_eq!(
+            result.get_root::<capnp::text::Reader>().unwrap(),
+            "x".repeat(1000).as_str()
+        );
+        drop(result);
+        assert_eq!(quota.used(), 0);
+    }
+}
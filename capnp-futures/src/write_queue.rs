@@ -18,30 +18,220 @@
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
 
+use std::collections::VecDeque;
 use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
 
 use futures_channel::oneshot;
+use futures_util::future::Either;
 use futures_util::{AsyncWrite, AsyncWriteExt, StreamExt, TryFutureExt};
 
 use capnp::Error;
 
+use crate::framing::Framing;
 use crate::serialize::AsOutputSegments;
 
 enum Item<M>
 where
     M: AsOutputSegments,
 {
-    Message(M, oneshot::Sender<M>),
+    Message(M, usize, oneshot::Sender<M>),
     Done(Result<(), Error>, oneshot::Sender<()>),
 }
 
+/// Limits on how much a write queue will buffer before [`Sender::send()`] starts waiting.
+///
+/// Messages passed to [`Sender::send_immediately()`] count towards the limits but are never
+/// held back by them. That is how `capnp-rpc`'s two-party network sends its messages, so there
+/// these limits throttle only streaming calls, through [`Sender::ready()`]; ordinary calls and
+/// returns are still queued without bound.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct QueueLimits {
+    /// The maximum number of messages that may be queued but not yet written.
+    /// `None` means no limit.
+    pub max_messages: Option<usize>,
+
+    /// The maximum total size, in bytes, of the messages that may be queued but not yet written.
+    /// `None` means no limit.
+    ///
+    /// A message that is larger than this limit is still accepted once the queue is empty.
+    pub max_bytes: Option<usize>,
+}
+
+impl QueueLimits {
+    /// Returns limits that never make `send()` wait.
+    pub const fn new() -> Self {
+        Self {
+            max_messages: None,
+            max_bytes: None,
+        }
+    }
+
+    pub fn max_messages(&mut self, value: Option<usize>) -> &mut Self {
+        self.max_messages = value;
+        self
+    }
+
+    pub fn max_bytes(&mut self, value: Option<usize>) -> &mut Self {
+        self.max_bytes = value;
+        self
+    }
+}
+
+/// The state that a queue shares with its senders. The counters are atomics so that, while no
+/// limits are set, sending a message never takes a lock.
+struct Shared {
+    messages: AtomicUsize,
+    bytes: AtomicUsize,
+
+    // Set once the queue has stopped processing messages, so that nobody waits on it forever.
+    closed: AtomicBool,
+
+    // Whether senders have to go through `waiting`: set while there are limits, or while
+    // sends are waiting for room.
+    limited: AtomicBool,
+    waiting: Mutex<Waiting>,
+}
+
+struct Waiting {
+    limits: QueueLimits,
+    waiters: Vec<Waker>,
+
+    // Tickets of the sends that are waiting for room, in the order they must be admitted.
+    parked: VecDeque<u64>,
+    next_ticket: u64,
+}
+
+impl Shared {
+    /// Whether a message of `size` bytes may be enqueued without exceeding `limits`.
+    fn has_room_for(&self, limits: &QueueLimits, size: usize) -> bool {
+        let messages = self.messages.load(Ordering::SeqCst);
+        if self.closed.load(Ordering::SeqCst) || messages == 0 {
+            return true;
+        }
+        let messages_ok = match limits.max_messages {
+            Some(max) => messages < max,
+            None => true,
+        };
+        let bytes_ok = match limits.max_bytes {
+            Some(max) => self.bytes.load(Ordering::SeqCst) + size <= max,
+            None => true,
+        };
+        messages_ok && bytes_ok
+    }
+
+    /// Whether the queue is strictly below `limits`.
+    fn is_ready(&self, limits: &QueueLimits) -> bool {
+        self.has_room_for(limits, 1)
+    }
+
+    fn add(&self, size: usize) {
+        if !self.closed.load(Ordering::SeqCst) {
+            self.messages.fetch_add(1, Ordering::SeqCst);
+            self.bytes.fetch_add(size, Ordering::SeqCst);
+        }
+    }
+
+    fn remove(&self, size: usize) {
+        self.messages.fetch_sub(1, Ordering::SeqCst);
+        self.bytes.fetch_sub(size, Ordering::SeqCst);
+        if self.limited.load(Ordering::SeqCst) {
+            self.waiting.lock().unwrap().wake_all();
+        }
+    }
+
+    /// Must be called with `waiting` locked whenever its limits or parked sends change.
+    fn update_limited(&self, waiting: &Waiting) {
+        let limited = waiting.limits != QueueLimits::new() || !waiting.parked.is_empty();
+        self.limited.store(limited, Ordering::SeqCst);
+    }
+}
+
+impl Waiting {
+    fn wake_all(&mut self) {
+        for waker in self.waiters.drain(..) {
+            waker.wake();
+        }
+    }
+}
+
+/// Waits until the queue has room. If `admit` is `Some((ticket, size))`, waits until the send
+/// holding `ticket` is the first one parked, then reserves room for a message of `size` bytes.
+struct WaitForRoom {
+    shared: Arc<Shared>,
+    admit: Option<(u64, usize)>,
+}
+
+impl Future for WaitForRoom {
+    type Output = ();
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        let this = &mut *self;
+        let shared = &*this.shared;
+        let mut waiting = shared.waiting.lock().unwrap();
+        let has_room = match this.admit {
+            Some((ticket, size)) => {
+                shared.closed.load(Ordering::SeqCst)
+                    || (waiting.parked.front() == Some(&ticket)
+                        && shared.has_room_for(&waiting.limits, size))
+            }
+            None => shared.is_ready(&waiting.limits),
+        };
+        if has_room {
+            if let Some((ticket, size)) = this.admit.take() {
+                waiting.parked.retain(|t| *t != ticket);
+                shared.update_limited(&waiting);
+                shared.add(size);
+                // The next parked send may fit too.
+                waiting.wake_all();
+            }
+            Poll::Ready(())
+        } else {
+            if !waiting.waiters.iter().any(|w| w.will_wake(cx.waker())) {
+                waiting.waiters.push(cx.waker().clone());
+            }
+            Poll::Pending
+        }
+    }
+}
+
+impl Drop for WaitForRoom {
+    fn drop(&mut self) {
+        // A send that gives up waiting must not hold up the ones behind it.
+        if let Some((ticket, _)) = self.admit {
+            let mut waiting = self.shared.waiting.lock().unwrap();
+            waiting.parked.retain(|t| *t != ticket);
+            self.shared.update_limited(&waiting);
+            waiting.wake_all();
+        }
+    }
+}
+
+/// Marks the queue as closed when the queue's task completes or is dropped.
+struct CloseOnDrop(Arc<Shared>);
+
+impl Drop for CloseOnDrop {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::SeqCst);
+        self.0.messages.store(0, Ordering::SeqCst);
+        self.0.bytes.store(0, Ordering::SeqCst);
+        self.0.waiting.lock().unwrap().wake_all();
+    }
+}
+
+fn message_size<M: AsOutputSegments>(message: &M) -> usize {
+    message.as_output_segments().iter().map(|s| s.len()).sum()
+}
+
 /// A handle that allows messages to be sent to a write queue.
 pub struct Sender<M>
 where
     M: AsOutputSegments,
 {
     sender: futures_channel::mpsc::UnboundedSender<Item<M>>,
-    in_flight: std::sync::Arc<std::sync::atomic::AtomicI32>,
+    shared: Arc<Shared>,
 }
 
 impl<M> Clone for Sender<M>
@@ -51,7 +241,7 @@ where
     fn clone(&self) -> Self {
         Self {
             sender: self.sender.clone(),
-            in_flight: self.in_flight.clone(),
+            shared: self.shared.clone(),
         }
     }
 }
@@ -62,26 +252,74 @@ where
 /// queue, and `task` is a future that performs the work of the writes. The queue
 /// will run as long as `task` is polled, until either `sender.terminate()` is
 /// called or `sender` and all of its clones are dropped.
-pub fn write_queue<W, M>(mut writer: W) -> (Sender<M>, impl Future<Output = Result<(), Error>>)
+///
+/// The queue is unbounded; see [`bounded_write_queue()`] for a variant that applies
+/// backpressure to senders.
+pub fn write_queue<W, M>(writer: W) -> (Sender<M>, impl Future<Output = Result<(), Error>>)
 where
     W: AsyncWrite + Unpin,
     M: AsOutputSegments,
+{
+    write_queue_with_framing(writer, crate::framing::Standard)
+}
+
+/// Like [`write_queue()`], but once the messages that are waiting to be written exceed
+/// `limits`, [`Sender::send()`] waits for the queue to drain before accepting more.
+///
+/// The limits can be changed later with [`Sender::set_limits()`], which also makes it possible
+/// to bound a queue created by [`write_queue_with_framing()`].
+pub fn bounded_write_queue<W, M>(
+    writer: W,
+    limits: QueueLimits,
+) -> (Sender<M>, impl Future<Output = Result<(), Error>>)
+where
+    W: AsyncWrite + Unpin,
+    M: AsOutputSegments,
+{
+    let (sender, queue) = write_queue(writer);
+    sender.set_limits(limits);
+    (sender, queue)
+}
+
+/// Like [`write_queue()`], but writes messages using `framing` rather than the
+/// standard stream framing.
+pub fn write_queue_with_framing<W, M, F>(
+    mut writer: W,
+    framing: F,
+) -> (Sender<M>, impl Future<Output = Result<(), Error>>)
+where
+    W: AsyncWrite + Unpin,
+    M: AsOutputSegments,
+    F: Framing,
 {
     let (tx, mut rx) = futures_channel::mpsc::unbounded::<Item<M>>();
 
-    let in_flight = std::sync::Arc::new(std::sync::atomic::AtomicI32::new(0));
+    let shared = Arc::new(Shared {
+        messages: AtomicUsize::new(0),
+        bytes: AtomicUsize::new(0),
+        closed: AtomicBool::new(false),
+        limited: AtomicBool::new(false),
+        waiting: Mutex::new(Waiting {
+            limits: QueueLimits::new(),
+            waiters: Vec::new(),
+            parked: VecDeque::new(),
+            next_ticket: 0,
+        }),
+    });
 
     let sender = Sender {
         sender: tx,
-        in_flight: in_flight.clone(),
+        shared: shared.clone(),
     };
 
+    let close_on_drop = CloseOnDrop(shared.clone());
     let queue = async move {
+        let _close_on_drop = close_on_drop;
         while let Some(item) = rx.next().await {
             match item {
-                Item::Message(m, returner) => {
-                    let result = crate::serialize::write_message(&mut writer, &m).await;
-                    in_flight.fetch_sub(1, std::sync::atomic::Ordering::SeqCst);
+                Item::Message(m, size, returner) => {
+                    let result = framing.write_message(&mut writer, &m).await;
+                    shared.remove(size);
                     result?;
                     writer.flush().await?;
                     let _ = returner.send(m);
@@ -120,28 +358,110 @@ where
     M: AsOutputSegments,
 {
     /// Enqueues a message to be written. Returns the message once the write
-    /// has completed. Dropping the returned future does *not* cancel the write.
+    /// has completed.
+    ///
+    /// If the queue is over its [`QueueLimits`], or earlier messages are still waiting for room,
+    /// the message is held by the returned future until enough of the queue has been written out
+    /// and the earlier messages have been enqueued. Dropping the future while it still holds the
+    /// message discards the message. Otherwise the message is enqueued immediately, and dropping
+    /// the returned future does *not* cancel the write.
     pub fn send(&mut self, message: M) -> impl Future<Output = Result<M, Error>> + Unpin {
-        self.in_flight
-            .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+        let size = message_size(&message);
         let (complete, oneshot) = oneshot::channel();
+        let oneshot = oneshot
+            .map_err(|oneshot::Canceled| Error::disconnected("WriteQueue has terminated".into()));
 
-        let _ = self.sender.unbounded_send(Item::Message(message, complete));
+        if self.shared.limited.load(Ordering::SeqCst) {
+            let ticket = {
+                let shared = &*self.shared;
+                let mut waiting = shared.waiting.lock().unwrap();
+                if shared.closed.load(Ordering::SeqCst)
+                    || (waiting.parked.is_empty() && shared.has_room_for(&waiting.limits, size))
+                {
+                    shared.add(size);
+                    None
+                } else {
+                    let ticket = waiting.next_ticket;
+                    waiting.next_ticket += 1;
+                    waiting.parked.push_back(ticket);
+                    shared.update_limited(&waiting);
+                    Some(ticket)
+                }
+            };
+            if let Some(ticket) = ticket {
+                let sender = self.sender.clone();
+                let wait = WaitForRoom {
+                    shared: self.shared.clone(),
+                    admit: Some((ticket, size)),
+                };
+                return Either::Right(Box::pin(async move {
+                    wait.await;
+                    let _ = sender.unbounded_send(Item::Message(message, size, complete));
+                    oneshot.await
+                }));
+            }
+        } else {
+            self.shared.add(size);
+        }
+        let _ = self
+            .sender
+            .unbounded_send(Item::Message(message, size, complete));
+        Either::Left(oneshot)
+    }
 
+    /// Like `send()`, but always enqueues the message immediately, even if that takes the queue
+    /// over its limits or puts it ahead of messages that `send()` is holding back. This is for
+    /// protocols that cannot hold back a message without reordering it relative to others; such
+    /// callers can use [`ready()`](Self::ready) to apply backpressure elsewhere.
+    pub fn send_immediately(
+        &mut self,
+        message: M,
+    ) -> impl Future<Output = Result<M, Error>> + Unpin {
+        let size = message_size(&message);
+        self.shared.add(size);
+        let (complete, oneshot) = oneshot::channel();
+        let _ = self
+            .sender
+            .unbounded_send(Item::Message(message, size, complete));
         oneshot.map_err(|oneshot::Canceled| Error::disconnected("WriteQueue has terminated".into()))
     }
 
+    /// Returns a future that resolves once the queue is within its limits, or once the queue
+    /// has terminated.
+    pub fn ready(&self) -> impl Future<Output = ()> + Unpin {
+        WaitForRoom {
+            shared: self.shared.clone(),
+            admit: None,
+        }
+    }
+
     /// Returns the number of messages queued to be written.
     pub fn len(&self) -> usize {
-        let result = self.in_flight.load(std::sync::atomic::Ordering::SeqCst);
-        assert!(result >= 0);
-        result as usize
+        self.shared.messages.load(Ordering::SeqCst)
     }
 
     pub fn is_empty(&self) -> bool {
         self.len() == 0
     }
 
+    /// Returns the total size, in bytes, of the messages queued to be written.
+    pub fn queued_bytes(&self) -> usize {
+        self.shared.bytes.load(Ordering::SeqCst)
+    }
+
+    /// Returns the limits currently applied to the queue.
+    pub fn limits(&self) -> QueueLimits {
+        self.shared.waiting.lock().unwrap().limits
+    }
+
+    /// Changes the limits applied to the queue. This affects all clones of the sender.
+    pub fn set_limits(&self, limits: QueueLimits) {
+        let mut waiting = self.shared.waiting.lock().unwrap();
+        waiting.limits = limits;
+        self.shared.update_limited(&waiting);
+        waiting.wake_all();
+    }
+
     /// Commands the queue to stop writing messages once it is empty. After this method has been called,
     /// any new calls to `send()` will return a future that immediately resolves to an error.
     /// If the passed-in `result` is an error, then the `WriteQueue` will resolve to that error.
@@ -157,3 +477,159 @@ where
             .map_err(|oneshot::Canceled| Error::disconnected("WriteQueue has terminated".into()))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::future::Future;
+    use std::task::{Context, Poll};
+
+    use capnp::message;
+    use futures::io::Cursor;
+    use futures::task::noop_waker_ref;
+
+    use super::{bounded_write_queue, QueueLimits};
+
+    type Message = message::Builder<message::HeapAllocator>;
+
+    fn poll<F: Future + Unpin>(f: &mut F) -> Poll<F::Output> {
+        std::pin::Pin::new(f).poll(&mut Context::from_waker(noop_waker_ref()))
+    }
+
+    fn message() -> Message {
+        let mut message = message::Builder::new_default();
+        message.set_root::<capnp::text::Owned>("hello").unwrap();
+        message
+    }
+
+    #[test]
+    fn send_waits_for_room() {
+        let (mut sender, queue) = bounded_write_queue::<_, Message>(
+            Cursor::new(Vec::new()),
+            *QueueLimits::new().max_messages(Some(2)),
+        );
+        let mut queue = Box::pin(queue);
+
+        let mut first = sender.send(message());
+        let mut second = sender.send(message());
+        let mut third = sender.send(message());
+        assert!(poll(&mut third).is_pending());
+        assert_eq!(sender.len(), 2);
+        assert!(poll(&mut sender.ready()).is_pending());
+
+        // Writing out the first two messages makes room for the third.
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut first), Poll::Ready(Ok(_))));
+        assert!(matches!(poll(&mut second), Poll::Ready(Ok(_))));
+        assert!(sender.is_empty());
+        assert!(poll(&mut sender.ready()).is_ready());
+
+        assert!(poll(&mut third).is_pending());
+        assert_eq!(sender.len(), 1);
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut third), Poll::Ready(Ok(_))));
+
+        drop(sender);
+        assert!(matches!(poll(&mut queue), Poll::Ready(Ok(()))));
+    }
+
+    #[test]
+    fn byte_limit_and_termination() {
+        let size = message().get_segments_for_output()[0].len();
+        let (mut sender, queue) =
+            bounded_write_queue::<_, Message>(Cursor::new(Vec::new()), QueueLimits::new());
+        sender.set_limits(*QueueLimits::new().max_bytes(Some(size)));
+
+        // `send_immediately()` enqueues messages even when that goes over the limit.
+        let _first = sender.send_immediately(message());
+        let _second = sender.send_immediately(message());
+        assert_eq!(sender.queued_bytes(), 2 * size);
+
+        let mut third = sender.send(message());
+        assert!(poll(&mut third).is_pending());
+
+        // Once the queue has gone away, waiting senders get an error rather than hanging.
+        drop(queue);
+        assert!(sender.is_empty());
+        assert!(matches!(poll(&mut third), Poll::Ready(Err(_))));
+    }
+
+    #[test]
+    fn waiting_sends_are_admitted_in_order() {
+        let size = message().get_segments_for_output()[0].len();
+        let (mut sender, queue) = bounded_write_queue::<_, Message>(
+            Cursor::new(Vec::new()),
+            *QueueLimits::new().max_bytes(Some(2 * size)),
+        );
+        let mut queue = Box::pin(queue);
+
+        let mut big_message = message();
+        big_message
+            .set_root::<capnp::text::Owned>(&"x".repeat(8 * size))
+            .unwrap();
+
+        let mut first = sender.send(message());
+        let mut big = sender.send(big_message);
+        // This one would fit, but must not overtake `big`.
+        let mut small = sender.send(message());
+        assert!(poll(&mut big).is_pending());
+        assert!(poll(&mut small).is_pending());
+        assert_eq!(sender.len(), 1);
+
+        // Once the queue has been written out, `big` goes in, and `small` waits behind it.
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut first), Poll::Ready(Ok(_))));
+        assert!(poll(&mut small).is_pending());
+        assert!(poll(&mut big).is_pending());
+        assert!(poll(&mut small).is_pending());
+        assert_eq!(sender.len(), 1);
+
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut big), Poll::Ready(Ok(_))));
+        assert!(poll(&mut small).is_pending());
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut small), Poll::Ready(Ok(_))));
+    }
+
+    #[test]
+    fn dropping_a_waiting_send_discards_it() {
+        let (mut sender, queue) = bounded_write_queue::<_, Message>(
+            Cursor::new(Vec::new()),
+            *QueueLimits::new().max_messages(Some(1)),
+        );
+        let mut queue = Box::pin(queue);
+
+        let mut first = sender.send(message());
+        let second = sender.send(message());
+        let mut third = sender.send(message());
+        assert!(poll(&mut third).is_pending());
+
+        // `third` no longer waits behind `second`, whose message is never written.
+        drop(second);
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut first), Poll::Ready(Ok(_))));
+        assert!(poll(&mut third).is_pending());
+        assert_eq!(sender.len(), 1);
+        assert!(poll(&mut queue).is_pending());
+        assert!(matches!(poll(&mut third), Poll::Ready(Ok(_))));
+        assert!(sender.is_empty());
+    }
+
+    #[test]
+    fn clearing_limits_admits_waiting_sends() {
+        let (mut sender, _queue) = bounded_write_queue::<_, Message>(
+            Cursor::new(Vec::new()),
+            *QueueLimits::new().max_messages(Some(1)),
+        );
+
+        let _first = sender.send(message());
+        let mut second = sender.send(message());
+        assert!(poll(&mut second).is_pending());
+        assert_eq!(sender.len(), 1);
+
+        sender.set_limits(QueueLimits::new());
+        assert!(poll(&mut second).is_pending());
+        assert_eq!(sender.len(), 2);
+        let _third = sender.send(message());
+        assert_eq!(sender.len(), 3);
+    }
+}
@@ -0,0 +1,59 @@
+use crate::hello_world_capnp::hello_world;
+use crate::metrics::Metrics;
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use std::net::ToSocketAddrs;
+use std::rc::Rc;
+
+use futures::AsyncReadExt;
+
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 4 {
+        println!("usage: {} client HOST:PORT NUM_CALLS", args[0]);
+        return Ok(());
+    }
+
+    let addr = args[2]
+        .to_socket_addrs()?
+        .next()
+        .expect("could not parse address");
+
+    let num_calls: u32 = args[3].parse()?;
+
+    tokio::task::LocalSet::new()
+        .run_until(async move {
+            let stream = tokio::net::TcpStream::connect(&addr).await?;
+            stream.set_nodelay(true)?;
+            let (reader, writer) =
+                tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+            let rpc_network = Box::new(twoparty::VatNetwork::new(
+                futures::io::BufReader::new(reader),
+                futures::io::BufWriter::new(writer),
+                rpc_twoparty_capnp::Side::Client,
+                Default::default(),
+            ));
+            let mut rpc_system = RpcSystem::new(rpc_network, None);
+            let metrics = Rc::new(Metrics::default());
+            rpc_system.set_interceptor(metrics.clone());
+            let hello_world: hello_world::Client =
+                rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+
+            tokio::task::spawn_local(rpc_system);
+
+            for i in 0..num_calls {
+                // Every tenth call sends an empty name, which the server rejects.
+                let name = if i % 10 == 9 {
+                    String::new()
+                } else {
+                    format!("caller {i}")
+                };
+                let mut request = hello_world.say_hello_request();
+                request.get().init_request().set_name(&name[..]);
+                let _ = request.send().promise.await;
+            }
+
+            metrics.print("client");
+            Ok(())
+        })
+        .await
+}
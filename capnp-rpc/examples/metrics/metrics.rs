@@ -0,0 +1,88 @@
+use capnp_rpc::interceptor::{CallInfo, Interceptor, Metadata, ReturnInfo, ReturnKind};
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+#[derive(Default)]
+struct MethodCounters {
+    calls: u64,
+    errors: u64,
+    words_sent: u64,
+    words_received: u64,
+    total_latency: Duration,
+}
+
+/// Counts calls per method. Installed on both the client and the server, so the
+/// same code sees outgoing calls on one side and incoming calls on the other.
+#[derive(Default)]
+pub struct Metrics {
+    methods: RefCell<BTreeMap<(u64, u16), MethodCounters>>,
+}
+
+/// What we attach to each call, to be read back when it returns.
+struct CallStart {
+    key: (u64, u16),
+    time: Instant,
+}
+
+impl Metrics {
+    fn start(&self, call: &CallInfo, words: impl Fn(&mut MethodCounters) -> &mut u64) -> Metadata {
+        let key = (call.interface_id, call.method_id);
+        let mut methods = self.methods.borrow_mut();
+        let counters = methods.entry(key).or_default();
+        counters.calls += 1;
+        *words(counters) += call.size_in_words as u64;
+        Box::new(CallStart {
+            key,
+            time: Instant::now(),
+        })
+    }
+
+    fn finish(
+        &self,
+        ret: &ReturnInfo,
+        metadata: Option<Metadata>,
+        words: impl Fn(&mut MethodCounters) -> &mut u64,
+    ) {
+        let Some(start) = metadata.and_then(|m| m.downcast::<CallStart>().ok()) else {
+            return;
+        };
+        let mut methods = self.methods.borrow_mut();
+        let counters = methods.entry(start.key).or_default();
+        if let ReturnKind::Exception(_) = ret.kind {
+            counters.errors += 1;
+        }
+        *words(counters) += ret.size_in_words as u64;
+        counters.total_latency += start.time.elapsed();
+    }
+
+    pub fn print(&self, title: &str) {
+        println!("{title}:");
+        for ((interface_id, method_id), c) in self.methods.borrow().iter() {
+            let mean_latency = c.total_latency / c.calls.max(1) as u32;
+            println!(
+                "  {interface_id:#x}.{method_id}: {} calls, {} errors, {} words sent, \
+                 {} words received, mean latency {mean_latency:?}",
+                c.calls, c.errors, c.words_sent, c.words_received
+            );
+        }
+    }
+}
+
+impl Interceptor for Metrics {
+    fn outgoing_call(&self, call: &CallInfo) -> Option<Metadata> {
+        Some(self.start(call, |c| &mut c.words_sent))
+    }
+
+    fn incoming_return(&self, ret: &ReturnInfo, metadata: Option<Metadata>) {
+        self.finish(ret, metadata, |c| &mut c.words_received)
+    }
+
+    fn incoming_call(&self, call: &CallInfo) -> Option<Metadata> {
+        Some(self.start(call, |c| &mut c.words_received))
+    }
+
+    fn outgoing_return(&self, ret: &ReturnInfo, metadata: Option<Metadata>) {
+        self.finish(ret, metadata, |c| &mut c.words_sent)
+    }
+}
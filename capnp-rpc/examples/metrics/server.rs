@@ -0,0 +1,71 @@
+use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+
+use crate::hello_world_capnp::hello_world;
+use crate::metrics::Metrics;
+
+use futures::AsyncReadExt;
+use std::net::ToSocketAddrs;
+use std::rc::Rc;
+
+struct HelloWorldImpl;
+
+impl hello_world::Server for HelloWorldImpl {
+    async fn say_hello(
+        self: Rc<Self>,
+        params: hello_world::SayHelloParams,
+        mut results: hello_world::SayHelloResults,
+    ) -> Result<(), ::capnp::Error> {
+        let request = params.get()?.get_request()?;
+        let name = request.get_name()?.to_str()?;
+        if name.is_empty() {
+            return Err(::capnp::Error::failed("empty name".into()));
+        }
+        let message = format!("Hello, {name}!");
+        results.get().init_reply().set_message(message);
+
+        Ok(())
+    }
+}
+
+pub async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        println!("usage: {} server ADDRESS[:PORT]", args[0]);
+        return Ok(());
+    }
+
+    let addr = args[2]
+        .to_socket_addrs()?
+        .next()
+        .expect("could not parse address");
+
+    tokio::task::LocalSet::new()
+        .run_until(async move {
+            let listener = tokio::net::TcpListener::bind(&addr).await?;
+            let hello_world_client: hello_world::Client = capnp_rpc::new_client(HelloWorldImpl);
+
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                stream.set_nodelay(true)?;
+                let (reader, writer) =
+                    tokio_util::compat::TokioAsyncReadCompatExt::compat(stream).split();
+                let network = twoparty::VatNetwork::new(
+                    futures::io::BufReader::new(reader),
+                    futures::io::BufWriter::new(writer),
+                    rpc_twoparty_capnp::Side::Server,
+                    Default::default(),
+                );
+
+                let mut rpc_system =
+                    RpcSystem::new(Box::new(network), Some(hello_world_client.clone().client));
+                let metrics = Rc::new(Metrics::default());
+                rpc_system.set_interceptor(metrics.clone());
+
+                tokio::task::spawn_local(async move {
+                    let _ = rpc_system.await;
+                    metrics.print(&format!("connection from {peer}"));
+                });
+            }
+        })
+        .await
+}
@@ -0,0 +1,91 @@
+//! Hooks for observing the calls that pass over a connection, for example to collect
+//! metrics or to trace requests. See [`RpcSystem::set_interceptor()`](crate::RpcSystem::set_interceptor).
+//!
+//! [`Metadata`] stays in the local vat. The `Call` message has no field for per-call data, and
+//! adding one would make this implementation incompatible with others. To propagate a trace ID
+//! to the peer, set aside a field for it in the parameters of the methods to be traced. An
+//! interceptor can then write the ID into that field in
+//! [`outgoing_call_params()`](Interceptor::outgoing_call_params), and the peer's interceptor can
+//! read it back in [`incoming_call_with_params()`](Interceptor::incoming_call_with_params).
+
+use capnp::any_pointer;
+use std::any::Any;
+
+/// Data that an [`Interceptor`] attaches to a call when it is made, and that is handed back
+/// to it when the call returns. This is never sent to the peer.
+pub type Metadata = Box<dyn Any>;
+
+/// Describes a `Call` message.
+#[derive(Clone, Copy, Debug)]
+pub struct CallInfo {
+    pub interface_id: u64,
+    pub method_id: u16,
+
+    /// Identifies the call on its connection. The callee refers to this as the answer ID.
+    pub question_id: u32,
+
+    /// Approximate size of the message, in words.
+    pub size_in_words: usize,
+}
+
+/// How a call completed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReturnKind {
+    Results,
+    Exception(capnp::ErrorKind),
+    Canceled,
+
+    /// The results were sent elsewhere, for example because the call was turned into a tail call.
+    Other,
+}
+
+/// Describes a `Return` message.
+#[derive(Clone, Copy, Debug)]
+pub struct ReturnInfo {
+    /// The question ID of the call that is returning.
+    pub question_id: u32,
+    pub kind: ReturnKind,
+
+    /// Approximate size of the message, in words.
+    pub size_in_words: usize,
+}
+
+/// Observes the `Call` and `Return` messages that an `RpcSystem` sends and receives. Every
+/// method has a default implementation that does nothing.
+///
+/// The methods are invoked synchronously while the RPC system processes a message, so they
+/// should return quickly and must not make calls on the connection being observed.
+pub trait Interceptor {
+    /// We are about to send a call to the peer, and `params` are its parameters. This is called
+    /// before `outgoing_call()`, and may write to the parameters, e.g. to fill in a field that
+    /// carries a trace ID. It must not add capabilities to them.
+    fn outgoing_call_params(&self, _call: &CallInfo, _params: any_pointer::Builder<'_>) {}
+
+    /// We are sending a call to the peer. Any metadata returned here is passed to
+    /// `incoming_return()` when the call returns.
+    fn outgoing_call(&self, _call: &CallInfo) -> Option<Metadata> {
+        None
+    }
+
+    /// The peer has returned from a call that was passed to `outgoing_call()`.
+    fn incoming_return(&self, _ret: &ReturnInfo, _metadata: Option<Metadata>) {}
+
+    /// We have received a call from the peer. Any metadata returned here is passed to
+    /// `outgoing_return()` when the call returns.
+    fn incoming_call(&self, _call: &CallInfo) -> Option<Metadata> {
+        None
+    }
+
+    /// Like `incoming_call()`, which it calls by default, but also gets the call's parameters,
+    /// e.g. to read a field that carries a trace ID.
+    fn incoming_call_with_params(
+        &self,
+        call: &CallInfo,
+        _params: any_pointer::Reader<'_>,
+    ) -> Option<Metadata> {
+        self.incoming_call(call)
+    }
+
+    /// We are returning from a call that was passed to `incoming_call()`.
+    fn outgoing_return(&self, _ret: &ReturnInfo, _metadata: Option<Metadata>) {}
+}
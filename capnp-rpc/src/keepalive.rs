@@ -0,0 +1,63 @@
+//! Detection of peers that have gone away without closing the connection, such as the far end
+//! of a half-open TCP connection. See [`RpcSystem::set_keepalive()`](crate::RpcSystem::set_keepalive).
+
+use capnp::capability::Promise;
+use capnp::Error;
+
+use std::rc::Rc;
+use std::time::Duration;
+
+/// A source of delays. The RPC system does not depend on any particular executor, so the
+/// application supplies this, typically by wrapping its executor's sleep function.
+///
+/// Any `Fn(Duration) -> Promise<(), Error>` is a `Timer`, so with tokio one can write:
+///
+/// ```ignore
+/// let timer = |delay| Promise::from_future(async move {
+///     tokio::time::sleep(delay).await;
+///     Ok(())
+/// });
+/// ```
+pub trait Timer {
+    /// Returns a promise that resolves once `delay` has elapsed.
+    fn after_delay(&self, delay: Duration) -> Promise<(), Error>;
+}
+
+impl<F> Timer for F
+where
+    F: Fn(Duration) -> Promise<(), Error>,
+{
+    fn after_delay(&self, delay: Duration) -> Promise<(), Error> {
+        self(delay)
+    }
+}
+
+/// Keepalive settings for a connection.
+///
+/// Whenever nothing has been received from the peer for `interval`, the RPC system sends it a
+/// `Bootstrap` message and immediately cancels it, which costs the peer almost nothing but
+/// still obliges it to send a `Return`. If nothing at all is received for `timeout`, the
+/// connection is disconnected with [`ErrorKind::Disconnected`](capnp::ErrorKind::Disconnected).
+///
+/// The connection is checked once per `interval`, so `timeout` should be a few times larger
+/// than `interval`, and a dead peer is detected up to `interval` after `timeout` has passed.
+///
+/// Time spent with incoming messages held back by
+/// [`RpcSystem::set_flow_limit()`](crate::RpcSystem::set_flow_limit) does not count as idle. If
+/// the timer returns an error, keepalive stops, and the connection carries on without it.
+#[derive(Clone)]
+pub struct Keepalive {
+    pub(crate) timer: Rc<dyn Timer>,
+    pub(crate) interval: Duration,
+    pub(crate) timeout: Duration,
+}
+
+impl Keepalive {
+    pub fn new(timer: Rc<dyn Timer>, interval: Duration, timeout: Duration) -> Self {
+        Self {
+            timer,
+            interval,
+            timeout,
+        }
+    }
+}
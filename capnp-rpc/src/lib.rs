@@ -63,12 +63,12 @@ use capnp::private::capability::ClientHook;
 use capnp::Error;
 use futures::channel::oneshot;
 use futures::{Future, FutureExt, TryFutureExt};
-use std::cell::RefCell;
+use std::cell::{Cell, RefCell};
 use std::pin::Pin;
 use std::rc::{Rc, Weak};
 use std::task::{Context, Poll};
 
-pub use crate::rpc::Disconnector;
+pub use crate::rpc::{Disconnector, DrainReport};
 use crate::task_set::TaskSet;
 
 pub use crate::reconnect::{auto_reconnect, lazy_auto_reconnect, SetTarget};
@@ -100,12 +100,17 @@ macro_rules! pry {
 mod attach;
 mod broken;
 mod flow_control;
+pub mod interceptor;
+pub mod keepalive;
 mod local;
+pub mod loopback;
 mod queued;
 mod reconnect;
+pub mod recording;
 mod rpc;
 mod sender_queue;
 mod split;
+pub mod stats;
 mod task_set;
 pub mod twoparty;
 
@@ -139,6 +144,14 @@ pub trait OutgoingMessage {
     /// whereas typical implementations can compute the size more cheaply by summing
     /// segment sizes.
     fn size_in_words(&self) -> usize;
+
+    /// Sets the file descriptors to send along with the message. The RPC system refers to them
+    /// by index in `CapDescriptor.attachedFd`. The default implementation, for transports that
+    /// cannot carry file descriptors, drops them.
+    #[cfg(unix)]
+    fn set_fds(&mut self, fds: Vec<std::os::fd::OwnedFd>) {
+        drop(fds);
+    }
 }
 
 /// A message received from a [`VatNetwork`].
@@ -148,6 +161,22 @@ pub trait IncomingMessage {
     /// The standard RPC implementation interprets it as a Message as defined
     /// in `schema/rpc.capnp`.
     fn get_body(&self) -> ::capnp::Result<::capnp::any_pointer::Reader<'_>>;
+
+    /// Gets the total size of the message, for flow control purposes. The default implementation
+    /// walks the message tree, so implementations that can sum segment sizes instead should
+    /// override it.
+    fn size_in_words(&self) -> usize {
+        self.get_body()
+            .and_then(|body| body.target_size())
+            .map_or(0, |size| size.word_count as usize)
+    }
+
+    /// Gets the file descriptors that were received along with the message. The default
+    /// implementation, for transports that cannot carry file descriptors, returns none.
+    #[cfg(unix)]
+    fn get_attached_fds(&self) -> &[std::os::fd::OwnedFd] {
+        &[]
+    }
 }
 
 /// A two-way RPC connection.
@@ -223,10 +252,12 @@ pub trait VatNetwork<VatId> {
 /// determines how to form connections between vats. The RPC implementation determines
 /// how to use such connections to manage object references and make method calls.
 ///
-/// At the moment, this is all rather more general than it needs to be, because the only
-/// implementation of `VatNetwork` is [`twoparty::VatNetwork`]. However, eventually we
-/// will need to have more sophisticated `VatNetwork` implementations, in order to support
-/// [level 3](https://capnproto.org/rpc.html#protocol-features) features.
+/// At the moment, this is all rather more general than it needs to be, because an `RpcSystem`
+/// only ever uses a single connection, as with [`twoparty::VatNetwork`]. (The in-process
+/// [`loopback::VatNetwork`] can connect more vats, but each `RpcSystem` still talks to just one
+/// of them.) However, eventually we will need to have more sophisticated `VatNetwork`
+/// implementations, in order to support [level 3](https://capnproto.org/rpc.html#protocol-features)
+/// features.
 ///
 /// An `RpcSystem` is a non-`Send`able `Future` and needs to be driven by a task
 /// executor. A common way accomplish that is to pass the `RpcSystem` to
@@ -244,6 +275,16 @@ where
     // to connection states.
     connection_state: Rc<RefCell<Option<Rc<rpc::ConnectionState<VatId>>>>>,
 
+    flow_limit: Rc<Cell<usize>>,
+
+    interceptor: Rc<RefCell<Option<Rc<dyn interceptor::Interceptor>>>>,
+
+    keepalive: Rc<RefCell<Option<keepalive::Keepalive>>>,
+
+    // Calls to `drain()` that were polled before the connection existed. They are handed the
+    // connection once it has been created.
+    pending_drains: Rc<RefCell<Vec<oneshot::Sender<Rc<rpc::ConnectionState<VatId>>>>>>,
+
     tasks: TaskSet<Error>,
     handle: crate::task_set::TaskSetHandle<Error>,
 }
@@ -282,7 +323,10 @@ impl<VatId> RpcSystem<VatId> {
             network,
             bootstrap_cap,
             connection_state: Rc::new(RefCell::new(None)),
-
+            flow_limit: Rc::new(Cell::new(usize::MAX)),
+            interceptor: Rc::new(RefCell::new(None)),
+            keepalive: Rc::new(RefCell::new(None)),
+            pending_drains: Rc::new(RefCell::new(Vec::new())),
             tasks,
             handle: handle.clone(),
         };
@@ -305,8 +349,12 @@ impl<VatId> RpcSystem<VatId> {
             &self.connection_state,
             self.bootstrap_cap.clone(),
             connection,
+            self.flow_limit.clone(),
+            self.interceptor.clone(),
+            self.keepalive.borrow().clone(),
             self.handle.clone(),
         );
+        Self::start_pending_drains(&self.pending_drains, &connection_state);
 
         let hook = rpc::ConnectionState::bootstrap(&connection_state);
         T::new(hook)
@@ -316,12 +364,40 @@ impl<VatId> RpcSystem<VatId> {
     fn accept_loop(&mut self) -> Promise<(), Error> {
         let connection_state_ref = self.connection_state.clone();
         let bootstrap_cap = self.bootstrap_cap.clone();
+        let flow_limit = self.flow_limit.clone();
+        let interceptor = self.interceptor.clone();
+        let keepalive = self.keepalive.clone();
+        let pending_drains = self.pending_drains.clone();
         let handle = self.handle.clone();
         Promise::from_future(self.network.accept().map_ok(move |connection| {
-            Self::get_connection_state(&connection_state_ref, bootstrap_cap, connection, handle);
+            let connection_state = Self::get_connection_state(
+                &connection_state_ref,
+                bootstrap_cap,
+                connection,
+                flow_limit,
+                interceptor,
+                keepalive.borrow().clone(),
+                handle,
+            );
+            Self::start_pending_drains(&pending_drains, &connection_state);
         }))
     }
 
+    // Hands `connection_state` to the calls to `drain()` that were waiting for a connection.
+    // It starts rejecting new calls right away, before those calls get to run.
+    fn start_pending_drains(
+        pending_drains: &RefCell<Vec<oneshot::Sender<Rc<rpc::ConnectionState<VatId>>>>>,
+        connection_state: &Rc<rpc::ConnectionState<VatId>>,
+    ) {
+        let pending_drains = std::mem::take(&mut *pending_drains.borrow_mut());
+        if !pending_drains.is_empty() {
+            connection_state.start_draining();
+        }
+        for pending_drain in pending_drains {
+            let _ = pending_drain.send(connection_state.clone());
+        }
+    }
+
     // If `connection_state_ref` is not already populated, populates it with a new
     // `ConnectionState` built from a local bootstrap capability and `connection`,
     // spawning any background tasks onto `handle`. Returns the resulting value
@@ -330,6 +406,9 @@ impl<VatId> RpcSystem<VatId> {
         connection_state_ref: &Rc<RefCell<Option<Rc<rpc::ConnectionState<VatId>>>>>,
         bootstrap_cap: Box<dyn ClientHook>,
         connection: Box<dyn crate::Connection<VatId>>,
+        flow_limit: Rc<Cell<usize>>,
+        interceptor: Rc<RefCell<Option<Rc<dyn interceptor::Interceptor>>>>,
+        keepalive: Option<keepalive::Keepalive>,
         mut handle: crate::task_set::TaskSetHandle<Error>,
     ) -> Rc<rpc::ConnectionState<VatId>> {
         // TODO this needs to be updated once we allow more general VatNetworks.
@@ -349,14 +428,93 @@ impl<VatId> RpcSystem<VatId> {
                         Err(e) => Promise::err(Error::failed(format!("{e}"))),
                     }
                 }));
-                rpc::ConnectionState::new(bootstrap_cap, connection, on_disconnect_fulfiller)
+                rpc::ConnectionState::new(
+                    bootstrap_cap,
+                    connection,
+                    on_disconnect_fulfiller,
+                    flow_limit,
+                    interceptor,
+                )
             }
         };
         *connection_state_ref.borrow_mut() = Some(result.clone());
         handle.add(tasks);
+        if let Some(keepalive) = keepalive {
+            rpc::ConnectionState::start_keepalive(&result, keepalive);
+        }
         result
     }
 
+    /// Limits the total size, in words, of the incoming calls that may be in progress at once on
+    /// each connection. When the limit is exceeded, the `RpcSystem` stops reading messages from
+    /// the connection until enough of those calls have completed, so that a peer cannot make this
+    /// process queue up unbounded work. By default there is no limit.
+    ///
+    /// The limit is soft: the call that crosses it is still delivered, so a single call may be
+    /// larger than the limit. Be careful not to set the limit so low that a call which depends on
+    /// a later call from the same peer can deadlock.
+    pub fn set_flow_limit(&mut self, words: usize) {
+        self.flow_limit.set(words);
+        if let Some(connection_state) = self.connection_state.borrow().as_ref() {
+            connection_state.check_flow();
+        }
+    }
+
+    /// Installs an interceptor that observes every call made or received over this
+    /// `RpcSystem`'s connections, replacing any previously installed one.
+    pub fn set_interceptor(&mut self, interceptor: Rc<dyn interceptor::Interceptor>) {
+        *self.interceptor.borrow_mut() = Some(interceptor);
+    }
+
+    /// Enables keepalive on this `RpcSystem`'s connections, so that a peer which stops
+    /// responding is detected and disconnected instead of being waited on forever. Replaces any
+    /// previous keepalive settings. See [`keepalive::Keepalive`] for details.
+    pub fn set_keepalive(&mut self, keepalive: keepalive::Keepalive) {
+        if let Some(connection_state) = self.connection_state.borrow().as_ref() {
+            rpc::ConnectionState::start_keepalive(connection_state, keepalive.clone());
+        }
+        *self.keepalive.borrow_mut() = Some(keepalive);
+    }
+
+    /// Returns a handle for inspecting the tables of this `RpcSystem`'s connection, which remains
+    /// usable after the `RpcSystem` has been spawned.
+    pub fn get_inspector(&self) -> stats::Inspector<VatId> {
+        stats::Inspector::new(&self.connection_state)
+    }
+
+    /// Returns a future that gracefully shuts down the connection to this `RpcSystem`'s network,
+    /// for example before a server process is replaced. Like the `Disconnector`, it should be
+    /// obtained before the `RpcSystem` is spawned, and it does nothing until it is polled.
+    ///
+    /// Once polled, new incoming calls are rejected with an `Overloaded` error, while the calls
+    /// already in progress, including streaming calls, are allowed to complete and send their
+    /// results. When they have all completed, or when `deadline` resolves, whichever comes
+    /// first, the connection is closed after flushing any outgoing messages. The returned
+    /// [`DrainReport`] counts the calls that did and did not complete.
+    ///
+    /// If there is no connection yet when the future is polled, it waits for one and drains it
+    /// as soon as it has been made, so the connection accepts no calls at all. If the
+    /// `RpcSystem` is dropped before then, the report is empty.
+    pub fn drain(&self, deadline: Promise<(), Error>) -> Promise<DrainReport, Error> {
+        let connection_state = self.connection_state.clone();
+        let pending_drains = self.pending_drains.clone();
+        Promise::from_future(async move {
+            let connection_state = connection_state.borrow().clone();
+            let state = match connection_state {
+                Some(state) => state,
+                None => {
+                    let (fulfiller, connected) = oneshot::channel();
+                    pending_drains.borrow_mut().push(fulfiller);
+                    match connected.await {
+                        Ok(state) => state,
+                        Err(oneshot::Canceled) => return Ok(DrainReport::default()),
+                    }
+                }
+            };
+            rpc::ConnectionState::drain(state, deadline).await
+        })
+    }
+
     /// Returns a `Disconnector` future that can be run to cleanly close the connection to this `RpcSystem`'s network.
     /// The future resolves once the connection's shutdown has completed, and it reports any error
     /// that occurred during shutdown.
@@ -394,6 +552,44 @@ where
     )))
 }
 
+/// Like `new_client()`, but the client also wraps the file descriptor `fd`. When the client is
+/// sent over a connection that supports file descriptor passing, such as one created by
+/// [`twoparty::unix::VatNetwork`], the receiver can obtain a duplicate of `fd` with
+/// [`get_fd()`]. The descriptor is closed once the client and all of its references are dropped.
+#[cfg(unix)]
+pub fn new_client_with_fd<C, S>(s: S, fd: std::os::fd::OwnedFd) -> C
+where
+    C: capnp::capability::FromServer<S>,
+{
+    capnp::capability::FromClientHook::new(Box::new(local::Client::new_with_fd(
+        <C as capnp::capability::FromServer<S>>::from_server(Rc::new(s)),
+        fd,
+    )))
+}
+
+/// Waits for `cap` to resolve, then returns a duplicate of the file descriptor that it wraps, or
+/// `None` if it does not wrap one. For a capability received from a peer, the descriptor is
+/// present only if the peer attached it and the connection supports file descriptor passing.
+#[cfg(unix)]
+pub async fn get_fd<C>(cap: &C) -> Result<Option<std::os::fd::OwnedFd>, Error>
+where
+    C: capnp::capability::FromClientHook,
+{
+    let mut hook = cap.as_client_hook().add_ref();
+    hook.when_resolved().await?;
+    while let Some(resolved) = hook.get_resolved() {
+        hook = resolved;
+    }
+    match hook.get_fd() {
+        Some(fd) => {
+            // Safety: `hook` keeps the descriptor open for as long as we borrow it here.
+            let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+            Ok(Some(fd.try_clone_to_owned()?))
+        }
+        None => Ok(None),
+    }
+}
+
 /// Creates a "broken" capability that returns the given error from every operation.
 ///
 /// The returned client fails with a clone of `error` on every method call
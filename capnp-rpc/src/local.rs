@@ -360,6 +360,10 @@ where
     /// non-streaming calls, like EOF methods, cannot overtake streaming writes.
     blocked: bool,
     blocked_calls: VecDeque<BlockedCall>,
+
+    /// File descriptor exposed to peers through `ClientHook::get_fd()`.
+    #[cfg(unix)]
+    fd: Option<std::os::fd::OwnedFd>,
 }
 
 struct BlockedCall {
@@ -536,9 +540,18 @@ where
                 broken_error: None,
                 blocked: false,
                 blocked_calls: VecDeque::new(),
+                #[cfg(unix)]
+                fd: None,
             })),
         }
     }
+
+    #[cfg(unix)]
+    pub(crate) fn new_with_fd(server: S, fd: std::os::fd::OwnedFd) -> Self {
+        let client = Self::new(server);
+        client.state.borrow_mut().fd = Some(fd);
+        client
+    }
 }
 
 impl<S> Clone for Client<S>
@@ -608,4 +621,10 @@ where
     fn when_resolved(&self) -> Promise<(), Error> {
         crate::rpc::default_when_resolved_impl(self)
     }
+
+    #[cfg(unix)]
+    fn get_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        self.state.borrow().fd.as_ref().map(AsRawFd::as_raw_fd)
+    }
 }
@@ -0,0 +1,346 @@
+//! An in-process [`VatNetwork`](crate::VatNetwork) that connects any number of vats living in
+//! the same thread. Messages are handed to the receiving vat as they are, without being
+//! serialized, which makes this network cheap and deterministic enough for tests.
+//!
+//! ```ignore
+//! let network = loopback::Network::new();
+//! let alice = RpcSystem::new(Box::new(network.add_vat("alice")), Some(bootstrap));
+//! let mut bob = RpcSystem::new(Box::new(network.add_vat("bob")), None);
+//! let client: foo::Client = bob.bootstrap("alice");
+//! ```
+//!
+//! Note that an [`RpcSystem`](crate::RpcSystem) currently serves only the first connection that
+//! its network makes or accepts, so each `RpcSystem` should talk to a single peer.
+
+use capnp::capability::Promise;
+use capnp::message::{Builder, HeapAllocator};
+use capnp::Error;
+use futures::channel::{mpsc, oneshot};
+use futures::{FutureExt, StreamExt};
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::{Rc, Weak};
+
+type Message = Rc<Builder<HeapAllocator>>;
+
+struct IncomingMessage {
+    message: Message,
+}
+
+impl crate::IncomingMessage for IncomingMessage {
+    fn get_body(&self) -> capnp::Result<capnp::any_pointer::Reader<'_>> {
+        self.message.get_root_as_reader()
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.message.size_in_words()
+    }
+}
+
+struct OutgoingMessage<VatId>
+where
+    VatId: 'static,
+{
+    message: Builder<HeapAllocator>,
+    connection: Weak<RefCell<ConnectionInner<VatId>>>,
+}
+
+impl<VatId> crate::OutgoingMessage for OutgoingMessage<VatId> {
+    fn get_body(&mut self) -> capnp::Result<capnp::any_pointer::Builder<'_>> {
+        self.message.get_root()
+    }
+
+    fn get_body_as_reader(&self) -> capnp::Result<capnp::any_pointer::Reader<'_>> {
+        self.message.get_root_as_reader()
+    }
+
+    fn send(self: Box<Self>) -> (Promise<(), Error>, Message) {
+        let Self {
+            message,
+            connection,
+        } = *self;
+        let message = Rc::new(message);
+        let sent = match connection.upgrade() {
+            Some(inner) => match inner.borrow().sender {
+                Some(ref sender) => sender
+                    .unbounded_send(message.clone())
+                    .map_err(|_| Error::disconnected("peer disconnected".into())),
+                None => Err(Error::disconnected("connection was shut down".into())),
+            },
+            None => Err(Error::disconnected("connection was dropped".into())),
+        };
+        (Promise::from_future(futures::future::ready(sent)), message)
+    }
+
+    fn take(self: Box<Self>) -> Builder<HeapAllocator> {
+        self.message
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.message.size_in_words()
+    }
+}
+
+struct ConnectionInner<VatId>
+where
+    VatId: 'static,
+{
+    peer: VatId,
+
+    // Becomes `None` on shutdown, so that the peer sees the end of the stream.
+    sender: Option<mpsc::UnboundedSender<Message>>,
+
+    // Taken out while a receive is in progress.
+    receiver: Rc<RefCell<Option<mpsc::UnboundedReceiver<Message>>>>,
+
+    vat: Weak<RefCell<VatInner<VatId>>>,
+}
+
+impl<VatId> ConnectionInner<VatId> {
+    fn new(
+        peer: VatId,
+        sender: mpsc::UnboundedSender<Message>,
+        receiver: mpsc::UnboundedReceiver<Message>,
+        vat: &Rc<RefCell<VatInner<VatId>>>,
+    ) -> Rc<RefCell<Self>> {
+        Rc::new(RefCell::new(Self {
+            peer,
+            sender: Some(sender),
+            receiver: Rc::new(RefCell::new(Some(receiver))),
+            vat: Rc::downgrade(vat),
+        }))
+    }
+}
+
+impl<VatId> Drop for ConnectionInner<VatId> {
+    fn drop(&mut self) {
+        if let Some(vat) = self.vat.upgrade() {
+            vat.borrow_mut().connection_dropped();
+        }
+    }
+}
+
+struct Connection<VatId>
+where
+    VatId: 'static,
+{
+    inner: Rc<RefCell<ConnectionInner<VatId>>>,
+}
+
+impl<VatId> crate::Connection<VatId> for Connection<VatId>
+where
+    VatId: Clone,
+{
+    fn get_peer_vat_id(&self) -> VatId {
+        self.inner.borrow().peer.clone()
+    }
+
+    fn new_outgoing_message(
+        &mut self,
+        first_segment_word_size: u32,
+    ) -> Box<dyn crate::OutgoingMessage> {
+        Box::new(OutgoingMessage {
+            message: Builder::new(
+                HeapAllocator::new().first_segment_words(first_segment_word_size),
+            ),
+            connection: Rc::downgrade(&self.inner),
+        })
+    }
+
+    fn receive_incoming_message(
+        &mut self,
+    ) -> Promise<Option<Box<dyn crate::IncomingMessage>>, Error> {
+        let return_it_here = self.inner.borrow().receiver.clone();
+        let Some(mut receiver) = return_it_here.borrow_mut().take() else {
+            return Promise::err(Error::failed(
+                "receive_incoming_message() called while a receive is in progress".into(),
+            ));
+        };
+        Promise::from_future(async move {
+            let message = receiver.next().await;
+            *return_it_here.borrow_mut() = Some(receiver);
+            Ok(message.map(|message| {
+                Box::new(IncomingMessage { message }) as Box<dyn crate::IncomingMessage>
+            }))
+        })
+    }
+
+    fn shutdown(&mut self, _result: capnp::Result<()>) -> Promise<(), Error> {
+        self.inner.borrow_mut().sender = None;
+        Promise::ok(())
+    }
+}
+
+struct VatInner<VatId>
+where
+    VatId: 'static,
+{
+    id: VatId,
+
+    // Connections to other vats, whether made or accepted, so that `connect()` can reuse them.
+    connections: HashMap<VatId, Weak<RefCell<ConnectionInner<VatId>>>>,
+
+    accept_sender: mpsc::UnboundedSender<Connection<VatId>>,
+
+    // Fulfilled once all of this vat's connections are gone.
+    shutdown_fulfiller: Option<oneshot::Sender<()>>,
+}
+
+impl<VatId> VatInner<VatId> {
+    fn connection_dropped(&mut self) {
+        self.connections.retain(|_, c| c.strong_count() > 0);
+        if self.connections.is_empty() {
+            if let Some(fulfiller) = self.shutdown_fulfiller.take() {
+                let _ = fulfiller.send(());
+            }
+        }
+    }
+}
+
+/// A set of vats that can connect to each other, identified by `VatId`s such as strings or
+/// integers.
+pub struct Network<VatId>
+where
+    VatId: 'static,
+{
+    vats: Rc<RefCell<HashMap<VatId, Weak<RefCell<VatInner<VatId>>>>>>,
+}
+
+impl<VatId> Clone for Network<VatId> {
+    fn clone(&self) -> Self {
+        Self {
+            vats: self.vats.clone(),
+        }
+    }
+}
+
+impl<VatId> Default for Network<VatId> {
+    fn default() -> Self {
+        Self {
+            vats: Rc::new(RefCell::new(HashMap::new())),
+        }
+    }
+}
+
+impl<VatId> Network<VatId>
+where
+    VatId: Clone + Eq + Hash,
+{
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a vat to the network and returns its view of the network, to be passed to
+    /// [`RpcSystem::new()`](crate::RpcSystem::new). The vat leaves the network when the
+    /// returned value is dropped.
+    ///
+    /// # Panics
+    ///
+    /// Panics if another vat with the same ID is still on the network.
+    pub fn add_vat(&self, id: VatId) -> VatNetwork<VatId> {
+        let mut vats = self.vats.borrow_mut();
+        vats.retain(|_, vat| vat.strong_count() > 0);
+        assert!(!vats.contains_key(&id), "vat ID is already in use");
+
+        let (accept_sender, accept_receiver) = mpsc::unbounded();
+        let (shutdown_fulfiller, shutdown_promise) = oneshot::channel();
+        let vat = Rc::new(RefCell::new(VatInner {
+            id: id.clone(),
+            connections: HashMap::new(),
+            accept_sender,
+            shutdown_fulfiller: Some(shutdown_fulfiller),
+        }));
+        vats.insert(id, Rc::downgrade(&vat));
+
+        VatNetwork {
+            vat,
+            network: self.clone(),
+            accept_receiver: Rc::new(RefCell::new(Some(accept_receiver))),
+            shutdown_promise: Promise::from_future(shutdown_promise.map(|_| Ok(()))).shared(),
+        }
+    }
+}
+
+/// One vat's view of a [`Network`].
+pub struct VatNetwork<VatId>
+where
+    VatId: 'static,
+{
+    vat: Rc<RefCell<VatInner<VatId>>>,
+    network: Network<VatId>,
+    accept_receiver: Rc<RefCell<Option<mpsc::UnboundedReceiver<Connection<VatId>>>>>,
+    shutdown_promise: futures::future::Shared<Promise<(), Error>>,
+}
+
+impl<VatId> crate::VatNetwork<VatId> for VatNetwork<VatId>
+where
+    VatId: Clone + Eq + Hash,
+{
+    fn connect(&mut self, host_id: VatId) -> Option<Box<dyn crate::Connection<VatId>>> {
+        let id = self.vat.borrow().id.clone();
+        if host_id == id {
+            return None;
+        }
+        let existing = self
+            .vat
+            .borrow()
+            .connections
+            .get(&host_id)
+            .and_then(Weak::upgrade);
+        if let Some(inner) = existing {
+            return Some(Box::new(Connection { inner }));
+        }
+
+        let (to_host, from_us) = mpsc::unbounded();
+        let (to_us, from_host) = mpsc::unbounded();
+        let ours = ConnectionInner::new(host_id.clone(), to_host, from_host, &self.vat);
+        self.vat
+            .borrow_mut()
+            .connections
+            .insert(host_id.clone(), Rc::downgrade(&ours));
+
+        let host = self
+            .network
+            .vats
+            .borrow()
+            .get(&host_id)
+            .and_then(Weak::upgrade);
+        // If there is no such vat, the other end is dropped here and the connection appears to
+        // have been closed by the peer.
+        if let Some(host) = host {
+            let theirs = ConnectionInner::new(id.clone(), to_us, from_us, &host);
+            host.borrow_mut()
+                .connections
+                .insert(id, Rc::downgrade(&theirs));
+            let sent = host
+                .borrow()
+                .accept_sender
+                .unbounded_send(Connection { inner: theirs });
+            drop(sent);
+        }
+        Some(Box::new(Connection { inner: ours }))
+    }
+
+    fn accept(&mut self) -> Promise<Box<dyn crate::Connection<VatId>>, Error> {
+        let return_it_here = self.accept_receiver.clone();
+        let Some(mut receiver) = return_it_here.borrow_mut().take() else {
+            return Promise::err(Error::failed(
+                "accept() called while another accept is in progress".into(),
+            ));
+        };
+        Promise::from_future(async move {
+            let connection = receiver.next().await;
+            *return_it_here.borrow_mut() = Some(receiver);
+            match connection {
+                Some(c) => Ok(Box::new(c) as Box<dyn crate::Connection<VatId>>),
+                None => Err(Error::disconnected("network was dropped".into())),
+            }
+        })
+    }
+
+    fn drive_until_shutdown(&mut self) -> Promise<(), Error> {
+        Promise::from_future(self.shutdown_promise.clone())
+    }
+}
@@ -0,0 +1,547 @@
+//! Tools for capturing the messages exchanged on a connection and replaying them later, to
+//! reproduce exact message interleavings when debugging or in regression tests.
+//!
+//! Wrap a network in a [`RecordingVatNetwork`] to capture a [`Trace`], which prints each
+//! message in a readable form. To check that the RPC system still behaves the same way, hand
+//! the trace to a [`ReplayVatNetwork`], drive the same application code against it, and then
+//! call [`Replay::check()`].
+
+use capnp::any_pointer;
+use capnp::capability::Promise;
+use capnp::message::{Builder, HeapAllocator};
+use capnp::Error;
+use futures::channel::oneshot;
+use futures::{FutureExt, TryFutureExt};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt;
+use std::rc::{Rc, Weak};
+use std::task::{Poll, Waker};
+use std::time::{Duration, Instant};
+
+use crate::rpc_capnp::message;
+
+/// Whether a message was received from the peer or sent to it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Incoming,
+    Outgoing,
+}
+
+#[derive(Clone)]
+enum Body {
+    Built(Rc<Builder<HeapAllocator>>),
+    Received(Rc<dyn crate::IncomingMessage>),
+}
+
+impl Body {
+    fn get(&self) -> capnp::Result<any_pointer::Reader<'_>> {
+        match self {
+            Self::Built(message) => message.get_root_as_reader(),
+            Self::Received(message) => message.get_body(),
+        }
+    }
+}
+
+/// A message captured in a [`Trace`].
+#[derive(Clone)]
+pub struct Event {
+    /// When the message was sent or received, relative to the start of the trace.
+    pub time: Duration,
+    pub direction: Direction,
+    body: Body,
+}
+
+impl Event {
+    /// Gets the message.
+    pub fn get(&self) -> capnp::Result<message::Reader<'_>> {
+        self.body.get()?.get_as()
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let arrow = match self.direction {
+            Direction::Incoming => "<-",
+            Direction::Outgoing => "->",
+        };
+        write!(f, "[{:>12?}] {arrow} ", self.time)?;
+        match self.get() {
+            Ok(message) => fmt::Debug::fmt(&message, f),
+            Err(e) => write!(f, "<malformed message: {e}>"),
+        }
+    }
+}
+
+struct TraceInner {
+    start: Instant,
+    events: Vec<Event>,
+}
+
+/// The messages sent and received on a connection, in order. Cloning a `Trace` yields a
+/// handle to the same trace. Printing it with `{:?}` lists one message per line.
+#[derive(Clone)]
+pub struct Trace {
+    inner: Rc<RefCell<TraceInner>>,
+}
+
+impl Default for Trace {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Trace {
+    pub fn new() -> Self {
+        Self {
+            inner: Rc::new(RefCell::new(TraceInner {
+                start: Instant::now(),
+                events: Vec::new(),
+            })),
+        }
+    }
+
+    /// Returns a snapshot of the events captured so far.
+    pub fn events(&self) -> Vec<Event> {
+        self.inner.borrow().events.clone()
+    }
+
+    /// Appends a message, e.g. to script a trace by hand for a regression test.
+    pub fn push(&self, direction: Direction, message: Builder<HeapAllocator>) {
+        self.record(direction, Body::Built(Rc::new(message)));
+    }
+
+    fn record(&self, direction: Direction, body: Body) {
+        let mut inner = self.inner.borrow_mut();
+        let time = inner.start.elapsed();
+        inner.events.push(Event {
+            time,
+            direction,
+            body,
+        });
+    }
+}
+
+impl fmt::Debug for Trace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for event in &self.inner.borrow().events {
+            writeln!(f, "{event:?}")?;
+        }
+        Ok(())
+    }
+}
+
+/// Lets a received message be both handed to the RPC system and kept in a trace.
+struct SharedIncomingMessage(Rc<dyn crate::IncomingMessage>);
+
+impl crate::IncomingMessage for SharedIncomingMessage {
+    fn get_body(&self) -> capnp::Result<any_pointer::Reader<'_>> {
+        self.0.get_body()
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.0.size_in_words()
+    }
+
+    #[cfg(unix)]
+    fn get_attached_fds(&self) -> &[std::os::fd::OwnedFd] {
+        self.0.get_attached_fds()
+    }
+}
+
+struct BuiltIncomingMessage(Rc<Builder<HeapAllocator>>);
+
+impl crate::IncomingMessage for BuiltIncomingMessage {
+    fn get_body(&self) -> capnp::Result<any_pointer::Reader<'_>> {
+        self.0.get_root_as_reader()
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.0.size_in_words()
+    }
+}
+
+fn into_incoming_message(body: Body) -> Box<dyn crate::IncomingMessage> {
+    match body {
+        Body::Built(message) => Box::new(BuiltIncomingMessage(message)),
+        Body::Received(message) => Box::new(SharedIncomingMessage(message)),
+    }
+}
+
+struct RecordingOutgoingMessage {
+    inner: Box<dyn crate::OutgoingMessage>,
+    trace: Trace,
+}
+
+impl crate::OutgoingMessage for RecordingOutgoingMessage {
+    fn get_body(&mut self) -> capnp::Result<any_pointer::Builder<'_>> {
+        self.inner.get_body()
+    }
+
+    fn get_body_as_reader(&self) -> capnp::Result<any_pointer::Reader<'_>> {
+        self.inner.get_body_as_reader()
+    }
+
+    fn send(self: Box<Self>) -> (Promise<(), Error>, Rc<Builder<HeapAllocator>>) {
+        let (promise, message) = self.inner.send();
+        self.trace
+            .record(Direction::Outgoing, Body::Built(message.clone()));
+        (promise, message)
+    }
+
+    fn take(self: Box<Self>) -> Builder<HeapAllocator> {
+        self.inner.take()
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.inner.size_in_words()
+    }
+
+    #[cfg(unix)]
+    fn set_fds(&mut self, fds: Vec<std::os::fd::OwnedFd>) {
+        self.inner.set_fds(fds)
+    }
+}
+
+struct RecordingConnection<VatId> {
+    inner: Box<dyn crate::Connection<VatId>>,
+    trace: Trace,
+}
+
+impl<VatId> crate::Connection<VatId> for RecordingConnection<VatId> {
+    fn get_peer_vat_id(&self) -> VatId {
+        self.inner.get_peer_vat_id()
+    }
+
+    fn new_outgoing_message(
+        &mut self,
+        first_segment_word_size: u32,
+    ) -> Box<dyn crate::OutgoingMessage> {
+        Box::new(RecordingOutgoingMessage {
+            inner: self.inner.new_outgoing_message(first_segment_word_size),
+            trace: self.trace.clone(),
+        })
+    }
+
+    fn receive_incoming_message(
+        &mut self,
+    ) -> Promise<Option<Box<dyn crate::IncomingMessage>>, Error> {
+        let trace = self.trace.clone();
+        Promise::from_future(
+            self.inner
+                .receive_incoming_message()
+                .map_ok(move |message| {
+                    message.map(|message| {
+                        let message: Rc<dyn crate::IncomingMessage> = Rc::from(message);
+                        trace.record(Direction::Incoming, Body::Received(message.clone()));
+                        Box::new(SharedIncomingMessage(message)) as Box<dyn crate::IncomingMessage>
+                    })
+                }),
+        )
+    }
+
+    fn new_stream(&mut self) -> (Box<dyn crate::FlowController>, Promise<(), Error>) {
+        self.inner.new_stream()
+    }
+
+    fn shutdown(&mut self, result: capnp::Result<()>) -> Promise<(), Error> {
+        self.inner.shutdown(result)
+    }
+}
+
+/// Wraps a [`VatNetwork`](crate::VatNetwork), capturing every message sent or received on its
+/// connections into a [`Trace`].
+pub struct RecordingVatNetwork<VatId> {
+    inner: Box<dyn crate::VatNetwork<VatId>>,
+    trace: Trace,
+}
+
+impl<VatId> RecordingVatNetwork<VatId> {
+    pub fn new(inner: Box<dyn crate::VatNetwork<VatId>>, trace: Trace) -> Self {
+        Self { inner, trace }
+    }
+}
+
+impl<VatId> crate::VatNetwork<VatId> for RecordingVatNetwork<VatId>
+where
+    VatId: 'static,
+{
+    fn connect(&mut self, host_id: VatId) -> Option<Box<dyn crate::Connection<VatId>>> {
+        let inner = self.inner.connect(host_id)?;
+        Some(Box::new(RecordingConnection {
+            inner,
+            trace: self.trace.clone(),
+        }))
+    }
+
+    fn accept(&mut self) -> Promise<Box<dyn crate::Connection<VatId>>, Error> {
+        let trace = self.trace.clone();
+        Promise::from_future(self.inner.accept().map_ok(move |inner| {
+            Box::new(RecordingConnection { inner, trace }) as Box<dyn crate::Connection<VatId>>
+        }))
+    }
+
+    fn drive_until_shutdown(&mut self) -> Promise<(), Error> {
+        self.inner.drive_until_shutdown()
+    }
+}
+
+// Compares messages by their canonical form where possible. Messages that contain
+// capabilities have no canonical form, so those must match byte for byte.
+fn same_message(a: &Builder<HeapAllocator>, b: &Builder<HeapAllocator>) -> bool {
+    fn canonical(message: &Builder<HeapAllocator>) -> capnp::Result<Vec<u8>> {
+        let mut canonical = Builder::new_default();
+        canonical.set_root_canonical(message.get_root_as_reader::<any_pointer::Reader>()?)?;
+        Ok(canonical.get_segments_for_output().concat())
+    }
+    match (canonical(a), canonical(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => *a.get_segments_for_output() == *b.get_segments_for_output(),
+    }
+}
+
+struct ReplayState {
+    // Remaining events of each direction, tagged with their positions in the trace.
+    incoming: VecDeque<(usize, Body)>,
+    outgoing: VecDeque<(usize, Event)>,
+
+    // The first discrepancy found.
+    error: Option<Error>,
+
+    // Set while the RPC system waits for the next incoming message.
+    receive_waker: Option<Waker>,
+}
+
+impl ReplayState {
+    fn check_sent(&mut self, message: Rc<Builder<HeapAllocator>>) {
+        if self.error.is_none() {
+            let actual = Event {
+                time: Duration::ZERO,
+                direction: Direction::Outgoing,
+                body: Body::Built(message.clone()),
+            };
+            self.error = match self.outgoing.pop_front() {
+                Some((
+                    _,
+                    Event {
+                        body: Body::Built(ref expected),
+                        ..
+                    },
+                )) if same_message(expected, &message) => None,
+                Some((_, expected)) => Some(Error::failed(format!(
+                    "replay expected {expected:?}\nbut got {actual:?}"
+                ))),
+                None => Some(Error::failed(format!("replay got unexpected {actual:?}"))),
+            };
+        }
+        if let Some(waker) = self.receive_waker.take() {
+            waker.wake();
+        }
+    }
+
+    fn poll_receive(&mut self, waker: &Waker) -> Poll<capnp::Result<Body>> {
+        if let Some(ref e) = self.error {
+            return Poll::Ready(Err(e.clone()));
+        }
+        // Deliver the next incoming message once every message that was sent before it in
+        // the trace has been sent again.
+        match (self.incoming.front(), self.outgoing.front()) {
+            (Some((i, _)), Some((o, _))) if o < i => {}
+            (Some(_), _) => {
+                let (_, body) = self.incoming.pop_front().unwrap();
+                return Poll::Ready(Ok(body));
+            }
+            (None, _) => {}
+        }
+        self.receive_waker = Some(waker.clone());
+        Poll::Pending
+    }
+}
+
+/// Reports how a replay went. See [`ReplayVatNetwork`].
+#[derive(Clone)]
+pub struct Replay {
+    state: Rc<RefCell<ReplayState>>,
+}
+
+impl Replay {
+    /// Returns an error describing the first message that the RPC system sent differently from
+    /// the trace, or the first message from the trace that it has not sent yet.
+    pub fn check(&self) -> capnp::Result<()> {
+        let state = self.state.borrow();
+        if let Some(ref e) = state.error {
+            return Err(e.clone());
+        }
+        match state.outgoing.front() {
+            Some((_, expected)) => Err(Error::failed(format!(
+                "replay is still waiting for {expected:?}"
+            ))),
+            None => Ok(()),
+        }
+    }
+}
+
+struct ReplayOutgoingMessage {
+    message: Builder<HeapAllocator>,
+    state: Weak<RefCell<ReplayState>>,
+}
+
+impl crate::OutgoingMessage for ReplayOutgoingMessage {
+    fn get_body(&mut self) -> capnp::Result<any_pointer::Builder<'_>> {
+        self.message.get_root()
+    }
+
+    fn get_body_as_reader(&self) -> capnp::Result<any_pointer::Reader<'_>> {
+        self.message.get_root_as_reader()
+    }
+
+    fn send(self: Box<Self>) -> (Promise<(), Error>, Rc<Builder<HeapAllocator>>) {
+        let message = Rc::new(self.message);
+        if let Some(state) = self.state.upgrade() {
+            state.borrow_mut().check_sent(message.clone());
+        }
+        (Promise::ok(()), message)
+    }
+
+    fn take(self: Box<Self>) -> Builder<HeapAllocator> {
+        self.message
+    }
+
+    fn size_in_words(&self) -> usize {
+        self.message.size_in_words()
+    }
+}
+
+struct ReplayConnectionInner<VatId> {
+    peer: VatId,
+    state: Rc<RefCell<ReplayState>>,
+    on_disconnect_fulfiller: Option<oneshot::Sender<()>>,
+}
+
+impl<VatId> Drop for ReplayConnectionInner<VatId> {
+    fn drop(&mut self) {
+        if let Some(fulfiller) = self.on_disconnect_fulfiller.take() {
+            let _ = fulfiller.send(());
+        }
+    }
+}
+
+struct ReplayConnection<VatId> {
+    inner: Rc<ReplayConnectionInner<VatId>>,
+}
+
+impl<VatId> crate::Connection<VatId> for ReplayConnection<VatId>
+where
+    VatId: Clone,
+{
+    fn get_peer_vat_id(&self) -> VatId {
+        self.inner.peer.clone()
+    }
+
+    fn new_outgoing_message(
+        &mut self,
+        first_segment_word_size: u32,
+    ) -> Box<dyn crate::OutgoingMessage> {
+        Box::new(ReplayOutgoingMessage {
+            message: Builder::new(
+                HeapAllocator::new().first_segment_words(first_segment_word_size),
+            ),
+            state: Rc::downgrade(&self.inner.state),
+        })
+    }
+
+    fn receive_incoming_message(
+        &mut self,
+    ) -> Promise<Option<Box<dyn crate::IncomingMessage>>, Error> {
+        let state = self.inner.state.clone();
+        Promise::from_future(futures::future::poll_fn(move |cx| {
+            state
+                .borrow_mut()
+                .poll_receive(cx.waker())
+                .map_ok(|body| Some(into_incoming_message(body)))
+        }))
+    }
+
+    fn shutdown(&mut self, _result: capnp::Result<()>) -> Promise<(), Error> {
+        Promise::ok(())
+    }
+}
+
+/// A [`VatNetwork`](crate::VatNetwork) with a single connection that plays back the incoming
+/// messages of a [`Trace`] and compares the messages that the RPC system sends with the
+/// outgoing messages of the trace.
+///
+/// Each incoming message is delivered only once the RPC system has sent every message that
+/// preceded it in the trace, so the RPC system sees the same interleaving as when the trace was
+/// recorded. When the trace runs out, the connection stays open and idle.
+pub struct ReplayVatNetwork<VatId>
+where
+    VatId: 'static,
+{
+    connection: Option<ReplayConnection<VatId>>,
+    weak_inner: Weak<ReplayConnectionInner<VatId>>,
+    self_id: VatId,
+    disconnect_promise: futures::future::Shared<Promise<(), Error>>,
+}
+
+impl<VatId> ReplayVatNetwork<VatId> {
+    /// Replays `trace` as seen by vat `self_id`, connected to vat `peer_id`.
+    pub fn new(trace: &Trace, self_id: VatId, peer_id: VatId) -> (Self, Replay) {
+        let mut incoming = VecDeque::new();
+        let mut outgoing = VecDeque::new();
+        for (i, event) in trace.events().into_iter().enumerate() {
+            match event.direction {
+                Direction::Incoming => incoming.push_back((i, event.body)),
+                Direction::Outgoing => outgoing.push_back((i, event)),
+            }
+        }
+        let state = Rc::new(RefCell::new(ReplayState {
+            incoming,
+            outgoing,
+            error: None,
+            receive_waker: None,
+        }));
+
+        let (fulfiller, disconnect_promise) = oneshot::channel();
+        let inner = Rc::new(ReplayConnectionInner {
+            peer: peer_id,
+            state: state.clone(),
+            on_disconnect_fulfiller: Some(fulfiller),
+        });
+        let network = Self {
+            weak_inner: Rc::downgrade(&inner),
+            connection: Some(ReplayConnection { inner }),
+            self_id,
+            disconnect_promise: Promise::from_future(disconnect_promise.map(|_| Ok(()))).shared(),
+        };
+        (network, Replay { state })
+    }
+}
+
+impl<VatId> crate::VatNetwork<VatId> for ReplayVatNetwork<VatId>
+where
+    VatId: Clone + PartialEq,
+{
+    fn connect(&mut self, host_id: VatId) -> Option<Box<dyn crate::Connection<VatId>>> {
+        if host_id == self.self_id {
+            return None;
+        }
+        let inner = self
+            .weak_inner
+            .upgrade()
+            .expect("tried to reconnect a disconnected replay vat network");
+        Some(Box::new(ReplayConnection { inner }))
+    }
+
+    fn accept(&mut self) -> Promise<Box<dyn crate::Connection<VatId>>, Error> {
+        match self.connection.take() {
+            Some(c) => Promise::ok(Box::new(c) as Box<dyn crate::Connection<VatId>>),
+            None => Promise::from_future(futures::future::pending()),
+        }
+    }
+
+    fn drive_until_shutdown(&mut self) -> Promise<(), Error> {
+        Promise::from_future(self.disconnect_promise.clone())
+    }
+}
@@ -23,7 +23,7 @@ use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use capnp::any_pointer;
-use capnp::capability::Promise;
+use capnp::capability::{CallHints, Promise};
 use capnp::private::capability::{
     ClientHook, ParamsHook, PipelineHook, PipelineOp, RequestHook, ResponseHook, ResultsHook,
 };
@@ -38,13 +38,19 @@ use std::collections::binary_heap::BinaryHeap;
 use std::collections::hash_map::{self, HashMap};
 use std::mem;
 use std::rc::{Rc, Weak};
+use std::time::{Duration, Instant};
 
 use crate::attach::Attach;
+use crate::interceptor::{self, CallInfo, Interceptor, ReturnInfo, ReturnKind};
+use crate::keepalive::Keepalive;
 use crate::local::ResultsDoneHook;
 use crate::rpc_capnp::{
     bootstrap, call, cap_descriptor, disembargo, exception, finish, message, message_target,
     payload, promised_answer, resolve, return_,
 };
+use crate::stats::{
+    AnswerStats, ConnectionStats, ExportStats, ImportStats, QuestionKind, QuestionStats,
+};
 use crate::task_set::TaskSet;
 use crate::{broken, local, queued};
 
@@ -53,6 +59,43 @@ pub(crate) type AnswerId = QuestionId;
 pub(crate) type ExportId = u32;
 pub(crate) type ImportId = ExportId;
 
+/// A file descriptor sent or received along with a message. Only Unix transports can carry
+/// them, so elsewhere there are none.
+#[cfg(unix)]
+type AttachedFd = std::os::fd::OwnedFd;
+#[cfg(not(unix))]
+enum AttachedFd {}
+
+#[cfg(unix)]
+fn attached_fds(message: &dyn crate::IncomingMessage) -> &[AttachedFd] {
+    message.get_attached_fds()
+}
+
+#[cfg(not(unix))]
+fn attached_fds(_message: &dyn crate::IncomingMessage) -> &[AttachedFd] {
+    &[]
+}
+
+#[cfg(unix)]
+fn set_fds(message: &mut dyn crate::OutgoingMessage, fds: Vec<AttachedFd>) {
+    if !fds.is_empty() {
+        message.set_fds(fds);
+    }
+}
+
+#[cfg(not(unix))]
+fn set_fds(_message: &mut dyn crate::OutgoingMessage, _fds: Vec<AttachedFd>) {}
+
+#[cfg(unix)]
+fn dup_fd(fd: &AttachedFd) -> ::capnp::Result<AttachedFd> {
+    Ok(fd.try_clone()?)
+}
+
+#[cfg(not(unix))]
+fn dup_fd(fd: &AttachedFd) -> ::capnp::Result<AttachedFd> {
+    match *fd {}
+}
+
 pub(crate) struct ImportTable<T> {
     slots: HashMap<u32, T>,
 }
@@ -157,6 +200,13 @@ where
 
     /// If true, don't send a Finish message.
     skip_finish: bool,
+
+    /// Set if the call was reported to an interceptor, holding whatever metadata it attached.
+    call_metadata: Option<Option<interceptor::Metadata>>,
+
+    // For `stats::Inspector::connection_stats()`.
+    kind: QuestionKind,
+    created: Instant,
 }
 
 impl<VatId> Question<VatId> {
@@ -167,6 +217,9 @@ impl<VatId> Question<VatId> {
             is_tail_call: false,
             self_ref: None,
             skip_finish: false,
+            call_metadata: None,
+            kind: QuestionKind::Bootstrap,
+            created: Instant::now(),
         }
     }
 }
@@ -261,6 +314,13 @@ where
     // List of exports that were sent in the results.  If the finish has `releaseResultCaps` these
     // will need to be released.
     result_exports: Vec<ExportId>,
+
+    // Set if the call was reported to an interceptor, holding whatever metadata it attached.
+    call_metadata: Option<Option<interceptor::Metadata>>,
+
+    // For `stats::Inspector::connection_stats()`.
+    kind: QuestionKind,
+    created: Instant,
 }
 
 impl<VatId> Answer<VatId> {
@@ -272,6 +332,9 @@ impl<VatId> Answer<VatId> {
             received_finish: Rc::new(Cell::new(false)),
             call_completion_promise: None,
             result_exports: Vec::new(),
+            call_metadata: None,
+            kind: QuestionKind::Bootstrap,
+            created: Instant::now(),
         }
     }
 }
@@ -404,6 +467,20 @@ fn remote_exception_to_error(exception: exception::Reader) -> Error {
     }
 }
 
+fn return_info(ret: return_::Reader, size_in_words: usize) -> ReturnInfo {
+    let kind = match ret.which() {
+        Ok(return_::Results(_)) => ReturnKind::Results,
+        Ok(return_::Exception(Ok(e))) => ReturnKind::Exception(remote_exception_to_error(e).kind),
+        Ok(return_::Canceled(())) => ReturnKind::Canceled,
+        _ => ReturnKind::Other,
+    };
+    ReturnInfo {
+        question_id: ret.get_answer_id(),
+        kind,
+        size_in_words,
+    }
+}
+
 pub(crate) struct ConnectionErrorHandler<VatId>
 where
     VatId: 'static,
@@ -449,6 +526,36 @@ where
     disconnect_promise: RefCell<Option<future::Shared<Promise<(), Error>>>>,
 
     client_downcast_map: RefCell<HashMap<usize, WeakClient<VatId>>>,
+
+    // Limit on `call_words_in_flight`, shared with the `RpcSystem`.
+    // See `RpcSystem::set_flow_limit()`.
+    flow_limit: Rc<Cell<usize>>,
+
+    // Total size of the incoming calls that have not completed yet.
+    call_words_in_flight: Cell<usize>,
+
+    // Set while the message loop is paused because `call_words_in_flight` exceeds
+    // `flow_limit`. Fulfilling it resumes the loop.
+    flow_waiter: RefCell<Option<oneshot::Sender<()>>>,
+
+    // Shared with the `RpcSystem`. See `RpcSystem::set_interceptor()`.
+    interceptor: Rc<RefCell<Option<Rc<dyn Interceptor>>>>,
+
+    // Number of messages received so far, so that keepalive can tell whether the peer is alive.
+    messages_received: Cell<u64>,
+
+    // Dropping this stops the keepalive task, if there is one.
+    keepalive_canceler: RefCell<Option<oneshot::Sender<()>>>,
+
+    // Number of incoming calls that have not completed yet.
+    calls_in_flight: Cell<usize>,
+
+    // Set once `drain()` has been called. New calls are then rejected, and counted here.
+    draining: Cell<bool>,
+    drain_rejected_calls: Cell<usize>,
+
+    // Set while `drain()` waits for `calls_in_flight` to reach zero.
+    drain_waiter: RefCell<Option<oneshot::Sender<()>>>,
 }
 
 impl<VatId> ConnectionState<VatId> {
@@ -456,6 +563,8 @@ impl<VatId> ConnectionState<VatId> {
         bootstrap_cap: Box<dyn ClientHook>,
         connection: Box<dyn crate::Connection<VatId>>,
         disconnect_fulfiller: oneshot::Sender<Promise<(), Error>>,
+        flow_limit: Rc<Cell<usize>>,
+        interceptor: Rc<RefCell<Option<Rc<dyn Interceptor>>>>,
     ) -> (TaskSet<Error>, Rc<Self>) {
         let state = Rc::new(Self {
             bootstrap_cap,
@@ -470,6 +579,16 @@ impl<VatId> ConnectionState<VatId> {
             disconnect_fulfiller: RefCell::new(Some(disconnect_fulfiller)),
             disconnect_promise: RefCell::new(None),
             client_downcast_map: RefCell::new(HashMap::new()),
+            flow_limit,
+            call_words_in_flight: Cell::new(0),
+            flow_waiter: RefCell::new(None),
+            interceptor,
+            messages_received: Cell::new(0),
+            keepalive_canceler: RefCell::new(None),
+            calls_in_flight: Cell::new(0),
+            draining: Cell::new(false),
+            drain_rejected_calls: Cell::new(0),
+            drain_waiter: RefCell::new(None),
         });
         let (mut handle, tasks) =
             TaskSet::new(Box::new(ConnectionErrorHandler::new(Rc::downgrade(&state))));
@@ -489,6 +608,72 @@ impl<VatId> ConnectionState<VatId> {
         }
     }
 
+    fn interceptor(&self) -> Option<Rc<dyn Interceptor>> {
+        self.interceptor.borrow().clone()
+    }
+
+    // Reports an outgoing call to the interceptor, if there is one, and lets it write to the
+    // call's parameters. The result should be stored in `Question::call_metadata`.
+    fn intercept_outgoing_call(
+        &self,
+        message: &mut Box<dyn crate::OutgoingMessage>,
+    ) -> Option<Option<interceptor::Metadata>> {
+        let interceptor = self.interceptor()?;
+        let mut call = get_call(message).ok()?;
+        let mut info = CallInfo {
+            interface_id: call.reborrow().get_interface_id(),
+            method_id: call.reborrow().get_method_id(),
+            question_id: call.reborrow().get_question_id(),
+            size_in_words: 0,
+        };
+        interceptor.outgoing_call_params(&info, call.get_params().ok()?.get_content());
+        let body = message.get_body_as_reader().ok()?;
+        info.size_in_words = body.target_size().ok()?.word_count as usize;
+        Some(interceptor.outgoing_call(&info))
+    }
+
+    // Reports an outgoing `Return` to the interceptor, if the call it answers was reported.
+    fn intercept_outgoing_return(&self, message: &dyn crate::OutgoingMessage) {
+        let Ok(body) = message.get_body_as_reader() else {
+            return;
+        };
+        let Ok(message::Return(Ok(ret))) = body
+            .get_as::<message::Reader>()
+            .and_then(|m| m.which().map_err(Error::from))
+        else {
+            return;
+        };
+        let answer_id = ret.get_answer_id();
+        let Some(metadata) = self
+            .answers
+            .borrow_mut()
+            .slots
+            .get_mut(&answer_id)
+            .and_then(|answer| answer.call_metadata.take())
+        else {
+            return;
+        };
+        if let (Some(interceptor), Ok(size)) = (self.interceptor(), body.target_size()) {
+            interceptor.outgoing_return(&return_info(ret, size.word_count as usize), metadata);
+        }
+    }
+
+    // Reports an incoming `Return` to the interceptor, if the call it answers was reported.
+    fn intercept_incoming_return(&self, ret: return_::Reader, size_in_words: usize) {
+        let question_id = ret.get_answer_id();
+        let Some(metadata) = self
+            .questions
+            .borrow_mut()
+            .find(question_id)
+            .and_then(|question| question.call_metadata.take())
+        else {
+            return;
+        };
+        if let Some(interceptor) = self.interceptor() {
+            interceptor.incoming_return(&return_info(ret, size_in_words), metadata);
+        }
+    }
+
     fn disconnect(&self, error: ::capnp::Error) {
         if self.connection.borrow().is_err() {
             // Already disconnected.
@@ -556,6 +741,7 @@ impl<VatId> ConnectionState<VatId> {
         drop(pipelines_to_release);
         drop(clients_to_release);
         drop(resolve_ops_to_release);
+        drop(self.keepalive_canceler.borrow_mut().take());
         // TODO drop tail calls
 
         match *self.connection.borrow_mut() {
@@ -682,6 +868,14 @@ impl<VatId> ConnectionState<VatId> {
             match promise.await? {
                 Some(m) => {
                     Self::handle_message(&weak_state, m)?;
+                    let flow_blocked = weak_state
+                        .upgrade()
+                        .expect("message loop outlived connection state?")
+                        .wait_for_flow();
+                    if let Some(unblocked) = flow_blocked {
+                        // Don't read any more calls until some of the ones in progress are done.
+                        let _ = unblocked.await;
+                    }
                     weak_state
                         .upgrade()
                         .expect("message loop outlived connection state?")
@@ -698,6 +892,221 @@ impl<VatId> ConnectionState<VatId> {
         })
     }
 
+    /// Starts a task that pings the peer whenever the connection has been idle for
+    /// `keepalive.interval`, and disconnects once it has been idle for `keepalive.timeout`.
+    /// Stops any keepalive task that was started earlier.
+    pub(crate) fn start_keepalive(state: &Rc<Self>, keepalive: Keepalive) {
+        let (canceler, canceled) = oneshot::channel::<()>();
+        *state.keepalive_canceler.borrow_mut() = Some(canceler);
+
+        let weak_state = Rc::downgrade(state);
+        let mut last_seen = state.messages_received.get();
+        let mut idle = Duration::ZERO;
+        let task = async move {
+            loop {
+                if keepalive
+                    .timer
+                    .after_delay(keepalive.interval)
+                    .await
+                    .is_err()
+                {
+                    // A broken timer stops keepalive, but the connection itself is fine.
+                    return Ok(());
+                }
+                let Some(state) = weak_state.upgrade() else {
+                    return Ok(());
+                };
+                if state.connection.borrow().is_err() {
+                    return Ok(());
+                }
+                if state.flow_waiter.borrow().is_some() {
+                    // The flow limit has paused the message loop, so whatever the peer sent is
+                    // still waiting to be read. The clock restarts once the loop resumes.
+                    idle = Duration::ZERO;
+                    continue;
+                }
+                let received = state.messages_received.get();
+                if received != last_seen {
+                    last_seen = received;
+                    idle = Duration::ZERO;
+                    continue;
+                }
+                idle += keepalive.interval;
+                if idle >= keepalive.timeout {
+                    state.disconnect(Error::disconnected(format!(
+                        "Peer did not respond to keepalive for {idle:?}."
+                    )));
+                    return Ok(());
+                }
+                state.send_ping()?;
+            }
+        };
+        state.add_task(future::select(Box::pin(task), canceled).map(|r| match r {
+            future::Either::Left((r, _)) => r,
+            future::Either::Right(_) => Ok(()),
+        }));
+    }
+
+    // Sends a `Bootstrap` message and cancels it right away. The peer must still answer with a
+    // `Return`, which is all that keepalive needs to know that it is there.
+    fn send_ping(&self) -> capnp::Result<()> {
+        let question_id = self.questions.borrow_mut().push(Question::new());
+        let mut message = self.new_outgoing_message(5)?;
+        message
+            .get_body()?
+            .init_as::<message::Builder>()
+            .init_bootstrap()
+            .set_question_id(question_id);
+        let _ = message.send();
+
+        let mut message = self.new_outgoing_message(5)?;
+        {
+            let mut builder = message
+                .get_body()?
+                .init_as::<message::Builder>()
+                .init_finish();
+            builder.set_question_id(question_id);
+            builder.set_release_result_caps(true);
+        }
+        let _ = message.send();
+        Ok(())
+    }
+
+    pub(crate) fn stats(&self) -> ConnectionStats {
+        let now = Instant::now();
+        let exports = self
+            .exports
+            .borrow()
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(id, export)| {
+                Some(ExportStats {
+                    id: id as u32,
+                    refcount: export.as_ref()?.refcount,
+                })
+            })
+            .collect();
+        let imports = self
+            .imports
+            .borrow()
+            .slots
+            .iter()
+            .map(|(&id, import)| ImportStats {
+                id,
+                remote_refcount: import
+                    .import_client
+                    .upgrade()
+                    .map_or(0, |c| c.borrow().remote_ref_count),
+                is_promise: import.promise_client_to_resolve.is_some(),
+            })
+            .collect();
+        let questions = self
+            .questions
+            .borrow()
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(id, question)| {
+                let question = question.as_ref()?;
+                Some(QuestionStats {
+                    id: id as u32,
+                    kind: question.kind,
+                    age: now.saturating_duration_since(question.created),
+                    awaiting_return: question.is_awaiting_return,
+                })
+            })
+            .collect();
+        let answers = self
+            .answers
+            .borrow()
+            .slots
+            .iter()
+            .map(|(&id, answer)| AnswerStats {
+                id,
+                kind: answer.kind,
+                age: now.saturating_duration_since(answer.created),
+                return_sent: answer.return_has_been_sent,
+            })
+            .collect();
+        ConnectionStats {
+            exports,
+            imports,
+            questions,
+            answers,
+            embargoes: self.embargoes.borrow().iter().count(),
+        }
+    }
+
+    /// Rejects new incoming calls, waits until the calls in progress have completed or `deadline`
+    /// has passed, and then disconnects.
+    pub(crate) async fn drain(
+        state: Rc<Self>,
+        deadline: Promise<(), Error>,
+    ) -> Result<DrainReport, Error> {
+        state.start_draining();
+        let started = state.calls_in_flight.get();
+        if started > 0 {
+            let (fulfiller, drained) = oneshot::channel();
+            *state.drain_waiter.borrow_mut() = Some(fulfiller);
+            let _ = future::select(drained, deadline).await;
+        }
+
+        let canceled_calls = state.calls_in_flight.get();
+        let abandoned_questions = state
+            .questions
+            .borrow()
+            .iter()
+            .filter(|q| q.is_awaiting_return && q.self_ref.is_some())
+            .count();
+        state.disconnect(Error::disconnected("RpcSystem was drained.".into()));
+        let shutdown_promise = state.disconnect_promise.borrow().clone();
+        if let Some(shutdown_promise) = shutdown_promise {
+            shutdown_promise.await?;
+        }
+        Ok(DrainReport {
+            completed_calls: started.saturating_sub(canceled_calls),
+            canceled_calls,
+            rejected_calls: state.drain_rejected_calls.get(),
+            abandoned_questions,
+        })
+    }
+
+    /// Starts rejecting new incoming calls, ahead of a call to `drain()`.
+    pub(crate) fn start_draining(&self) {
+        self.draining.set(true);
+    }
+
+    /// Wakes up `drain()` if it is waiting and no incoming calls are left.
+    fn check_drained(&self) {
+        if self.calls_in_flight.get() == 0 {
+            if let Some(fulfiller) = self.drain_waiter.borrow_mut().take() {
+                let _ = fulfiller.send(());
+            }
+        }
+    }
+
+    /// If incoming calls in progress exceed the flow limit, returns a promise that resolves once
+    /// they no longer do.
+    fn wait_for_flow(&self) -> Option<oneshot::Receiver<()>> {
+        if self.call_words_in_flight.get() > self.flow_limit.get() {
+            let (fulfiller, promise) = oneshot::channel();
+            *self.flow_waiter.borrow_mut() = Some(fulfiller);
+            Some(promise)
+        } else {
+            None
+        }
+    }
+
+    /// Resumes the message loop if it is waiting in `wait_for_flow()` and may continue.
+    pub(crate) fn check_flow(&self) {
+        if self.call_words_in_flight.get() <= self.flow_limit.get() {
+            if let Some(fulfiller) = self.flow_waiter.borrow_mut().take() {
+                let _ = fulfiller.send(());
+            }
+        }
+    }
+
     fn send_unimplemented(
         connection_state: &Rc<Self>,
         message: &dyn crate::IncomingMessage,
@@ -760,6 +1169,7 @@ impl<VatId> ConnectionState<VatId> {
 
         let mut response = connection_state.new_outgoing_message(10)?;
 
+        let mut fds = Vec::new();
         let result_exports = {
             let mut ret = response
                 .get_body()?
@@ -777,8 +1187,9 @@ impl<VatId> ConnectionState<VatId> {
             }
             assert_eq!(cap_table.len(), 1);
 
-            Self::write_descriptors(connection_state, &cap_table, payload)
+            Self::write_descriptors(connection_state, &cap_table, payload, &mut fds)
         };
+        set_fds(&mut *response, fds);
 
         let slots = &mut connection_state.answers.borrow_mut().slots;
         let hash_map::Entry::Vacant(slot) = slots.entry(answer_id) else {
@@ -831,9 +1242,13 @@ impl<VatId> ConnectionState<VatId> {
         Ok(())
     }
 
-    fn handle_resolve(connection_state: &Rc<Self>, resolve: resolve::Reader) -> capnp::Result<()> {
+    fn handle_resolve(
+        connection_state: &Rc<Self>,
+        resolve: resolve::Reader,
+        fds: &[AttachedFd],
+    ) -> capnp::Result<()> {
         let replacement_or_error = match resolve.which()? {
-            resolve::Cap(c) => match Self::receive_cap(connection_state, c?)? {
+            resolve::Cap(c) => match Self::receive_cap(connection_state, c?, fds)? {
                 Some(cap) => Ok(cap),
                 None => {
                     return Err(Error::failed(
@@ -950,6 +1365,10 @@ impl<VatId> ConnectionState<VatId> {
             ));
         };
 
+        connection_state
+            .messages_received
+            .set(connection_state.messages_received.get() + 1);
+
         let reader = message.get_body()?.get_as::<message::Reader>()?;
         match reader.which() {
             Ok(message::Unimplemented(message)) => {
@@ -961,7 +1380,19 @@ impl<VatId> ConnectionState<VatId> {
             }
             Ok(message::Call(call)) => {
                 let call = call?;
-                let capability = connection_state.get_message_target(call.get_target()?)?;
+                let mut capability = connection_state.get_message_target(call.get_target()?)?;
+                let rejected = connection_state.draining.get();
+                if rejected {
+                    let rejected = &connection_state.drain_rejected_calls;
+                    rejected.set(rejected.get() + 1);
+                    capability = broken::new_cap(Error::overloaded(
+                        "Connection is draining and accepts no new calls.".into(),
+                    ));
+                }
+                let hints = CallHints {
+                    no_promise_pipelining: call.get_no_promise_pipelining(),
+                    only_promise_pipeline: call.get_only_promise_pipeline(),
+                };
                 let (interface_id, method_id, question_id, cap_table_array, redirect_results) = {
                     let redirect_results = match call.get_send_results_to().which()? {
                         call::send_results_to::Caller(()) => false,
@@ -978,7 +1409,11 @@ impl<VatId> ConnectionState<VatId> {
                         call.get_interface_id(),
                         call.get_method_id(),
                         call.get_question_id(),
-                        Self::receive_caps(&connection_state, payload.get_cap_table()?)?,
+                        Self::receive_caps(
+                            &connection_state,
+                            payload.get_cap_table()?,
+                            attached_fds(&*message),
+                        )?,
                         redirect_results,
                     )
                 };
@@ -994,9 +1429,27 @@ impl<VatId> ConnectionState<VatId> {
                     )));
                 }
 
-                let params = Params::new(message, cap_table_array);
+                let mut answer = Answer::new();
+                answer.kind = QuestionKind::Call {
+                    interface_id,
+                    method_id,
+                };
+                if let Some(interceptor) = connection_state.interceptor() {
+                    let info = CallInfo {
+                        interface_id,
+                        method_id,
+                        question_id,
+                        size_in_words: message.size_in_words(),
+                    };
+                    let params = call.get_params()?.get_content();
+                    answer.call_metadata =
+                        Some(interceptor.incoming_call_with_params(&info, params));
+                }
 
-                let answer = Answer::new();
+                // A rejected call is not counted, so that `drain()` does not wait for it.
+                let call_words = (!rejected)
+                    .then(|| CallWordsInFlight::new(&connection_state, message.size_in_words()));
+                let params = Params::new(message, cap_table_array);
 
                 let (results_inner_fulfiller, results_inner_promise) = oneshot::channel();
                 let results_inner_promise = results_inner_promise.map_err(crate::canceled_to_error);
@@ -1009,6 +1462,7 @@ impl<VatId> ConnectionState<VatId> {
                     results_inner_fulfiller,
                     answer.received_finish.clone(),
                     Some(pipeline_sender.weak_clone()),
+                    hints.only_promise_pipeline,
                 );
 
                 let (redirected_results_done_promise, redirected_results_done_fulfiller) =
@@ -1049,17 +1503,22 @@ impl<VatId> ConnectionState<VatId> {
                             }
                         }
                         Promise::ok(())
-                    });
+                    })
+                    .attach(call_words);
 
                 let fork = promise.shared();
-                pipeline.drive(fork.clone());
 
                 {
                     let slots = &mut connection_state.answers.borrow_mut().slots;
                     let Some(answer) = slots.get_mut(&question_id) else {
                         unreachable!()
                     };
-                    answer.pipeline = Some(Box::new(pipeline));
+                    // If the caller has promised not to pipeline on this answer, there is no
+                    // need to keep the results around once we have returned.
+                    if !hints.no_promise_pipelining {
+                        pipeline.drive(fork.clone());
+                        answer.pipeline = Some(Box::new(pipeline));
+                    }
                     if redirect_results {
                         answer.redirected_results = redirected_results_done_promise;
                         // More to do here?
@@ -1072,6 +1531,7 @@ impl<VatId> ConnectionState<VatId> {
             Ok(message::Return(oret)) => {
                 let ret = oret?;
                 let question_id = ret.get_answer_id();
+                connection_state.intercept_incoming_return(ret, message.size_in_words());
 
                 let mut questions = connection_state.questions.borrow_mut();
                 match questions.find(question_id) {
@@ -1086,6 +1546,7 @@ impl<VatId> ConnectionState<VatId> {
                                     let cap_table = Self::receive_caps(
                                         &connection_state,
                                         results?.get_cap_table()?,
+                                        attached_fds(&*message),
                                     )?;
 
                                     let question_ref =
@@ -1157,7 +1618,9 @@ impl<VatId> ConnectionState<VatId> {
                 }
             }
             Ok(message::Finish(finish)) => Self::handle_finish(&connection_state, finish?)?,
-            Ok(message::Resolve(resolve)) => Self::handle_resolve(&connection_state, resolve?)?,
+            Ok(message::Resolve(resolve)) => {
+                Self::handle_resolve(&connection_state, resolve?, attached_fds(&*message))?
+            }
             Ok(message::Release(release)) => {
                 let release = release?;
                 connection_state.release_export(release.get_id(), release.get_reference_count())?;
@@ -1396,6 +1859,7 @@ impl<VatId> ConnectionState<VatId> {
 
                     // OK, we have to send a `Resolve` message.
                     let mut message = connection_state.new_outgoing_message(15)?;
+                    let mut fds = Vec::new();
                     {
                         let root: message::Builder = message.get_body()?.get_as()?;
                         let mut resolve = root.init_resolve();
@@ -1404,8 +1868,10 @@ impl<VatId> ConnectionState<VatId> {
                             &connection_state,
                             resolution,
                             resolve.init_cap(),
+                            &mut fds,
                         )?;
                     }
+                    set_fds(&mut *message, fds);
                     let _ = message.send();
                     Ok(())
                 }
@@ -1429,14 +1895,25 @@ impl<VatId> ConnectionState<VatId> {
         state: &Rc<Self>,
         mut inner: Box<dyn ClientHook>,
         mut descriptor: cap_descriptor::Builder,
+        fds: &mut Vec<AttachedFd>,
     ) -> ::capnp::Result<Option<ExportId>> {
         // Find the innermost wrapped capability.
         while let Some(resolved) = inner.get_resolved() {
             inner = resolved;
         }
+        #[cfg(unix)]
+        if let Some(fd) = inner.get_fd() {
+            // An index of 0xff means "no fd", so at most 255 can be attached to one message.
+            if let Ok(index @ 0..=254) = u8::try_from(fds.len()) {
+                // Safety: `inner` keeps the descriptor open while we duplicate it.
+                let fd = unsafe { std::os::fd::BorrowedFd::borrow_raw(fd) };
+                fds.push(fd.try_clone_to_owned()?);
+                descriptor.set_attached_fd(index);
+            }
+        }
         if inner.get_brand() == state.get_brand() {
             if let Some(c) = Client::from_ptr(inner.get_ptr(), state) {
-                return Ok(c.write_descriptor(descriptor));
+                return Ok(c.write_descriptor(descriptor, fds));
             }
             // The hook claims to belong to this connection but the downcast
             // map has no live entry for it (e.g. a stale entry left by a
@@ -1485,6 +1962,7 @@ impl<VatId> ConnectionState<VatId> {
         state: &Rc<Self>,
         cap_table: &[Option<Box<dyn ClientHook>>],
         payload: payload::Builder,
+        fds: &mut Vec<AttachedFd>,
     ) -> Vec<ExportId> {
         let mut cap_table_builder = payload.init_cap_table(cap_table.len() as u32);
         let mut exports = Vec::new();
@@ -1495,6 +1973,7 @@ impl<VatId> ConnectionState<VatId> {
                         state,
                         cap.clone(),
                         cap_table_builder.reborrow().get(idx as u32),
+                        fds,
                     )
                     .unwrap()
                     {
@@ -1509,7 +1988,12 @@ impl<VatId> ConnectionState<VatId> {
         exports
     }
 
-    fn import(state: &Rc<Self>, import_id: ImportId, is_promise: bool) -> Box<dyn ClientHook> {
+    fn import(
+        state: &Rc<Self>,
+        import_id: ImportId,
+        is_promise: bool,
+        fd: Option<AttachedFd>,
+    ) -> Box<dyn ClientHook> {
         let import_client = {
             match state.imports.borrow_mut().slots.entry(import_id) {
                 hash_map::Entry::Occupied(occ) => occ
@@ -1528,6 +2012,11 @@ impl<VatId> ConnectionState<VatId> {
         // We just received a copy of this import ID, so the remote refcount has gone up.
         import_client.borrow_mut().add_remote_ref();
 
+        if let Some(fd) = fd {
+            // The peer attaches the fd each time it sends the capability; keep the first one.
+            import_client.borrow_mut().fd.get_or_insert(fd);
+        }
+
         let mut tmp = state.imports.borrow_mut();
         let Some(import) = tmp.slots.get_mut(&import_id) else {
             unreachable!()
@@ -1586,14 +2075,19 @@ impl<VatId> ConnectionState<VatId> {
     fn receive_cap(
         state: &Rc<Self>,
         descriptor: cap_descriptor::Reader,
+        fds: &[AttachedFd],
     ) -> ::capnp::Result<Option<Box<dyn ClientHook>>> {
+        let fd = match fds.get(usize::from(descriptor.get_attached_fd())) {
+            Some(fd) => Some(dup_fd(fd)?),
+            None => None,
+        };
         match descriptor.which()? {
             cap_descriptor::None(()) => Ok(None),
             cap_descriptor::SenderHosted(sender_hosted) => {
-                Ok(Some(Self::import(state, sender_hosted, false)))
+                Ok(Some(Self::import(state, sender_hosted, false, fd)))
             }
             cap_descriptor::SenderPromise(sender_promise) => {
-                Ok(Some(Self::import(state, sender_promise, true)))
+                Ok(Some(Self::import(state, sender_promise, true, fd)))
             }
             cap_descriptor::ReceiverHosted(receiver_hosted) => {
                 if let Some(exp) = state.exports.borrow_mut().find(receiver_hosted) {
@@ -1626,15 +2120,71 @@ impl<VatId> ConnectionState<VatId> {
     fn receive_caps(
         state: &Rc<Self>,
         cap_table: ::capnp::struct_list::Reader<cap_descriptor::Owned>,
+        fds: &[AttachedFd],
     ) -> ::capnp::Result<Vec<Option<Box<dyn ClientHook>>>> {
         let mut result = Vec::new();
         for idx in 0..cap_table.len() {
-            result.push(Self::receive_cap(state, cap_table.get(idx))?);
+            result.push(Self::receive_cap(state, cap_table.get(idx), fds)?);
         }
         Ok(result)
     }
 }
 
+/// Counts an incoming call against its connection's flow limit, and as in flight for the
+/// purposes of `drain()`, until the call completes.
+struct CallWordsInFlight<VatId>
+where
+    VatId: 'static,
+{
+    connection_state: Weak<ConnectionState<VatId>>,
+    words: usize,
+}
+
+impl<VatId> CallWordsInFlight<VatId> {
+    fn new(connection_state: &Rc<ConnectionState<VatId>>, words: usize) -> Self {
+        let in_flight = &connection_state.call_words_in_flight;
+        in_flight.set(in_flight.get() + words);
+        let calls = &connection_state.calls_in_flight;
+        calls.set(calls.get() + 1);
+        Self {
+            connection_state: Rc::downgrade(connection_state),
+            words,
+        }
+    }
+}
+
+impl<VatId> Drop for CallWordsInFlight<VatId> {
+    fn drop(&mut self) {
+        if let Some(connection_state) = self.connection_state.upgrade() {
+            let in_flight = &connection_state.call_words_in_flight;
+            in_flight.set(in_flight.get() - self.words);
+            connection_state.check_flow();
+            let calls = &connection_state.calls_in_flight;
+            calls.set(calls.get() - 1);
+            connection_state.check_drained();
+        }
+    }
+}
+
+/// What happened to the calls on a connection when it was drained. See
+/// [`RpcSystem::drain()`](crate::RpcSystem::drain).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DrainReport {
+    /// Incoming calls that were in progress when draining started and completed in time.
+    pub completed_calls: usize,
+
+    /// Incoming calls that were still in progress at the deadline. Their callers see the
+    /// connection drop.
+    pub canceled_calls: usize,
+
+    /// Incoming calls that arrived while draining and were rejected with `Overloaded`.
+    pub rejected_calls: usize,
+
+    /// Calls that we had made to the peer and that were still waiting for a `Return` when the
+    /// connection was closed. They fail with `Disconnected`.
+    pub abandoned_questions: usize,
+}
+
 enum DisconnectorState {
     New,
     Disconnecting(future::Shared<Promise<(), Error>>),
@@ -1790,6 +2340,14 @@ where
     cap_table: Vec<Option<Box<dyn ClientHook>>>,
 }
 
+fn call_kind(message: &mut Box<dyn crate::OutgoingMessage>) -> QuestionKind {
+    let call = get_call(message).unwrap().into_reader();
+    QuestionKind::Call {
+        interface_id: call.get_interface_id(),
+        method_id: call.get_method_id(),
+    }
+}
+
 fn get_call(message: &mut Box<dyn crate::OutgoingMessage>) -> ::capnp::Result<call::Builder<'_>> {
     let message_root: message::Builder = message.get_body()?.get_as()?;
     match message_root.which()? {
@@ -1833,16 +2391,20 @@ where
         Promise<Response<VatId>, Error>,
     ) {
         // Build the cap table.
+        let mut fds = Vec::new();
         let exports = ConnectionState::write_descriptors(
             connection_state,
             cap_table,
             get_call(&mut message).unwrap().get_params().unwrap(),
+            &mut fds,
         );
+        set_fds(&mut *message, fds);
 
         // Init the question table.  Do this after writing descriptors to avoid interference.
         let mut question = Question::<VatId>::new();
         question.is_awaiting_return = true;
         question.param_exports = exports;
+        question.kind = call_kind(&mut message);
         question.is_tail_call = is_tail_call;
 
         let question_id = connection_state.questions.borrow_mut().push(question);
@@ -1854,6 +2416,7 @@ where
                 call_builder.get_send_results_to().set_yourself(());
             }
         }
+        let call_metadata = connection_state.intercept_outgoing_call(&mut message);
         let _ = message.send();
         // Make the result promise.
         let (fulfiller, promise) = oneshot::channel::<Promise<Response<VatId>, Error>>();
@@ -1867,6 +2430,7 @@ where
         match connection_state.questions.borrow_mut().slots[question_id as usize] {
             Some(ref mut q) => {
                 q.self_ref = Some(Rc::downgrade(&question_ref));
+                q.call_metadata = call_metadata;
             }
             None => unreachable!(),
         }
@@ -1884,16 +2448,20 @@ where
         flow: Rc<RefCell<Option<Box<dyn crate::FlowController>>>>,
     ) -> Promise<(), Error> {
         // Build the cap table.
+        let mut fds = Vec::new();
         let exports = ConnectionState::write_descriptors(
             connection_state,
             cap_table,
             get_call(&mut message).unwrap().get_params().unwrap(),
+            &mut fds,
         );
+        set_fds(&mut *message, fds);
 
         // Init the question table.  Do this after writing descriptors to avoid interference.
         let mut question = Question::<VatId>::new();
         question.is_awaiting_return = true;
         question.param_exports = exports;
+        question.kind = call_kind(&mut message);
         question.is_tail_call = false;
 
         let question_id = connection_state.questions.borrow_mut().push(question);
@@ -1901,6 +2469,7 @@ where
             let mut call_builder: call::Builder = get_call(&mut message).unwrap();
             call_builder.reborrow().set_question_id(question_id);
         }
+        let call_metadata = connection_state.intercept_outgoing_call(&mut message);
 
         // Make the result promise.
         let (fulfiller, promise) = oneshot::channel::<Promise<Response<VatId>, Error>>();
@@ -1914,6 +2483,7 @@ where
         match connection_state.questions.borrow_mut().slots[question_id as usize] {
             Some(ref mut q) => {
                 q.self_ref = Some(Rc::downgrade(&question_ref));
+                q.call_metadata = call_metadata;
             }
             None => unreachable!(),
         }
@@ -1957,6 +2527,13 @@ impl<VatId> RequestHook for Request<VatId> {
     fn get_brand<'a>(&self) -> usize {
         self.connection_state.get_brand()
     }
+    fn set_hints(&mut self, hints: CallHints) {
+        let mut call_builder = get_call(&mut self.message).unwrap();
+        call_builder.set_no_promise_pipelining(hints.no_promise_pipelining);
+        // `onlyPromisePipeline` is not sent. A peer that honors it may never send a `Return`, so
+        // the question ID could only be reused safely if such calls took their IDs from a
+        // separate space, which is not implemented.
+    }
     fn send(self: Box<Self>) -> ::capnp::capability::RemotePromise<any_pointer::Owned> {
         let tmp = *self;
         let Self {
@@ -1979,6 +2556,10 @@ impl<VatId> RequestHook for Request<VatId> {
                 None,
             );
 
+            replacement.set_hints(CallHints {
+                no_promise_pipelining: call_builder.reborrow().get_no_promise_pipelining(),
+                ..CallHints::default()
+            });
             replacement
                 .set(
                     call_builder
@@ -2324,6 +2905,9 @@ where
     answer_id: AnswerId,
     finish_received: Rc<Cell<bool>>,
     pipeline_sender: Option<queued::PipelineInnerSender>,
+
+    /// The caller will only pipeline on the results, so the `Return` can leave them out.
+    only_promise_pipeline: bool,
 }
 
 impl<VatId> ResultsInner<VatId>
@@ -2379,6 +2963,7 @@ where
         fulfiller: oneshot::Sender<ResultsInner<VatId>>,
         finish_received: Rc<Cell<bool>>,
         pipeline_sender: Option<queued::PipelineInnerSender>,
+        only_promise_pipeline: bool,
     ) -> Self {
         Self {
             inner: Some(ResultsInner {
@@ -2388,6 +2973,7 @@ where
                 answer_id,
                 finish_received,
                 pipeline_sender,
+                only_promise_pipeline,
             }),
             results_done_fulfiller: Some(fulfiller),
         }
@@ -2483,6 +3069,7 @@ impl<VatId> ResultsHook for Results<VatId> {
                         ret.set_release_param_caps(false);
                         ret.set_take_from_other_question(question_id);
                     }
+                    state.intercept_outgoing_return(&*message);
                     let _ = message.send();
 
                     // TODO cleanupanswertable
@@ -2540,6 +3127,7 @@ impl ResultsDone {
                     variant,
                     answer_id,
                     finish_received,
+                    only_promise_pipeline,
                     ..
                 } = results_inner;
                 match variant {
@@ -2565,6 +3153,34 @@ impl ResultsDone {
                                         ret.set_release_param_caps(false);
                                         ret.set_canceled(());
                                     }
+                                    connection_state.intercept_outgoing_return(&*message);
+                                    let _ = message.send();
+                                }
+
+                                connection_state.answer_has_sent_return(answer_id, Vec::new());
+                                Ok(hook)
+                            }
+                            (false, Ok(())) if only_promise_pipeline => {
+                                // Keep the results locally to serve pipelined calls, but send
+                                // an empty `Return`, as the caller will never look at it.
+                                let hook = Box::new(Self::rpc(Rc::new(message.take()), cap_table))
+                                    as Box<dyn ResultsDoneHook>;
+                                pipeline_sender
+                                    .complete(Box::new(local::Pipeline::new(hook.clone())));
+
+                                if let Ok(connection) =
+                                    connection_state.connection.borrow_mut().as_mut()
+                                {
+                                    let mut message = connection.new_outgoing_message(10);
+                                    {
+                                        let root: message::Builder =
+                                            message.get_body()?.get_as()?;
+                                        let mut ret = root.init_return();
+                                        ret.set_answer_id(answer_id);
+                                        ret.set_release_param_caps(false);
+                                        ret.init_results();
+                                    }
+                                    connection_state.intercept_outgoing_return(&*message);
                                     let _ = message.send();
                                 }
 
@@ -2572,6 +3188,7 @@ impl ResultsDone {
                                 Ok(hook)
                             }
                             (false, Ok(())) => {
+                                let mut fds = Vec::new();
                                 let exports = {
                                     let root: message::Builder = message.get_body()?.get_as()?;
                                     let message::Return(Ok(mut ret)) = root.which()? else {
@@ -2590,9 +3207,12 @@ impl ResultsDone {
                                         &connection_state,
                                         &cap_table,
                                         payload,
+                                        &mut fds,
                                     )
                                 };
+                                set_fds(&mut *message, fds);
 
+                                connection_state.intercept_outgoing_return(&*message);
                                 let (_promise, m) = message.send();
                                 connection_state.answer_has_sent_return(answer_id, exports);
                                 let hook =
@@ -2616,6 +3236,7 @@ impl ResultsDone {
                                         let mut exc = ret.init_exception();
                                         from_error(&e, exc.reborrow());
                                     }
+                                    connection_state.intercept_outgoing_return(&*message);
                                     let _ = message.send();
                                 }
                                 connection_state.answer_has_sent_return(answer_id, Vec::new());
@@ -2753,6 +3374,9 @@ where
 
     /// Number of times we've received this import from the peer.
     remote_ref_count: u32,
+
+    /// File descriptor that the peer attached to this capability, if any.
+    fd: Option<AttachedFd>,
 }
 
 impl<VatId> Drop for ImportClient<VatId> {
@@ -2802,6 +3426,7 @@ where
             connection_state: connection_state.clone(),
             import_id,
             remote_ref_count: 0,
+            fd: None,
         }))
     }
 
@@ -3082,7 +3707,11 @@ impl<VatId> Client<VatId> {
         }
     }
 
-    fn write_descriptor(&self, mut descriptor: cap_descriptor::Builder) -> Option<u32> {
+    fn write_descriptor(
+        &self,
+        mut descriptor: cap_descriptor::Builder,
+        fds: &mut Vec<AttachedFd>,
+    ) -> Option<u32> {
         match &self.variant {
             ClientVariant::Import(import_client) => {
                 descriptor.set_receiver_hosted(import_client.borrow().import_id);
@@ -3114,6 +3743,7 @@ impl<VatId> Client<VatId> {
                     &self.connection_state.clone(),
                     promise_client.borrow().cap.clone(),
                     descriptor,
+                    fds,
                 )
                 .unwrap()
             }
@@ -3246,6 +3876,25 @@ impl<VatId> ClientHook for Client<VatId> {
     fn when_resolved(&self) -> Promise<(), Error> {
         default_when_resolved_impl(self)
     }
+
+    #[cfg(unix)]
+    fn get_fd(&self) -> Option<std::os::fd::RawFd> {
+        use std::os::fd::AsRawFd;
+        match &self.variant {
+            ClientVariant::Import(import_client) => {
+                import_client.borrow().fd.as_ref().map(AsRawFd::as_raw_fd)
+            }
+            ClientVariant::Pipeline(_pipeline_client) => None,
+            ClientVariant::Promise(promise_client) => {
+                let promise_client = promise_client.borrow();
+                if promise_client.is_resolved {
+                    promise_client.cap.get_fd()
+                } else {
+                    None
+                }
+            }
+        }
+    }
 }
 
 pub(crate) fn default_when_resolved_impl<C>(client: &C) -> Promise<(), Error>
@@ -0,0 +1,137 @@
+//! Snapshots of the tables that an `RpcSystem` keeps for its connection, for tracking down
+//! capabilities and calls that are never released. See
+//! [`RpcSystem::get_inspector()`](crate::RpcSystem::get_inspector).
+
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::time::Duration;
+
+use crate::rpc::ConnectionState;
+
+/// What a question or answer is for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum QuestionKind {
+    /// A `Bootstrap` message. Keepalive pings are also bootstrap questions.
+    Bootstrap,
+    Call {
+        interface_id: u64,
+        method_id: u16,
+    },
+}
+
+/// A capability that we have sent to the peer and that it has not released yet.
+///
+/// Unlike questions and answers, exports and imports carry no interface ID: the protocol does not
+/// send one along with a capability, and a capability does not know which interface it
+/// implements. The calls made on a capability show up in [`QuestionStats`] and [`AnswerStats`].
+#[derive(Clone, Copy, Debug)]
+pub struct ExportStats {
+    pub id: u32,
+
+    /// How many times the capability has been sent to the peer without being released.
+    pub refcount: u32,
+}
+
+/// A capability that the peer has sent to us. See [`ExportStats`] for why there is no interface
+/// ID.
+#[derive(Clone, Copy, Debug)]
+pub struct ImportStats {
+    pub id: u32,
+
+    /// How many times the peer has sent us the capability. These references are all released
+    /// together once we no longer hold the capability.
+    pub remote_refcount: u32,
+
+    /// Whether the capability is a promise that has not resolved yet.
+    pub is_promise: bool,
+}
+
+/// A call or bootstrap request that we have made to the peer.
+#[derive(Clone, Copy, Debug)]
+pub struct QuestionStats {
+    pub id: u32,
+    pub kind: QuestionKind,
+
+    /// How long ago the question was sent.
+    pub age: Duration,
+
+    /// False once the peer has sent a `Return`. The question stays in the table until we also
+    /// send a `Finish`, which happens when the response is dropped.
+    pub awaiting_return: bool,
+}
+
+/// A call or bootstrap request that the peer has made to us.
+#[derive(Clone, Copy, Debug)]
+pub struct AnswerStats {
+    pub id: u32,
+    pub kind: QuestionKind,
+
+    /// How long ago the question was received.
+    pub age: Duration,
+
+    /// False while the call is still running. The answer stays in the table until the peer
+    /// also sends a `Finish`.
+    pub return_sent: bool,
+}
+
+/// The contents of a connection's tables at one point in time.
+#[derive(Clone, Debug, Default)]
+pub struct ConnectionStats {
+    pub exports: Vec<ExportStats>,
+    pub imports: Vec<ImportStats>,
+    pub questions: Vec<QuestionStats>,
+    pub answers: Vec<AnswerStats>,
+
+    /// Number of embargoes that have not been lifted yet.
+    pub embargoes: usize,
+}
+
+impl ConnectionStats {
+    /// Returns true if no capabilities, calls or embargoes remain, as should be the case once
+    /// everything that used the connection has been dropped.
+    pub fn is_empty(&self) -> bool {
+        self.exports.is_empty()
+            && self.imports.is_empty()
+            && self.questions.is_empty()
+            && self.answers.is_empty()
+            && self.embargoes == 0
+    }
+}
+
+/// Takes snapshots of an `RpcSystem`'s connection. Cloning an `Inspector` yields another handle
+/// to the same `RpcSystem`.
+///
+/// An `Inspector` does not keep the connection alive, so it may safely outlive the `RpcSystem`.
+pub struct Inspector<VatId>
+where
+    VatId: 'static,
+{
+    connection_state: Weak<RefCell<Option<Rc<ConnectionState<VatId>>>>>,
+}
+
+impl<VatId> Clone for Inspector<VatId> {
+    fn clone(&self) -> Self {
+        Self {
+            connection_state: self.connection_state.clone(),
+        }
+    }
+}
+
+impl<VatId> Inspector<VatId> {
+    pub(crate) fn new(connection_state: &Rc<RefCell<Option<Rc<ConnectionState<VatId>>>>>) -> Self {
+        Self {
+            connection_state: Rc::downgrade(connection_state),
+        }
+    }
+
+    /// Returns the current contents of the connection's tables, or `None` if there is no
+    /// connection, either because none has been made yet, or because it has been closed, or
+    /// because the `RpcSystem` has gone away.
+    pub fn connection_stats(&self) -> Option<ConnectionStats> {
+        self.connection_state
+            .upgrade()?
+            .borrow()
+            .as_ref()
+            .map(|connection_state| connection_state.stats())
+    }
+}
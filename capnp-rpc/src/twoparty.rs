@@ -24,21 +24,32 @@
 
 use capnp::capability::Promise;
 use capnp::message::ReaderOptions;
+use capnp_futures::framing::{self, Framing};
 use futures::channel::oneshot;
 use futures::{AsyncRead, AsyncWrite, FutureExt, TryFutureExt};
 
 use std::cell::RefCell;
 use std::rc::{Rc, Weak};
 
+#[cfg(unix)]
+pub mod unix;
+pub mod websocket;
+
 pub type VatId = crate::rpc_twoparty_capnp::Side;
 
 struct IncomingMessage {
     message: ::capnp::message::Reader<capnp::serialize::OwnedSegments>,
+    #[cfg(unix)]
+    fds: Vec<std::os::fd::OwnedFd>,
 }
 
 impl IncomingMessage {
     pub(crate) fn new(message: ::capnp::message::Reader<capnp::serialize::OwnedSegments>) -> Self {
-        Self { message }
+        Self {
+            message,
+            #[cfg(unix)]
+            fds: Vec::new(),
+        }
     }
 }
 
@@ -46,11 +57,24 @@ impl crate::IncomingMessage for IncomingMessage {
     fn get_body(&self) -> ::capnp::Result<::capnp::any_pointer::Reader<'_>> {
         self.message.get_root()
     }
+
+    fn size_in_words(&self) -> usize {
+        self.message.size_in_words()
+    }
+
+    #[cfg(unix)]
+    fn get_attached_fds(&self) -> &[std::os::fd::OwnedFd] {
+        &self.fds
+    }
 }
 
 struct OutgoingMessage {
     message: ::capnp::message::Builder<::capnp::message::HeapAllocator>,
     sender: ::capnp_futures::Sender<Rc<::capnp::message::Builder<::capnp::message::HeapAllocator>>>,
+    #[cfg(unix)]
+    fds: Vec<std::os::fd::OwnedFd>,
+    #[cfg(unix)]
+    fd_transport: Option<unix::FdTransport>,
 }
 
 impl crate::OutgoingMessage for OutgoingMessage {
@@ -72,10 +96,23 @@ impl crate::OutgoingMessage for OutgoingMessage {
         let Self {
             message,
             mut sender,
+            #[cfg(unix)]
+            fds,
+            #[cfg(unix)]
+            fd_transport,
         } = tmp;
+        // The descriptors are queued in the same order as the messages, so that the writer can
+        // attach them to the right one.
+        #[cfg(unix)]
+        if let Some(fd_transport) = fd_transport {
+            fd_transport.push_outgoing(fds);
+        }
         let m = Rc::new(message);
         (
-            Promise::from_future(sender.send(m.clone()).map_ok(|_| ())),
+            // The RPC system relies on messages going out in the order they were sent, so they
+            // are never held back here. Backpressure is applied to streaming calls instead;
+            // see `QueueAwareFlowController`.
+            Promise::from_future(sender.send_immediately(m.clone()).map_ok(|_| ())),
             m,
         )
     }
@@ -87,28 +124,39 @@ impl crate::OutgoingMessage for OutgoingMessage {
     fn size_in_words(&self) -> usize {
         self.message.size_in_words()
     }
+
+    #[cfg(unix)]
+    fn set_fds(&mut self, fds: Vec<std::os::fd::OwnedFd>) {
+        self.fds = fds;
+    }
 }
 
-struct ConnectionInner<T>
+struct ConnectionInner<T, F>
 where
     T: AsyncRead + 'static,
 {
     input_stream: Rc<RefCell<Option<T>>>,
+    framing: F,
     sender: ::capnp_futures::Sender<Rc<::capnp::message::Builder<::capnp::message::HeapAllocator>>>,
     side: crate::rpc_twoparty_capnp::Side,
     receive_options: ReaderOptions,
+    receive_quota: Option<capnp::message::MemoryQuota>,
     on_disconnect_fulfiller: Option<oneshot::Sender<()>>,
     window_size_in_bytes: usize,
+
+    // Set if the connection can carry file descriptors.
+    #[cfg(unix)]
+    fd_transport: Option<unix::FdTransport>,
 }
 
-struct Connection<T>
+struct Connection<T, F>
 where
     T: AsyncRead + 'static,
 {
-    inner: Rc<RefCell<ConnectionInner<T>>>,
+    inner: Rc<RefCell<ConnectionInner<T, F>>>,
 }
 
-impl<T> Drop for ConnectionInner<T>
+impl<T, F> Drop for ConnectionInner<T, F>
 where
     T: AsyncRead,
 {
@@ -122,12 +170,13 @@ where
     }
 }
 
-impl<T> Connection<T>
+impl<T, F> Connection<T, F>
 where
     T: AsyncRead,
 {
     fn new(
         input_stream: T,
+        framing: F,
         sender: ::capnp_futures::Sender<
             Rc<::capnp::message::Builder<::capnp::message::HeapAllocator>>,
         >,
@@ -138,19 +187,24 @@ where
         Self {
             inner: Rc::new(RefCell::new(ConnectionInner {
                 input_stream: Rc::new(RefCell::new(Some(input_stream))),
+                framing,
                 sender,
                 side,
                 receive_options,
+                receive_quota: None,
                 on_disconnect_fulfiller: Some(on_disconnect_fulfiller),
                 window_size_in_bytes: crate::flow_control::DEFAULT_WINDOW_SIZE,
+                #[cfg(unix)]
+                fd_transport: None,
             })),
         }
     }
 }
 
-impl<T> crate::Connection<crate::rpc_twoparty_capnp::Side> for Connection<T>
+impl<T, F> crate::Connection<crate::rpc_twoparty_capnp::Side> for Connection<T, F>
 where
     T: AsyncRead + Unpin,
+    F: Framing + Clone + 'static,
 {
     fn get_peer_vat_id(&self) -> crate::rpc_twoparty_capnp::Side {
         self.inner.borrow().side
@@ -163,9 +217,14 @@ where
         let message = ::capnp::message::Builder::new(
             ::capnp::message::HeapAllocator::new().first_segment_words(first_segment_word_size),
         );
+        let inner = self.inner.borrow();
         Box::new(OutgoingMessage {
             message,
-            sender: self.inner.borrow().sender.clone(),
+            sender: inner.sender.clone(),
+            #[cfg(unix)]
+            fds: Vec::new(),
+            #[cfg(unix)]
+            fd_transport: inner.fd_transport.clone(),
         })
     }
 
@@ -179,13 +238,28 @@ where
         match maybe_input_stream {
             Some(mut s) => {
                 let receive_options = inner.receive_options;
+                let framing = inner.framing.clone();
+                let receive_quota = inner.receive_quota.clone();
+                #[cfg(unix)]
+                let fd_transport = inner.fd_transport.clone();
                 Promise::from_future(async move {
-                    let maybe_message =
-                        ::capnp_futures::serialize::try_read_message(&mut s, receive_options)
-                            .await?;
+                    let maybe_message = match receive_quota {
+                        Some(quota) => {
+                            framing
+                                .try_read_message_with_quota(&mut s, receive_options, &quota)
+                                .await?
+                        }
+                        None => framing.try_read_message(&mut s, receive_options).await?,
+                    };
                     *return_it_here.borrow_mut() = Some(s);
                     Ok(maybe_message.map(|message| {
-                        Box::new(IncomingMessage::new(message)) as Box<dyn crate::IncomingMessage>
+                        let message = IncomingMessage::new(message);
+                        #[cfg(unix)]
+                        let message = IncomingMessage {
+                            fds: fd_transport.map_or_else(Vec::new, |t| t.take_incoming()),
+                            ..message
+                        };
+                        Box::new(message) as Box<dyn crate::IncomingMessage>
                     }))
                 })
             }
@@ -199,27 +273,71 @@ where
     }
 
     fn new_stream(&mut self) -> (Box<dyn crate::FlowController>, Promise<(), capnp::Error>) {
-        let (fc, f) = crate::flow_control::FixedWindowFlowController::new(
-            self.inner.borrow().window_size_in_bytes,
-        );
+        let inner = self.inner.borrow();
+        let (fc, f) =
+            crate::flow_control::FixedWindowFlowController::new(inner.window_size_in_bytes);
+        let fc = QueueAwareFlowController {
+            inner: fc,
+            sender: inner.sender.clone(),
+        };
         (Box::new(fc), f)
     }
 
     fn shutdown(&mut self, result: ::capnp::Result<()>) -> Promise<(), ::capnp::Error> {
-        Promise::from_future(self.inner.borrow_mut().sender.terminate(result))
+        let mut inner = self.inner.borrow_mut();
+        let terminated = inner.sender.terminate(result);
+        #[cfg(unix)]
+        if let Some(fd_transport) = inner.fd_transport.clone() {
+            return Promise::from_future(async move {
+                terminated.await?;
+                // Let the peer see end-of-file, even though we may still be reading.
+                fd_transport.shutdown_write()?;
+                Ok(())
+            });
+        }
+        Promise::from_future(terminated)
+    }
+}
+
+/// Wraps a stream's flow controller so that streaming calls also wait while the connection's
+/// outgoing queue is over its limits.
+struct QueueAwareFlowController {
+    inner: crate::flow_control::FixedWindowFlowController,
+    sender: ::capnp_futures::Sender<Rc<::capnp::message::Builder<::capnp::message::HeapAllocator>>>,
+}
+
+impl crate::FlowController for QueueAwareFlowController {
+    fn send(
+        &mut self,
+        message: Box<dyn crate::OutgoingMessage>,
+        ack: Promise<(), ::capnp::Error>,
+    ) -> Promise<(), ::capnp::Error> {
+        let sent = self.inner.send(message, ack);
+        let ready = self.sender.ready();
+        Promise::from_future(async move {
+            sent.await?;
+            ready.await;
+            Ok(())
+        })
+    }
+
+    fn wait_all_acked(&mut self) -> Promise<(), ::capnp::Error> {
+        self.inner.wait_all_acked()
     }
 }
 
 /// A vat network with two parties, the client and the server.
-pub struct VatNetwork<T>
+///
+/// `F` is the [`Framing`] used to read and write messages on the connection.
+pub struct VatNetwork<T, F = framing::Standard>
 where
     T: AsyncRead + 'static + Unpin,
 {
     // connection handle that we will return on accept()
-    connection: Option<Connection<T>>,
+    connection: Option<Connection<T, F>>,
 
     // connection handle that we will return on connect()
-    weak_connection_inner: Weak<RefCell<ConnectionInner<T>>>,
+    weak_connection_inner: Weak<RefCell<ConnectionInner<T, F>>>,
 
     execution_driver: futures::future::Shared<Promise<(), ::capnp::Error>>,
     side: crate::rpc_twoparty_capnp::Side,
@@ -246,6 +364,35 @@ where
         side: crate::rpc_twoparty_capnp::Side,
         receive_options: ReaderOptions,
     ) -> Self
+    where
+        U: AsyncWrite + 'static + Unpin,
+    {
+        Self::new_with_framing(
+            input_stream,
+            output_stream,
+            side,
+            receive_options,
+            framing::Standard,
+        )
+    }
+}
+
+impl<T, F> VatNetwork<T, F>
+where
+    T: AsyncRead + Unpin,
+    F: Framing + Clone + 'static,
+{
+    /// Like `new()`, but reads and writes messages using `framing` instead of the standard
+    /// stream framing. This makes it possible to, for example, compress all traffic with
+    /// `capnp_futures::serialize_compressed::Compressed`. Both sides of the connection must
+    /// use the same framing.
+    pub fn new_with_framing<U>(
+        input_stream: T,
+        output_stream: U,
+        side: crate::rpc_twoparty_capnp::Side,
+        receive_options: ReaderOptions,
+        framing: F,
+    ) -> Self
     where
         U: AsyncWrite + 'static + Unpin,
     {
@@ -254,7 +401,8 @@ where
             disconnect_promise.map_err(|_| ::capnp::Error::disconnected("disconnected".into()));
 
         let (execution_driver, sender) = {
-            let (tx, write_queue) = ::capnp_futures::write_queue(output_stream);
+            let (tx, write_queue) =
+                ::capnp_futures::write_queue_with_framing(output_stream, framing.clone());
 
             // Don't use `.join()` here because we need to make sure to wait for `disconnect_promise` to
             // resolve even if `write_queue` resolves to an error.
@@ -269,7 +417,14 @@ where
             )
         };
 
-        let connection = Connection::new(input_stream, sender, side, receive_options, fulfiller);
+        let connection = Connection::new(
+            input_stream,
+            framing,
+            sender,
+            side,
+            receive_options,
+            fulfiller,
+        );
         let weak_inner = Rc::downgrade(&connection.inner);
         Self {
             connection: Some(connection),
@@ -286,11 +441,44 @@ where
             conn.inner.borrow_mut().window_size_in_bytes = window_size;
         }
     }
+
+    /// Applies backpressure to streaming calls: while more than `limits` of outgoing data is
+    /// buffered for the peer, the promises returned to callers of streaming methods do not
+    /// resolve, so a slow peer slows down the callers instead of making this process buffer
+    /// without bound.
+    ///
+    /// Only streaming calls wait. All other messages still go out without waiting, since
+    /// holding them back could reorder them, but they do count towards the limits. By default
+    /// there are no limits.
+    pub fn set_streaming_queue_limits(&mut self, limits: ::capnp_futures::QueueLimits) {
+        if let Some(inner) = self.weak_connection_inner.upgrade() {
+            inner.borrow().sender.set_limits(limits);
+        }
+    }
+
+    /// Charges the messages received on this connection against `quota` for as long as they
+    /// are held, e.g. while the calls they carry are in progress. If the peer sends a message
+    /// that does not fit in the quota, the connection fails with an `Overloaded` error, so that
+    /// one peer cannot exhaust this process's memory. A quota may be shared by several networks
+    /// to bound their total usage.
+    pub fn set_receive_quota(&mut self, quota: capnp::message::MemoryQuota) {
+        if let Some(inner) = self.weak_connection_inner.upgrade() {
+            inner.borrow_mut().receive_quota = Some(quota);
+        }
+    }
+
+    #[cfg(unix)]
+    pub(crate) fn set_fd_transport(&mut self, fd_transport: unix::FdTransport) {
+        if let Some(inner) = self.weak_connection_inner.upgrade() {
+            inner.borrow_mut().fd_transport = Some(fd_transport);
+        }
+    }
 }
 
-impl<T> crate::VatNetwork<VatId> for VatNetwork<T>
+impl<T, F> crate::VatNetwork<VatId> for VatNetwork<T, F>
 where
     T: AsyncRead + Unpin,
+    F: Framing + Clone + 'static,
 {
     fn connect(&mut self, host_id: VatId) -> Option<Box<dyn crate::Connection<VatId>>> {
         if host_id == self.side {
@@ -0,0 +1,491 @@
+//! A two-party [`VatNetwork`](crate::VatNetwork) over a Unix domain socket that can pass file
+//! descriptors between the parties.
+//!
+//! A capability created with [`new_client_with_fd()`](crate::new_client_with_fd) carries a file
+//! descriptor. When such a capability is sent over this network, the descriptor is sent along
+//! with the message as `SCM_RIGHTS` ancillary data, and the receiver can get it back with
+//! [`get_fd()`](crate::get_fd).
+//!
+//! This module does not depend on any particular async runtime. The socket is accessed through
+//! the [`FdStream`] trait, which can be implemented for a runtime's Unix stream type with the
+//! help of [`send_with_fds()`] and [`recv_with_fds()`].
+//!
+//! Apart from the file descriptors, the network behaves like
+//! [`twoparty::VatNetwork`](super::VatNetwork), and supports the same framings and limits.
+
+use capnp::capability::Promise;
+use capnp::message::ReaderOptions;
+use capnp_futures::framing::{self, Framing};
+use futures::{ready, AsyncRead, AsyncWrite};
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::pin::Pin;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+
+pub use super::VatId;
+
+/// A connected Unix domain stream socket that can send and receive file descriptors.
+pub trait FdStream: AsFd {
+    /// Attempts to receive bytes into `buf`. Any file descriptors that arrive with them are
+    /// appended to `fds`; descriptors beyond the first `max_fds` are discarded. Returns the
+    /// number of bytes received, where zero means end-of-file.
+    ///
+    /// Implementations typically wait for the socket to become readable and then call
+    /// [`recv_with_fds()`].
+    fn poll_recv_with_fds(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        fds: &mut Vec<OwnedFd>,
+        max_fds: usize,
+    ) -> Poll<io::Result<usize>>;
+
+    /// Attempts to send bytes from `buf`, along with `fds` if at least one byte is sent.
+    /// Returns the number of bytes sent.
+    ///
+    /// Implementations typically wait for the socket to become writable and then call
+    /// [`send_with_fds()`].
+    fn poll_send_with_fds(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Poll<io::Result<usize>>;
+}
+
+/// Sends bytes from `buf` on `socket`, along with `fds`, using a single `sendmsg()` call.
+/// Returns the number of bytes sent. The call does not block if `socket` is non-blocking.
+pub fn send_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &[u8],
+    fds: &[BorrowedFd<'_>],
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_ptr() as *mut libc::c_void,
+        iov_len: buf.len(),
+    };
+    // Safety: all-zero is a valid `msghdr`.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let payload_len = control_payload_len(fds.len())?;
+    let mut control = if fds.is_empty() {
+        Vec::new()
+    } else {
+        control_buffer(payload_len)
+    };
+    if !control.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        // Safety: `CMSG_SPACE` only does arithmetic.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(payload_len) } as _;
+        // Safety: `control` is large enough for one control message carrying `fds`.
+        unsafe {
+            let cmsg = libc::CMSG_FIRSTHDR(&msg);
+            (*cmsg).cmsg_level = libc::SOL_SOCKET;
+            (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+            (*cmsg).cmsg_len = libc::CMSG_LEN(payload_len) as _;
+            let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+            for (idx, fd) in fds.iter().enumerate() {
+                data.add(idx).write_unaligned(fd.as_raw_fd());
+            }
+        }
+    }
+
+    // Safety: `msg` points to buffers that outlive the call.
+    let n = unsafe { libc::sendmsg(socket.as_raw_fd(), &msg, SEND_FLAGS) };
+    if n < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(n as usize)
+    }
+}
+
+/// Receives bytes into `buf` from `socket` using a single `recvmsg()` call. File descriptors
+/// that arrive with them are appended to `fds`, with close-on-exec set. The kernel discards any
+/// descriptors beyond the first `max_fds`. Returns the number of bytes received. The call does
+/// not block if `socket` is non-blocking.
+pub fn recv_with_fds(
+    socket: BorrowedFd<'_>,
+    buf: &mut [u8],
+    fds: &mut Vec<OwnedFd>,
+    max_fds: usize,
+) -> io::Result<usize> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr().cast(),
+        iov_len: buf.len(),
+    };
+    // Safety: all-zero is a valid `msghdr`.
+    let mut msg: libc::msghdr = unsafe { std::mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+
+    let payload_len = control_payload_len(max_fds)?;
+    let mut control = if max_fds == 0 {
+        Vec::new()
+    } else {
+        control_buffer(payload_len)
+    };
+    if !control.is_empty() {
+        msg.msg_control = control.as_mut_ptr().cast();
+        // Safety: `CMSG_SPACE` only does arithmetic.
+        msg.msg_controllen = unsafe { libc::CMSG_SPACE(payload_len) } as _;
+    }
+
+    // Safety: `msg` points to buffers that outlive the call.
+    let n = unsafe { libc::recvmsg(socket.as_raw_fd(), &mut msg, RECV_FLAGS) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let first_new_fd = fds.len();
+    if !control.is_empty() {
+        // Safety: the kernel has filled in `msg.msg_control` with well-formed control messages.
+        unsafe {
+            let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+            while !cmsg.is_null() {
+                if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                    let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                    let payload_len = (*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize;
+                    for idx in 0..payload_len / std::mem::size_of::<RawFd>() {
+                        fds.push(OwnedFd::from_raw_fd(data.add(idx).read_unaligned()));
+                    }
+                }
+                cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+            }
+        }
+    }
+    for fd in &fds[first_new_fd..] {
+        set_cloexec(fd)?;
+    }
+    Ok(n as usize)
+}
+
+// Writing to a socket whose peer has gone away must fail with `EPIPE` rather than raise
+// `SIGPIPE`, which would kill the process. Apple's platforms lack `MSG_NOSIGNAL`; there, the
+// network sets `SO_NOSIGPIPE` on the socket instead.
+#[cfg(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "solaris",
+    target_os = "illumos",
+))]
+const SEND_FLAGS: libc::c_int = libc::MSG_NOSIGNAL;
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "freebsd",
+    target_os = "dragonfly",
+    target_os = "openbsd",
+    target_os = "netbsd",
+    target_os = "solaris",
+    target_os = "illumos",
+)))]
+const SEND_FLAGS: libc::c_int = 0;
+
+#[cfg(target_vendor = "apple")]
+fn disable_sigpipe(socket: BorrowedFd<'_>) -> io::Result<()> {
+    let one: libc::c_int = 1;
+    // Safety: `one` outlives the call.
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_NOSIGPIPE,
+            (&one as *const libc::c_int).cast(),
+            std::mem::size_of::<libc::c_int>() as libc::socklen_t,
+        )
+    };
+    if result < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(not(target_vendor = "apple"))]
+fn disable_sigpipe(_socket: BorrowedFd<'_>) -> io::Result<()> {
+    // Done by `SEND_FLAGS`, where possible.
+    Ok(())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+const RECV_FLAGS: libc::c_int = libc::MSG_CMSG_CLOEXEC;
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+const RECV_FLAGS: libc::c_int = 0;
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_cloexec(_fd: &OwnedFd) -> io::Result<()> {
+    // Already done by `MSG_CMSG_CLOEXEC`.
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_cloexec(fd: &OwnedFd) -> io::Result<()> {
+    // Safety: `fd` is a valid descriptor.
+    if unsafe { libc::fcntl(fd.as_raw_fd(), libc::F_SETFD, libc::FD_CLOEXEC) } < 0 {
+        Err(io::Error::last_os_error())
+    } else {
+        Ok(())
+    }
+}
+
+fn control_payload_len(fd_count: usize) -> io::Result<u32> {
+    fd_count
+        .checked_mul(std::mem::size_of::<RawFd>())
+        .and_then(|len| u32::try_from(len).ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "too many file descriptors"))
+}
+
+/// Allocates a zeroed buffer that is large enough, and suitably aligned, for one control message
+/// with `payload_len` bytes of data.
+fn control_buffer(payload_len: u32) -> Vec<u64> {
+    // Safety: `CMSG_SPACE` only does arithmetic.
+    let space = unsafe { libc::CMSG_SPACE(payload_len) } as usize;
+    vec![0; space.div_ceil(std::mem::size_of::<u64>())]
+}
+
+/// The file descriptors passing through a connection, shared between its reader, its writer
+/// and [`twoparty::VatNetwork`](super::VatNetwork).
+#[derive(Clone)]
+pub(crate) struct FdTransport {
+    // Received since the last message was read.
+    incoming: Rc<RefCell<Vec<OwnedFd>>>,
+
+    // To be sent, one entry per message, in the order in which the messages were queued.
+    outgoing: Rc<RefCell<VecDeque<Vec<OwnedFd>>>>,
+
+    socket: Rc<dyn AsFd>,
+}
+
+impl FdTransport {
+    /// Takes the descriptors that arrived while the last message was being read.
+    pub(crate) fn take_incoming(&self) -> Vec<OwnedFd> {
+        std::mem::take(&mut *self.incoming.borrow_mut())
+    }
+
+    /// Queues the descriptors to send with the message that has just been queued.
+    pub(crate) fn push_outgoing(&self, fds: Vec<OwnedFd>) {
+        self.outgoing.borrow_mut().push_back(fds);
+    }
+
+    /// Shuts down the sending side of the socket.
+    pub(crate) fn shutdown_write(&self) -> io::Result<()> {
+        // Safety: `socket` is a valid descriptor.
+        if unsafe { libc::shutdown(self.socket.as_fd().as_raw_fd(), libc::SHUT_WR) } < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Reads from an `FdStream`, collecting the file descriptors that arrive along the way.
+struct FdReader<S> {
+    stream: Rc<S>,
+    fds: Rc<RefCell<Vec<OwnedFd>>>,
+    max_fds: usize,
+}
+
+impl<S> AsyncRead for FdReader<S>
+where
+    S: FdStream,
+{
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let mut fds = this.fds.borrow_mut();
+        let max_fds = this.max_fds.saturating_sub(fds.len());
+        this.stream.poll_recv_with_fds(cx, buf, &mut fds, max_fds)
+    }
+}
+
+/// Writes to an `FdStream`. The write queue flushes after every message, so each flush sends one
+/// message, with the next entry of `fds` attached to its first byte.
+struct FdWriter<S> {
+    stream: Rc<S>,
+    fds: Rc<RefCell<VecDeque<Vec<OwnedFd>>>>,
+    buffer: Vec<u8>,
+    written: usize,
+
+    // The descriptors for the message in `buffer`, once it has started to be sent.
+    attached: Option<Vec<OwnedFd>>,
+    sigpipe_disabled: bool,
+}
+
+impl<S> AsyncWrite for FdWriter<S>
+where
+    S: FdStream,
+{
+    fn poll_write(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.get_mut().buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let this = self.get_mut();
+        if this.buffer.is_empty() {
+            return Poll::Ready(Ok(()));
+        }
+        if !this.sigpipe_disabled {
+            disable_sigpipe(this.stream.as_fd())?;
+            this.sigpipe_disabled = true;
+        }
+        let attached = this
+            .attached
+            .get_or_insert_with(|| this.fds.borrow_mut().pop_front().unwrap_or_default());
+        while this.written < this.buffer.len() {
+            let fds: Vec<BorrowedFd<'_>> = if this.written == 0 {
+                attached.iter().map(AsFd::as_fd).collect()
+            } else {
+                Vec::new()
+            };
+            let n = ready!(this
+                .stream
+                .poll_send_with_fds(cx, &this.buffer[this.written..], &fds))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.written += n;
+        }
+        this.buffer.clear();
+        this.written = 0;
+        this.attached = None;
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+/// A vat network with two parties, the client and the server, connected by a Unix domain
+/// socket over which capabilities may carry file descriptors.
+pub struct VatNetwork<S, F = framing::Standard>
+where
+    S: FdStream + 'static,
+{
+    network: super::VatNetwork<FdReader<S>, F>,
+}
+
+impl<S> VatNetwork<S>
+where
+    S: FdStream + 'static,
+{
+    /// Creates a new two-party vat network that sends and receives messages on `stream`.
+    ///
+    /// At most `max_fds_per_message` file descriptors are accepted with each incoming message;
+    /// any others the peer sends are closed. Since each received descriptor takes up a slot in
+    /// this process's descriptor table, this should be kept small. If it is zero, capabilities
+    /// received from the peer never carry file descriptors.
+    ///
+    /// `side` and `receive_options` have the same meaning as for
+    /// [`twoparty::VatNetwork::new()`](super::VatNetwork::new).
+    pub fn new(
+        stream: S,
+        side: VatId,
+        receive_options: ReaderOptions,
+        max_fds_per_message: usize,
+    ) -> Self {
+        Self::new_with_framing(
+            stream,
+            side,
+            receive_options,
+            max_fds_per_message,
+            framing::Standard,
+        )
+    }
+}
+
+impl<S, F> VatNetwork<S, F>
+where
+    S: FdStream + 'static,
+    F: Framing + Clone + 'static,
+{
+    /// Like `new()`, but reads and writes messages using `framing`, as with
+    /// [`twoparty::VatNetwork::new_with_framing()`](super::VatNetwork::new_with_framing).
+    ///
+    /// The descriptors that arrive while a message is being read are taken to belong to that
+    /// message, so `framing` must not read past the end of a message. None of the framings in
+    /// `capnp_futures` do.
+    pub fn new_with_framing(
+        stream: S,
+        side: VatId,
+        receive_options: ReaderOptions,
+        max_fds_per_message: usize,
+        framing: F,
+    ) -> Self {
+        let stream = Rc::new(stream);
+        let fd_transport = FdTransport {
+            incoming: Default::default(),
+            outgoing: Default::default(),
+            socket: stream.clone(),
+        };
+        let reader = FdReader {
+            stream: stream.clone(),
+            fds: fd_transport.incoming.clone(),
+            max_fds: max_fds_per_message,
+        };
+        let writer = FdWriter {
+            stream,
+            fds: fd_transport.outgoing.clone(),
+            buffer: Vec::new(),
+            written: 0,
+            attached: None,
+            sigpipe_disabled: false,
+        };
+        let mut network =
+            super::VatNetwork::new_with_framing(reader, writer, side, receive_options, framing);
+        network.set_fd_transport(fd_transport);
+        Self { network }
+    }
+
+    /// See [`twoparty::VatNetwork::set_window_size()`](super::VatNetwork::set_window_size).
+    pub fn set_window_size(&mut self, window_size: usize) {
+        self.network.set_window_size(window_size);
+    }
+
+    /// See
+    /// [`twoparty::VatNetwork::set_streaming_queue_limits()`](super::VatNetwork::set_streaming_queue_limits).
+    pub fn set_streaming_queue_limits(&mut self, limits: capnp_futures::QueueLimits) {
+        self.network.set_streaming_queue_limits(limits);
+    }
+
+    /// See [`twoparty::VatNetwork::set_receive_quota()`](super::VatNetwork::set_receive_quota).
+    pub fn set_receive_quota(&mut self, quota: capnp::message::MemoryQuota) {
+        self.network.set_receive_quota(quota);
+    }
+}
+
+impl<S, F> crate::VatNetwork<VatId> for VatNetwork<S, F>
+where
+    S: FdStream + 'static,
+    F: Framing + Clone + 'static,
+{
+    fn connect(&mut self, host_id: VatId) -> Option<Box<dyn crate::Connection<VatId>>> {
+        self.network.connect(host_id)
+    }
+
+    fn accept(&mut self) -> Promise<Box<dyn crate::Connection<VatId>>, ::capnp::Error> {
+        self.network.accept()
+    }
+
+    fn drive_until_shutdown(&mut self) -> Promise<(), ::capnp::Error> {
+        self.network.drive_until_shutdown()
+    }
+}
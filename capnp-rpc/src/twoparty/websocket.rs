@@ -0,0 +1,139 @@
+//! Adapters for running a two-party connection over a WebSocket, with each Cap'n Proto message
+//! sent as one binary frame, as expected by the C++ implementation's `kj-http`.
+//!
+//! This module does not depend on any particular WebSocket library. [`FrameReader`] and
+//! [`FrameWriter`] turn a stream of incoming binary frame payloads and a sink of outgoing ones
+//! into an `AsyncRead` and an `AsyncWrite`, which can be passed to
+//! [`VatNetwork::new()`](super::VatNetwork::new) like any other byte stream. The RPC system
+//! flushes its output after every message, and `FrameWriter` sends everything written since the
+//! last flush as a single frame. For example, with `tokio-tungstenite`:
+//!
+//! ```ignore
+//! let (sink, stream) = websocket.split();
+//! let stream = stream
+//!     .try_filter_map(|message| async move {
+//!         match message {
+//!             Message::Binary(data) => Ok(Some(data.to_vec())),
+//!             Message::Text(_) => Err(tungstenite::Error::Io(io::Error::other("text frame"))),
+//!             _ => Ok(None),
+//!         }
+//!     })
+//!     .map_err(io::Error::other);
+//! let sink = sink
+//!     .with(|data: Vec<u8>| async move { Ok::<_, tungstenite::Error>(Message::binary(data)) })
+//!     .sink_map_err(io::Error::other);
+//! let network = twoparty::VatNetwork::new(
+//!     FrameReader::new(stream),
+//!     FrameWriter::new(sink),
+//!     rpc_twoparty_capnp::Side::Client,
+//!     Default::default(),
+//! );
+//! ```
+
+use futures::{ready, AsyncRead, AsyncWrite, Sink, Stream};
+
+use std::io;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+/// Reads the payloads of incoming binary frames as one continuous byte stream.
+///
+/// `S` yields the payload of each binary frame. Control frames should be filtered out before
+/// they get here, and text frames should be reported as errors.
+pub struct FrameReader<S> {
+    frames: S,
+    frame: Vec<u8>,
+    pos: usize,
+}
+
+impl<S> FrameReader<S> {
+    pub fn new(frames: S) -> Self {
+        Self {
+            frames,
+            frame: Vec::new(),
+            pos: 0,
+        }
+    }
+}
+
+impl<S> AsyncRead for FrameReader<S>
+where
+    S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = &mut *self;
+        while this.pos == this.frame.len() {
+            match ready!(Pin::new(&mut this.frames).poll_next(cx)) {
+                Some(frame) => {
+                    this.frame = frame?;
+                    this.pos = 0;
+                }
+                None => return Poll::Ready(Ok(0)),
+            }
+        }
+        let n = buf.len().min(this.frame.len() - this.pos);
+        buf[..n].copy_from_slice(&this.frame[this.pos..this.pos + n]);
+        this.pos += n;
+        Poll::Ready(Ok(n))
+    }
+}
+
+/// Collects the bytes written to it and sends them as a single binary frame on each flush.
+///
+/// `S` accepts the payload of each binary frame.
+pub struct FrameWriter<S> {
+    frames: S,
+    buffer: Vec<u8>,
+}
+
+impl<S> FrameWriter<S> {
+    pub fn new(frames: S) -> Self {
+        Self {
+            frames,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+impl<S> FrameWriter<S>
+where
+    S: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    // Hands the buffered bytes, if any, to the sink as a frame.
+    fn poll_send_frame(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        if !self.buffer.is_empty() {
+            ready!(Pin::new(&mut self.frames).poll_ready(cx))?;
+            let frame = std::mem::take(&mut self.buffer);
+            Pin::new(&mut self.frames).start_send(frame)?;
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S> AsyncWrite for FrameWriter<S>
+where
+    S: Sink<Vec<u8>, Error = io::Error> + Unpin,
+{
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        self.buffer.extend_from_slice(buf);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_frame(cx))?;
+        Pin::new(&mut self.frames).poll_flush(cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(self.poll_send_frame(cx))?;
+        Pin::new(&mut self.frames).poll_close(cx)
+    }
+}
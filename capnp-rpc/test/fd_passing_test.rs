@@ -0,0 +1,205 @@
+use std::cell::RefCell;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsFd, BorrowedFd, OwnedFd};
+use std::os::unix::net::UnixStream;
+use std::rc::Rc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use capnp::Error;
+use capnp_rpc::twoparty::unix::{self, FdStream};
+use capnp_rpc::{rpc_twoparty_capnp, RpcSystem};
+use futures::executor::LocalPool;
+use futures::task::LocalSpawnExt;
+
+use crate::impls;
+use crate::test_capnp::bootstrap;
+
+/// A non-blocking socket that, lacking a reactor, retries after a short sleep when it
+/// would block.
+struct Socket(UnixStream);
+
+impl Socket {
+    fn new(stream: UnixStream) -> Self {
+        stream.set_nonblocking(true).unwrap();
+        Self(stream)
+    }
+
+    fn retry_later<T>(cx: &mut Context<'_>, result: io::Result<T>) -> Poll<io::Result<T>> {
+        match result {
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let waker = cx.waker().clone();
+                std::thread::spawn(move || {
+                    std::thread::sleep(Duration::from_millis(1));
+                    waker.wake();
+                });
+                Poll::Pending
+            }
+            r => Poll::Ready(r),
+        }
+    }
+}
+
+impl AsFd for Socket {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.0.as_fd()
+    }
+}
+
+impl FdStream for Socket {
+    fn poll_recv_with_fds(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+        fds: &mut Vec<OwnedFd>,
+        max_fds: usize,
+    ) -> Poll<io::Result<usize>> {
+        Self::retry_later(cx, unix::recv_with_fds(self.as_fd(), buf, fds, max_fds))
+    }
+
+    fn poll_send_with_fds(
+        &self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        fds: &[BorrowedFd<'_>],
+    ) -> Poll<io::Result<usize>> {
+        Self::retry_later(cx, unix::send_with_fds(self.as_fd(), buf, fds))
+    }
+}
+
+/// Hands out a `TestInterface` that wraps `fd`.
+struct FdBootstrap {
+    fd: RefCell<Option<OwnedFd>>,
+}
+
+impl bootstrap::Server for FdBootstrap {
+    async fn test_interface(
+        self: Rc<Self>,
+        _params: bootstrap::TestInterfaceParams,
+        mut results: bootstrap::TestInterfaceResults,
+    ) -> Result<(), Error> {
+        let Some(fd) = self.fd.borrow_mut().take() else {
+            return Err(Error::failed("fd already taken".into()));
+        };
+        results.get().set_cap(capnp_rpc::new_client_with_fd(
+            impls::TestInterface::new(),
+            fd,
+        ));
+        Ok(())
+    }
+}
+
+/// Serves a capability that wraps `fd` and returns the descriptor that the client receives.
+fn pass_fd_over_socketpair(fd: OwnedFd, client_max_fds: usize) -> Option<OwnedFd> {
+    pass_fd_over_socketpair_with_framing(fd, client_max_fds, capnp_futures::framing::Standard)
+}
+
+fn pass_fd_over_socketpair_with_framing<F>(
+    fd: OwnedFd,
+    client_max_fds: usize,
+    framing: F,
+) -> Option<OwnedFd>
+where
+    F: capnp_futures::framing::Framing + Clone + 'static,
+{
+    let (client_socket, server_socket) = UnixStream::pair().unwrap();
+
+    let mut pool = LocalPool::new();
+    let spawner = pool.spawner();
+
+    let server_network = unix::VatNetwork::new_with_framing(
+        Socket::new(server_socket),
+        rpc_twoparty_capnp::Side::Server,
+        Default::default(),
+        4,
+        framing.clone(),
+    );
+    let bootstrap: bootstrap::Client = capnp_rpc::new_client(FdBootstrap {
+        fd: RefCell::new(Some(fd)),
+    });
+    let server_rpc_system = RpcSystem::new(Box::new(server_network), Some(bootstrap.client));
+
+    let client_network = unix::VatNetwork::new_with_framing(
+        Socket::new(client_socket),
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+        client_max_fds,
+        framing,
+    );
+    let mut client_rpc_system = RpcSystem::new(Box::new(client_network), None);
+    let client: bootstrap::Client = client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+
+    spawner
+        .spawn_local(async {
+            let _ = server_rpc_system.await;
+        })
+        .unwrap();
+    spawner
+        .spawn_local(async {
+            let _ = client_rpc_system.await;
+        })
+        .unwrap();
+
+    pool.run_until(async move {
+        let response = client.test_interface_request().send().promise.await?;
+        let cap = response.get()?.get_cap()?;
+
+        // The capability still works as usual.
+        let mut request = cap.foo_request();
+        request.get().set_i(123);
+        request.get().set_j(true);
+        let response = request.send().promise.await?;
+        assert_eq!(response.get()?.get_x()?, "foo");
+
+        capnp_rpc::get_fd(&cap).await
+    })
+    .unwrap()
+}
+
+#[test]
+fn receive_fd() {
+    let (mut reader, writer) = UnixStream::pair().unwrap();
+    let fd = pass_fd_over_socketpair(writer.into(), 4).expect("fd was not passed");
+    UnixStream::from(fd).write_all(b"hello").unwrap();
+
+    let mut buf = [0; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn receive_fd_with_compressed_framing() {
+    let (mut reader, writer) = UnixStream::pair().unwrap();
+    let framing = capnp_futures::serialize_compressed::Compressed(
+        capnp::serialize_compressed::Compression::Lz4,
+    );
+    let fd =
+        pass_fd_over_socketpair_with_framing(writer.into(), 4, framing).expect("fd was not passed");
+    UnixStream::from(fd).write_all(b"hello").unwrap();
+
+    let mut buf = [0; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
+
+#[test]
+fn fds_are_dropped_when_not_accepted() {
+    let (_reader, writer) = UnixStream::pair().unwrap();
+    assert!(pass_fd_over_socketpair(writer.into(), 0).is_none());
+}
+
+#[test]
+fn local_get_fd() {
+    let (mut reader, writer) = UnixStream::pair().unwrap();
+    let client: crate::test_capnp::test_interface::Client =
+        capnp_rpc::new_client_with_fd(impls::TestInterface::new(), writer.into());
+
+    let fd = futures::executor::block_on(capnp_rpc::get_fd(&client))
+        .unwrap()
+        .unwrap();
+    UnixStream::from(fd).write_all(b"hello").unwrap();
+
+    let mut buf = [0; 5];
+    reader.read_exact(&mut buf).unwrap();
+    assert_eq!(&buf, b"hello");
+}
@@ -0,0 +1,90 @@
+use capnp::Error;
+use capnp_rpc::recording::{Direction, RecordingVatNetwork, ReplayVatNetwork, Trace};
+use capnp_rpc::{loopback, RpcSystem};
+use futures::executor::LocalPool;
+use futures::Future;
+
+use crate::test_capnp::bootstrap;
+use crate::{impls, spawn};
+
+/// Runs `main` against a client `RpcSystem` that is connected to a server over `network`,
+/// then disconnects.
+fn run_client<F, G>(
+    pool: &mut LocalPool,
+    network: Box<dyn capnp_rpc::VatNetwork<&'static str>>,
+    main: F,
+) where
+    F: FnOnce(bootstrap::Client) -> G,
+    G: Future<Output = Result<(), Error>>,
+{
+    let mut spawner = pool.spawner();
+    let mut rpc_system = RpcSystem::new(network, None);
+    let client: bootstrap::Client = rpc_system.bootstrap("server");
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+    let result = pool.run_until(main(client));
+    pool.run_until(disconnector).unwrap();
+    result.unwrap();
+}
+
+fn record<F, G>(main: F) -> Trace
+where
+    F: FnOnce(bootstrap::Client) -> G,
+    G: Future<Output = Result<(), Error>>,
+{
+    let mut pool = LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let bootstrap: bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server = RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    spawn(&mut spawner, server);
+
+    let trace = Trace::new();
+    let client_network =
+        RecordingVatNetwork::new(Box::new(network.add_vat("client")), trace.clone());
+    run_client(&mut pool, Box::new(client_network), main);
+    trace
+}
+
+async fn call_foo(client: bootstrap::Client, i: u32) -> Result<(), Error> {
+    let response = client.test_interface_request().send().promise.await?;
+    let client = response.get()?.get_cap()?;
+    let mut request = client.foo_request();
+    request.get().set_i(i);
+    request.get().set_j(true);
+    let response = request.send().promise.await?;
+    assert_eq!(response.get()?.get_x()?, "foo");
+    Ok(())
+}
+
+#[test]
+fn record_and_replay() {
+    let trace = record(|client| call_foo(client, 123));
+    assert!(format!("{trace:?}").contains("-> (bootstrap = (questionId = 0))"));
+    let events = trace.events();
+    assert_eq!(events[0].direction, Direction::Outgoing);
+    assert!(events.iter().any(|e| e.direction == Direction::Incoming));
+
+    // No server this time: the incoming messages come from the trace.
+    let mut pool = LocalPool::new();
+    let (network, replay) = ReplayVatNetwork::new(&trace, "client", "server");
+    run_client(&mut pool, Box::new(network), |client| call_foo(client, 123));
+    replay.check().unwrap();
+}
+
+#[test]
+fn replay_detects_divergence() {
+    let trace = record(|client| call_foo(client, 123));
+
+    let mut pool = LocalPool::new();
+    let (network, replay) = ReplayVatNetwork::new(&trace, "client", "server");
+    run_client(&mut pool, Box::new(network), |client| async move {
+        // The call sends different parameters than were recorded, so the replay stops there
+        // and the call never completes successfully.
+        assert!(call_foo(client, 124).await.is_err());
+        Ok(())
+    });
+    let e = replay.check().unwrap_err();
+    assert_eq!(e.kind, capnp::ErrorKind::Failed);
+}
@@ -24,7 +24,7 @@
 
 use capnp::capability::{FromClientHook, Promise};
 use capnp::Error;
-use capnp_rpc::{rpc_twoparty_capnp, twoparty, RpcSystem};
+use capnp_rpc::{keepalive, loopback, rpc_twoparty_capnp, twoparty, RpcSystem};
 
 use futures::channel::oneshot;
 use futures::{Future, FutureExt, TryFutureExt};
@@ -32,9 +32,13 @@ use futures::{Future, FutureExt, TryFutureExt};
 capnp::generated_code!(pub mod test_capnp);
 
 pub mod disconnector_test;
+#[cfg(unix)]
+pub mod fd_passing_test;
 pub mod impls;
 pub mod reconnect_test;
+pub mod recording_test;
 pub mod test_util;
+pub mod websocket_test;
 
 fn canceled_to_error(_e: futures::channel::oneshot::Canceled) -> Error {
     Error::failed("oneshot was canceled".to_string())
@@ -82,6 +86,54 @@ fn disconnector_setup() -> (
     (client_rpc_system, server_rpc_system)
 }
 
+/// Connects a client `RpcSystem` to a server `RpcSystem` whose bootstrap capability is
+/// `bootstrap`, over a two-party connection. Returns `(client, server)`, neither of them spawned.
+fn twoparty_setup(
+    bootstrap: capnp::capability::Client,
+) -> (
+    RpcSystem<rpc_twoparty_capnp::Side>,
+    RpcSystem<rpc_twoparty_capnp::Side>,
+) {
+    twoparty_setup_with(bootstrap, capnp_futures::framing::Standard, |_| {})
+}
+
+/// Like `twoparty_setup()`, but both sides use `framing`, and `configure_client` gets to adjust
+/// the client's network before its `RpcSystem` is created.
+fn twoparty_setup_with<F>(
+    bootstrap: capnp::capability::Client,
+    framing: F,
+    configure_client: impl FnOnce(&mut twoparty::VatNetwork<async_byte_channel::Receiver, F>),
+) -> (
+    RpcSystem<rpc_twoparty_capnp::Side>,
+    RpcSystem<rpc_twoparty_capnp::Side>,
+)
+where
+    F: capnp_futures::framing::Framing + Clone + 'static,
+{
+    let (client_writer, server_reader) = async_byte_channel::channel();
+    let (server_writer, client_reader) = async_byte_channel::channel();
+    let mut client_network = twoparty::VatNetwork::new_with_framing(
+        client_reader,
+        client_writer,
+        rpc_twoparty_capnp::Side::Client,
+        Default::default(),
+        framing.clone(),
+    );
+    configure_client(&mut client_network);
+    let client_rpc_system = RpcSystem::new(Box::new(client_network), None);
+
+    let server_network = Box::new(twoparty::VatNetwork::new_with_framing(
+        server_reader,
+        server_writer,
+        rpc_twoparty_capnp::Side::Server,
+        Default::default(),
+        framing,
+    ));
+    let server_rpc_system = RpcSystem::new(server_network, Some(bootstrap));
+
+    (client_rpc_system, server_rpc_system)
+}
+
 fn spawn<F>(spawner: &mut futures::executor::LocalSpawner, task: F)
 where
     F: Future<Output = Result<(), Error>> + 'static,
@@ -307,6 +359,39 @@ where
     local_top_level(main);
 }
 
+/// Like rpc_top_level(), but connects the two vats with a `loopback::Network`, so that
+/// everything runs on one thread and no messages are serialized.
+fn loopback_top_level<F, G>(main: F)
+where
+    F: FnOnce(futures::executor::LocalSpawner, test_capnp::bootstrap::Client) -> G,
+    G: Future<Output = Result<(), Error>> + 'static,
+{
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server_rpc_system =
+        RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    let (server_done_sender, server_done) = oneshot::channel();
+    spawn(
+        &mut spawner,
+        server_rpc_system.map_ok(|()| {
+            let _ = server_done_sender.send(());
+        }),
+    );
+
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+    pool.run_until(main(spawner, client)).unwrap();
+
+    // Once the client disconnects, the server's `RpcSystem` should finish too.
+    pool.run_until(disconnector).unwrap();
+    pool.run_until(server_done).unwrap();
+}
+
 #[test]
 fn do_nothing() {
     rpc_top_level(|_spawner, _client| async { Ok(()) });
@@ -349,6 +434,223 @@ fn basic_rpc_calls() {
     });
 }
 
+#[test]
+fn compressed_framing() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let framing = capnp_futures::serialize_compressed::Compressed(
+        capnp::serialize_compressed::Compression::Lz4,
+    );
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let (mut client_rpc_system, server_rpc_system) =
+        twoparty_setup_with(bootstrap.client, framing, |_| {});
+
+    let client: test_capnp::bootstrap::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    let disconnector = client_rpc_system.get_disconnector();
+    spawn(&mut spawner, client_rpc_system);
+    {
+        // The server's side of the connection may end with a disconnection error.
+        use futures::task::LocalSpawnExt;
+        spawner.spawn_local(server_rpc_system.map(|_| ())).unwrap();
+    }
+
+    pool.run_until(async move {
+        let response = client.test_interface_request().send().promise.await?;
+        let client = response.get()?.get_cap()?;
+
+        let mut request = client.foo_request();
+        request.get().set_i(123);
+        request.get().set_j(true);
+        let response = request.send().promise.await?;
+        assert_eq!(response.get()?.get_x()?, "foo");
+        Ok::<(), Error>(())
+    })
+    .unwrap();
+    pool.run_until(disconnector).unwrap();
+}
+
+#[test]
+fn loopback_network() {
+    loopback_top_level(|_spawner, client| async move {
+        let response = client.test_pipeline_request().send().promise.await?;
+        let client = response.get()?.get_cap()?;
+
+        let mut request = client.get_cap_request();
+        request.get().set_n(234);
+        request
+            .get()
+            .set_in_cap(capnp_rpc::new_client(impls::TestInterface::new()));
+        let promise = request.send();
+
+        let mut pipeline_request = promise.pipeline.get_out_box().get_cap().foo_request();
+        pipeline_request.get().set_i(321);
+        let pipeline_response = pipeline_request.send().promise.await?;
+        assert_eq!(pipeline_response.get()?.get_x()?, "bar");
+
+        let response = promise.promise.await?;
+        assert_eq!(response.get()?.get_s()?, "bar");
+        Ok(())
+    });
+}
+
+#[test]
+fn loopback_connect_to_missing_vat() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat(1)), None);
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap(2);
+    spawn(&mut spawner, rpc_system);
+
+    let result = pool.run_until(client.test_interface_request().send().promise);
+    match result {
+        Err(e) => assert_eq!(e.kind, capnp::ErrorKind::Disconnected),
+        Ok(_) => panic!("expected the call to fail"),
+    }
+}
+
+/// A `keepalive::Timer` whose delays only elapse when `tick()` is called.
+#[derive(Clone, Default)]
+struct ManualTimer {
+    waiting: std::rc::Rc<std::cell::RefCell<Vec<oneshot::Sender<()>>>>,
+}
+
+impl ManualTimer {
+    fn timer(&self) -> std::rc::Rc<dyn keepalive::Timer> {
+        let waiting = self.waiting.clone();
+        std::rc::Rc::new(move |_delay| {
+            let (sender, receiver) = oneshot::channel();
+            waiting.borrow_mut().push(sender);
+            Promise::from_future(receiver.map_err(canceled_to_error))
+        })
+    }
+
+    fn tick(&self) {
+        for sender in self.waiting.borrow_mut().drain(..) {
+            let _ = sender.send(());
+        }
+    }
+}
+
+#[test]
+fn keepalive_disconnects_unresponsive_peer() {
+    use std::time::Duration;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    // Nothing runs an `RpcSystem` for the server, so it never answers, like a half-open connection.
+    let _server = network.add_vat("server");
+
+    let timer = ManualTimer::default();
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    rpc_system.set_keepalive(keepalive::Keepalive::new(
+        timer.timer(),
+        Duration::from_secs(1),
+        Duration::from_secs(3),
+    ));
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    spawn(&mut spawner, rpc_system);
+
+    let (result_sender, mut result) = oneshot::channel();
+    spawn(
+        &mut spawner,
+        client.test_interface_request().send().promise.map(|r| {
+            let _ = result_sender.send(r.map(drop));
+            Ok(())
+        }),
+    );
+
+    for _ in 0..2 {
+        pool.run_until_stalled();
+        timer.tick();
+    }
+    pool.run_until_stalled();
+    assert!(result.try_recv().unwrap().is_none());
+
+    timer.tick();
+    pool.run_until_stalled();
+    match result.try_recv().unwrap() {
+        Some(Err(e)) => assert_eq!(e.kind, capnp::ErrorKind::Disconnected),
+        _ => panic!("expected the call to fail"),
+    }
+}
+
+#[test]
+fn keepalive_keeps_responsive_peer() {
+    use std::time::Duration;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server = RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    spawn(&mut spawner, server);
+
+    let timer = ManualTimer::default();
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    rpc_system.set_keepalive(keepalive::Keepalive::new(
+        timer.timer(),
+        Duration::from_secs(1),
+        Duration::from_secs(3),
+    ));
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+
+    // The connection is idle the whole time, but the peer answers every ping.
+    for _ in 0..10 {
+        pool.run_until_stalled();
+        timer.tick();
+    }
+
+    let response = pool
+        .run_until(client.test_interface_request().send().promise)
+        .unwrap();
+    let mut request = response.get().unwrap().get_cap().unwrap().foo_request();
+    request.get().set_i(123);
+    request.get().set_j(true);
+    let response = pool.run_until(request.send().promise).unwrap();
+    assert_eq!(response.get().unwrap().get_x().unwrap(), "foo");
+
+    pool.run_until(disconnector).unwrap();
+}
+
+#[test]
+fn keepalive_stops_on_timer_failure() {
+    use std::time::Duration;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server = RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    spawn(&mut spawner, server);
+
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    let timer: std::rc::Rc<dyn keepalive::Timer> =
+        std::rc::Rc::new(|_delay| Promise::err(Error::failed("no timer".to_string())));
+    rpc_system.set_keepalive(keepalive::Keepalive::new(
+        timer,
+        Duration::from_secs(1),
+        Duration::from_secs(3),
+    ));
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+    pool.run_until_stalled();
+
+    // The timer's error does not break the connection.
+    pool.run_until(client.test_interface_request().send().promise)
+        .unwrap();
+    pool.run_until(disconnector).unwrap();
+}
+
 #[test]
 fn basic_pipelining() {
     rpc_and_local_top_level(|_spawner, client| async move {
@@ -427,6 +729,146 @@ fn pipelining_return_null() {
     });
 }
 
+/// Builds a message with `build` and sends it over `connection`.
+fn send_raw(
+    connection: &mut dyn capnp_rpc::Connection<&'static str>,
+    build: impl FnOnce(capnp_rpc::rpc_capnp::message::Builder),
+) {
+    let mut message = connection.new_outgoing_message(100);
+    build(message.get_body().unwrap().init_as());
+    let _ = message.send();
+}
+
+// `capnp-rpc` does not send `onlyPromisePipeline` itself, so the client side of this test
+// speaks the protocol by hand.
+#[test]
+fn only_promise_pipeline_gets_empty_return() {
+    use capnp_rpc::rpc_capnp::{cap_descriptor, message, return_};
+    use capnp_rpc::VatNetwork;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server = RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    spawn(&mut spawner, server);
+
+    let mut client_vat = network.add_vat("client");
+    let mut connection = client_vat.connect("server").unwrap();
+    let mut receive_return = |connection: &mut Box<dyn capnp_rpc::Connection<&'static str>>| {
+        let incoming = pool
+            .run_until(connection.receive_incoming_message())
+            .unwrap()
+            .unwrap();
+        let root: message::Reader = incoming.get_body().unwrap().get_as().unwrap();
+        let message::Return(Ok(ret)) = root.which().unwrap() else {
+            panic!("expected a Return");
+        };
+        let return_::Results(Ok(payload)) = ret.which().unwrap() else {
+            panic!("expected results");
+        };
+        let cap_ids = payload
+            .get_cap_table()
+            .unwrap()
+            .iter()
+            .map(|cap| match cap.which().unwrap() {
+                cap_descriptor::SenderHosted(id) => id,
+                _ => panic!("expected a sender-hosted capability"),
+            })
+            .collect::<Vec<u32>>();
+        let text = payload
+            .get_content()
+            .get_as::<test_capnp::test_interface::foo_results::Reader>()
+            .map(|results| results.get_x().unwrap().to_string().unwrap());
+        (
+            ret.get_answer_id(),
+            payload.get_content().is_null(),
+            cap_ids,
+            text,
+        )
+    };
+
+    send_raw(&mut *connection, |m| {
+        m.init_bootstrap().set_question_id(0);
+    });
+    let (answer_id, _, cap_ids, _) = receive_return(&mut connection);
+    assert_eq!(answer_id, 0);
+    let bootstrap_id = cap_ids[0];
+
+    // bootstrap.testInterface(), asking for an empty `Return`.
+    send_raw(&mut *connection, |m| {
+        let mut call = m.init_call();
+        call.set_question_id(1);
+        call.set_interface_id(test_capnp::bootstrap::_private::TYPE_ID);
+        call.set_method_id(0);
+        call.set_only_promise_pipeline(true);
+        call.reborrow().init_target().set_imported_cap(bootstrap_id);
+        call.init_params();
+    });
+
+    // A pipelined foo() on the returned capability.
+    send_raw(&mut *connection, |m| {
+        let mut call = m.init_call();
+        call.set_question_id(2);
+        call.set_interface_id(test_capnp::test_interface::_private::TYPE_ID);
+        call.set_method_id(0);
+        let mut promised_answer = call.reborrow().init_target().init_promised_answer();
+        promised_answer.set_question_id(1);
+        promised_answer
+            .init_transform(1)
+            .get(0)
+            .set_get_pointer_field(0);
+        let mut params = call
+            .init_params()
+            .init_content()
+            .init_as::<test_capnp::test_interface::foo_params::Builder>();
+        params.set_i(123);
+        params.set_j(true);
+    });
+
+    let (answer_id, content_is_null, cap_ids, _) = receive_return(&mut connection);
+    assert_eq!(answer_id, 1);
+    assert!(content_is_null);
+    assert!(cap_ids.is_empty());
+
+    let (answer_id, _, _, text) = receive_return(&mut connection);
+    assert_eq!(answer_id, 2);
+    assert_eq!(text.unwrap(), "foo");
+}
+
+#[test]
+fn no_promise_pipelining() {
+    rpc_top_level(|_spawner, client| async move {
+        let response = client.test_pipeline_request().send().promise.await?;
+        let client = response.get()?.get_cap()?;
+
+        let mut request = client.get_cap_request();
+        request.get().set_n(234);
+        request
+            .get()
+            .set_in_cap(capnp_rpc::new_client(impls::TestInterface::new()));
+        request.set_hints(capnp::capability::CallHints {
+            no_promise_pipelining: true,
+            ..Default::default()
+        });
+        let promise = request.send();
+
+        // The callee did not retain the results, so pipelining on them is an error.
+        let mut pipeline_request = promise.pipeline.get_out_box().get_cap().foo_request();
+        pipeline_request.get().set_i(321);
+        match pipeline_request.send().promise.await {
+            Err(e) if e.extra.contains("Pipeline call on a request") => (),
+            Err(e) => return Err(e),
+            Ok(_) => return Err(Error::failed("expected pipelined call to fail".into())),
+        }
+
+        // The results themselves are still returned.
+        let response = promise.promise.await?;
+        assert_eq!(response.get()?.get_s()?, "bar");
+        Ok(())
+    });
+}
+
 #[test]
 fn null_capability() {
     let mut message = ::capnp::message::Builder::new_default();
@@ -1241,6 +1683,549 @@ fn basic_streaming_on_pipeline() {
     });
 }
 
+#[test]
+fn streaming_with_queue_limits() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let (mut client_rpc_system, server_rpc_system) = twoparty_setup_with(
+        bootstrap.client,
+        capnp_futures::framing::Standard,
+        |client_network| {
+            client_network.set_streaming_queue_limits(
+                *capnp_futures::QueueLimits::new()
+                    .max_messages(Some(2))
+                    .max_bytes(Some(256)),
+            );
+        },
+    );
+
+    let client: test_capnp::bootstrap::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    {
+        use futures::task::LocalSpawnExt;
+        spawner.spawn_local(server_rpc_system.map(|_| ())).unwrap();
+    }
+
+    pool.run_until(async move {
+        let response = client.test_more_stuff_request().send().promise.await?;
+        let client = response.get()?.get_cap()?;
+        let response = client.get_test_streaming_request().send().promise.await?;
+        let client = response.get()?.get_cap()?;
+
+        const EACH: u32 = 10;
+        const ITERS: u32 = 100;
+        for _ in 0..ITERS {
+            let mut request = client.do_stream_i_request();
+            request.get().set_i(EACH);
+            request.send().await?;
+        }
+
+        let r = client.finish_stream_request().send().promise.await?;
+        assert_eq!(r.get()?.get_total_i(), ITERS * EACH);
+        Ok::<(), Error>(())
+    })
+    .unwrap();
+}
+
+/// A `TestInterface` whose `foo()` doesn't return until released, while `bar()` returns
+/// immediately.
+struct Blocking {
+    release: std::cell::RefCell<Option<oneshot::Receiver<()>>>,
+}
+
+impl test_capnp::test_interface::Server for Blocking {
+    async fn foo(
+        self: std::rc::Rc<Self>,
+        _params: test_capnp::test_interface::FooParams,
+        _results: test_capnp::test_interface::FooResults,
+    ) -> Result<(), Error> {
+        let release = self.release.borrow_mut().take().unwrap();
+        release.await.map_err(canceled_to_error)
+    }
+
+    async fn bar(
+        self: std::rc::Rc<Self>,
+        _params: test_capnp::test_interface::BarParams,
+        _results: test_capnp::test_interface::BarResults,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+#[test]
+fn flow_limit_pauses_incoming_calls() {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let (release, release_receiver) = oneshot::channel();
+    let bootstrap: test_capnp::test_interface::Client = capnp_rpc::new_client(Blocking {
+        release: RefCell::new(Some(release_receiver)),
+    });
+    let (mut client_rpc_system, mut server_rpc_system) = twoparty_setup(bootstrap.client);
+    server_rpc_system.set_flow_limit(1);
+
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    spawn(&mut spawner, server_rpc_system);
+
+    let foo_done = Rc::new(Cell::new(false));
+    let bar_done = Rc::new(Cell::new(false));
+    {
+        let foo_done = foo_done.clone();
+        let request = client.foo_request();
+        spawn(
+            &mut spawner,
+            request.send().promise.map_ok(move |_| foo_done.set(true)),
+        );
+    }
+    {
+        let bar_done = bar_done.clone();
+        let request = client.bar_request();
+        spawn(
+            &mut spawner,
+            request.send().promise.map_ok(move |_| bar_done.set(true)),
+        );
+    }
+
+    // `foo()` is over the limit, so the server doesn't even read the `bar()` call.
+    pool.run_until_stalled();
+    assert!(!foo_done.get());
+    assert!(!bar_done.get());
+
+    release.send(()).unwrap();
+    pool.run_until_stalled();
+    assert!(foo_done.get());
+    assert!(bar_done.get());
+}
+
+#[test]
+fn keepalive_waits_while_flow_limit_holds_back_messages() {
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Duration;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let (release, release_receiver) = oneshot::channel();
+    let bootstrap: test_capnp::test_interface::Client = capnp_rpc::new_client(Blocking {
+        release: RefCell::new(Some(release_receiver)),
+    });
+    let (mut client_rpc_system, mut server_rpc_system) = twoparty_setup(bootstrap.client);
+    server_rpc_system.set_flow_limit(1);
+    let timer = ManualTimer::default();
+    server_rpc_system.set_keepalive(keepalive::Keepalive::new(
+        timer.timer(),
+        Duration::from_secs(1),
+        Duration::from_secs(3),
+    ));
+
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    spawn(&mut spawner, server_rpc_system);
+
+    let foo_done = Rc::new(Cell::new(false));
+    let bar_done = Rc::new(Cell::new(false));
+    {
+        let foo_done = foo_done.clone();
+        let request = client.foo_request();
+        spawn(
+            &mut spawner,
+            request.send().promise.map_ok(move |_| foo_done.set(true)),
+        );
+    }
+    {
+        let bar_done = bar_done.clone();
+        let request = client.bar_request();
+        spawn(
+            &mut spawner,
+            request.send().promise.map_ok(move |_| bar_done.set(true)),
+        );
+    }
+
+    // The server reads nothing from the client for well past the keepalive timeout, but that
+    // is because of its own flow limit, so it must not give up on the client.
+    for _ in 0..10 {
+        pool.run_until_stalled();
+        timer.tick();
+    }
+    pool.run_until_stalled();
+    assert!(!foo_done.get());
+
+    release.send(()).unwrap();
+    pool.run_until_stalled();
+    assert!(foo_done.get());
+    assert!(bar_done.get());
+}
+
+/// Connects a client to a server whose bootstrap is a `Blocking`, then starts a call to `foo()`
+/// and has the server drain until `deadline`. Returns the client, the sender that releases
+/// `foo()`, and receivers for the result of the call and for the drain report.
+#[allow(clippy::type_complexity)]
+fn drain_setup(
+    spawner: &mut futures::executor::LocalSpawner,
+    deadline: Promise<(), Error>,
+) -> (
+    test_capnp::test_interface::Client,
+    oneshot::Sender<()>,
+    oneshot::Receiver<Result<(), Error>>,
+    oneshot::Receiver<Result<capnp_rpc::DrainReport, Error>>,
+) {
+    let (release, release_receiver) = oneshot::channel();
+    let bootstrap: test_capnp::test_interface::Client = capnp_rpc::new_client(Blocking {
+        release: std::cell::RefCell::new(Some(release_receiver)),
+    });
+    let (mut client_rpc_system, server_rpc_system) = twoparty_setup(bootstrap.client);
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(spawner, client_rpc_system);
+
+    let drain = server_rpc_system.drain(deadline);
+    spawn(spawner, server_rpc_system);
+
+    let (foo_sender, foo_result) = oneshot::channel();
+    spawn(
+        spawner,
+        client.foo_request().send().promise.map(|r| {
+            let _ = foo_sender.send(r.map(drop));
+            Ok(())
+        }),
+    );
+
+    // Only start draining once `foo()` is in progress.
+    let (report_sender, report) = oneshot::channel();
+    let foo_started = client.bar_request().send().promise;
+    spawn(spawner, async move {
+        foo_started.await?;
+        let _ = report_sender.send(drain.await);
+        Ok(())
+    });
+    (client, release, foo_result, report)
+}
+
+#[test]
+fn drain_waits_for_calls_in_progress() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let deadline = Promise::from_future(futures::future::pending());
+    let (client, release, foo_result, mut report) = drain_setup(&mut spawner, deadline);
+    pool.run_until_stalled();
+
+    // New calls are turned away while `foo()` is still running.
+    match pool.run_until(client.bar_request().send().promise) {
+        Err(e) => assert_eq!(e.kind, capnp::ErrorKind::Overloaded),
+        Ok(_) => panic!("expected the call to be rejected"),
+    }
+    assert!(report.try_recv().unwrap().is_none());
+
+    release.send(()).unwrap();
+    pool.run_until(foo_result).unwrap().unwrap();
+    let report = pool.run_until(report).unwrap().unwrap();
+    assert_eq!(
+        report,
+        capnp_rpc::DrainReport {
+            completed_calls: 1,
+            canceled_calls: 0,
+            rejected_calls: 1,
+            abandoned_questions: 0,
+        }
+    );
+}
+
+#[test]
+fn drain_gives_up_at_deadline() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let (deadline_sender, deadline) = oneshot::channel();
+    let deadline = Promise::from_future(deadline.map_err(canceled_to_error));
+    let (_client, _release, foo_result, report) = drain_setup(&mut spawner, deadline);
+    pool.run_until_stalled();
+
+    deadline_sender.send(()).unwrap();
+    let report = pool.run_until(report).unwrap().unwrap();
+    assert_eq!(
+        report,
+        capnp_rpc::DrainReport {
+            completed_calls: 0,
+            canceled_calls: 1,
+            rejected_calls: 0,
+            abandoned_questions: 0,
+        }
+    );
+    match pool.run_until(foo_result).unwrap() {
+        Err(e) => assert_eq!(e.kind, capnp::ErrorKind::Disconnected),
+        Ok(()) => panic!("expected the call to fail"),
+    }
+}
+
+#[test]
+fn drain_before_connection_rejects_every_call() {
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let bootstrap: test_capnp::test_interface::Client =
+        capnp_rpc::new_client(impls::TestInterface::new());
+    let (mut client_rpc_system, server_rpc_system) = twoparty_setup(bootstrap.client);
+
+    // The server has not accepted its connection yet when `drain()` is first polled.
+    let (report_sender, report) = oneshot::channel();
+    let drain = server_rpc_system.drain(Promise::from_future(futures::future::pending()));
+    spawn(&mut spawner, async move {
+        let _ = report_sender.send(drain.await);
+        Ok(())
+    });
+    pool.run_until_stalled();
+
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    spawn(&mut spawner, server_rpc_system);
+
+    let mut request = client.foo_request();
+    request.get().set_i(123);
+    request.get().set_j(true);
+    if pool.run_until(request.send().promise).is_ok() {
+        panic!("expected the call to fail");
+    }
+    let report = pool.run_until(report).unwrap().unwrap();
+    assert_eq!(report.completed_calls, 0);
+    assert_eq!(report.canceled_calls, 0);
+}
+
+#[test]
+fn connection_stats_drain_to_zero() {
+    use capnp::traits::HasTypeId;
+    use capnp_rpc::stats::QuestionKind;
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let network = loopback::Network::new();
+
+    let bootstrap: test_capnp::bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server_rpc_system =
+        RpcSystem::new(Box::new(network.add_vat("server")), Some(bootstrap.client));
+    let server_inspector = server_rpc_system.get_inspector();
+    spawn(&mut spawner, server_rpc_system);
+
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    let client_inspector = rpc_system.get_inspector();
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+
+    let response = pool
+        .run_until(client.test_interface_request().send().promise)
+        .unwrap();
+    let cap = response.get().unwrap().get_cap().unwrap();
+    drop(response);
+
+    let stats = client_inspector.connection_stats().unwrap();
+    assert_eq!(stats.imports.len(), 1);
+    assert_eq!(stats.imports[0].remote_refcount, 1);
+    let stats = server_inspector.connection_stats().unwrap();
+    assert_eq!(stats.exports.len(), 1);
+    assert_eq!(stats.exports[0].refcount, 1);
+
+    let mut request = cap.foo_request();
+    request.get().set_i(123);
+    request.get().set_j(true);
+    let promise = request.send().promise;
+    let foo_kind = QuestionKind::Call {
+        interface_id: test_capnp::test_interface::Client::TYPE_ID,
+        method_id: 0,
+    };
+    let stats = client_inspector.connection_stats().unwrap();
+    assert!(stats
+        .questions
+        .iter()
+        .any(|q| q.kind == foo_kind && q.awaiting_return));
+    drop(pool.run_until(promise).unwrap());
+
+    drop(cap);
+    drop(client);
+    pool.run_until_stalled();
+    assert!(client_inspector.connection_stats().unwrap().is_empty());
+    assert!(server_inspector.connection_stats().unwrap().is_empty());
+
+    pool.run_until(disconnector).unwrap();
+    pool.run_until_stalled();
+    assert!(client_inspector.connection_stats().is_none());
+}
+
+#[test]
+fn inspector_does_not_keep_connection_alive() {
+    let network = loopback::Network::new();
+    let _server_rpc_system = RpcSystem::new(Box::new(network.add_vat("server")), None);
+    let mut rpc_system = RpcSystem::new(Box::new(network.add_vat("client")), None);
+    let client: test_capnp::bootstrap::Client = rpc_system.bootstrap("server");
+    let inspector = rpc_system.get_inspector();
+    assert!(inspector.connection_stats().is_some());
+
+    // No disconnect, so only dropping the `RpcSystem` releases the connection.
+    drop(rpc_system);
+    assert!(inspector.connection_stats().is_none());
+    drop(client);
+}
+
+#[test]
+fn interceptor_sees_calls_and_returns() {
+    use capnp::traits::HasTypeId;
+    use capnp_rpc::interceptor::{CallInfo, Interceptor, Metadata, ReturnInfo, ReturnKind};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[derive(Default)]
+    struct Recorder {
+        calls: RefCell<Vec<CallInfo>>,
+        returns: RefCell<Vec<(ReturnInfo, u16)>>,
+    }
+
+    impl Recorder {
+        fn call(&self, call: &CallInfo) -> Option<Metadata> {
+            self.calls.borrow_mut().push(*call);
+            Some(Box::new(call.method_id))
+        }
+
+        fn ret(&self, ret: &ReturnInfo, metadata: Option<Metadata>) {
+            let method_id = *metadata.unwrap().downcast::<u16>().unwrap();
+            self.returns.borrow_mut().push((*ret, method_id));
+        }
+    }
+
+    impl Interceptor for Recorder {
+        fn outgoing_call(&self, call: &CallInfo) -> Option<Metadata> {
+            self.call(call)
+        }
+        fn incoming_return(&self, ret: &ReturnInfo, metadata: Option<Metadata>) {
+            self.ret(ret, metadata)
+        }
+        fn incoming_call(&self, call: &CallInfo) -> Option<Metadata> {
+            self.call(call)
+        }
+        fn outgoing_return(&self, ret: &ReturnInfo, metadata: Option<Metadata>) {
+            self.ret(ret, metadata)
+        }
+    }
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let bootstrap: test_capnp::test_interface::Client =
+        capnp_rpc::new_client(impls::TestInterface::new());
+    let (mut client_rpc_system, mut server_rpc_system) = twoparty_setup(bootstrap.client);
+    let client_recorder = Rc::new(Recorder::default());
+    client_rpc_system.set_interceptor(client_recorder.clone());
+    let server_recorder = Rc::new(Recorder::default());
+    server_rpc_system.set_interceptor(server_recorder.clone());
+
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    spawn(&mut spawner, server_rpc_system);
+
+    pool.run_until(async move {
+        let mut request = client.foo_request();
+        request.get().set_i(123);
+        request.get().set_j(true);
+        request.send().promise.await.unwrap();
+        assert!(client.bar_request().send().promise.await.is_err());
+    });
+
+    for recorder in [client_recorder, server_recorder] {
+        let calls = recorder.calls.borrow();
+        assert_eq!(calls.len(), 2);
+        for (call, method_id) in calls.iter().zip([0, 1]) {
+            assert_eq!(
+                call.interface_id,
+                test_capnp::test_interface::Client::TYPE_ID
+            );
+            assert_eq!(call.method_id, method_id);
+            assert!(call.size_in_words > 0);
+        }
+
+        let returns = recorder.returns.borrow();
+        assert_eq!(returns.len(), 2);
+        assert_eq!(returns[0].0.question_id, calls[0].question_id);
+        assert_eq!(returns[0].0.kind, ReturnKind::Results);
+        assert_eq!(returns[0].1, 0);
+        assert_eq!(returns[1].0.question_id, calls[1].question_id);
+        assert_eq!(
+            returns[1].0.kind,
+            ReturnKind::Exception(capnp::ErrorKind::Unimplemented)
+        );
+        assert_eq!(returns[1].1, 1);
+    }
+}
+
+#[test]
+fn interceptor_propagates_trace_id() {
+    use capnp::traits::HasTypeId;
+    use capnp_rpc::interceptor::{CallInfo, Interceptor, Metadata};
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // Carries a trace ID in the `i` parameter of `TestInterface.foo()`.
+    struct Tracer {
+        trace_id: Cell<u32>,
+    }
+
+    fn is_foo(call: &CallInfo) -> bool {
+        call.interface_id == test_capnp::test_interface::Client::TYPE_ID && call.method_id == 0
+    }
+
+    impl Interceptor for Tracer {
+        fn outgoing_call_params(&self, call: &CallInfo, params: capnp::any_pointer::Builder<'_>) {
+            if is_foo(call) {
+                let mut params: test_capnp::test_interface::foo_params::Builder =
+                    params.get_as().unwrap();
+                params.set_i(self.trace_id.get());
+            }
+        }
+
+        fn incoming_call_with_params(
+            &self,
+            call: &CallInfo,
+            params: capnp::any_pointer::Reader<'_>,
+        ) -> Option<Metadata> {
+            if is_foo(call) {
+                let params: test_capnp::test_interface::foo_params::Reader =
+                    params.get_as().unwrap();
+                self.trace_id.set(params.get_i());
+            }
+            None
+        }
+    }
+
+    let mut pool = futures::executor::LocalPool::new();
+    let mut spawner = pool.spawner();
+    let bootstrap: test_capnp::test_interface::Client =
+        capnp_rpc::new_client(impls::TestInterface::new());
+    let (mut client_rpc_system, mut server_rpc_system) = twoparty_setup(bootstrap.client);
+    client_rpc_system.set_interceptor(Rc::new(Tracer {
+        trace_id: Cell::new(123),
+    }));
+    let server_tracer = Rc::new(Tracer {
+        trace_id: Cell::new(0),
+    });
+    server_rpc_system.set_interceptor(server_tracer.clone());
+
+    let client: test_capnp::test_interface::Client =
+        client_rpc_system.bootstrap(rpc_twoparty_capnp::Side::Server);
+    spawn(&mut spawner, client_rpc_system);
+    spawn(&mut spawner, server_rpc_system);
+
+    // `foo()` fails unless `i` is 123, which only the client's interceptor sets.
+    pool.run_until(async move {
+        let mut request = client.foo_request();
+        request.get().set_j(true);
+        request.send().promise.await.unwrap();
+    });
+    assert_eq!(server_tracer.trace_id.get(), 123);
+}
+
 #[test]
 fn stream_error_gets_reported() {
     rpc_and_local_top_level(|_spawner, client| async move {
@@ -0,0 +1,101 @@
+use capnp_rpc::rpc_twoparty_capnp::Side;
+use capnp_rpc::twoparty::websocket::{FrameReader, FrameWriter};
+use capnp_rpc::{twoparty, RpcSystem};
+use futures::executor::LocalPool;
+use futures::{AsyncRead, SinkExt, StreamExt, TryStreamExt};
+use tokio_tungstenite::tungstenite::{self, Message};
+use tokio_tungstenite::WebSocketStream;
+
+use std::cell::Cell;
+use std::io;
+use std::rc::Rc;
+
+use crate::test_capnp::bootstrap;
+use crate::{impls, spawn};
+
+/// Makes a network over `websocket`, checking that every binary frame that it sends holds
+/// exactly one message, and counting the frames in `frames_sent`.
+fn network<S>(
+    websocket: WebSocketStream<S>,
+    side: Side,
+    frames_sent: Rc<Cell<usize>>,
+) -> twoparty::VatNetwork<impl AsyncRead + Unpin>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + 'static,
+{
+    let (sink, stream) = websocket.split();
+    let stream = stream
+        .try_filter_map(|message| async move {
+            match message {
+                Message::Binary(data) => Ok(Some(data.to_vec())),
+                Message::Text(_) => Err(tungstenite::Error::Io(io::Error::other("text frame"))),
+                _ => Ok(None),
+            }
+        })
+        .map_err(io::Error::other);
+    let sink = sink
+        .with(move |data: Vec<u8>| {
+            let mut slice = &data[..];
+            capnp::serialize::read_message_from_flat_slice(&mut slice, Default::default()).unwrap();
+            assert!(slice.is_empty(), "frame holds more than one message");
+            frames_sent.set(frames_sent.get() + 1);
+            async move { Ok::<_, tungstenite::Error>(Message::binary(data)) }
+        })
+        .sink_map_err(io::Error::other);
+    twoparty::VatNetwork::new(
+        FrameReader::new(Box::pin(stream)),
+        FrameWriter::new(Box::pin(sink)),
+        side,
+        Default::default(),
+    )
+}
+
+#[test]
+fn calls_over_websocket() {
+    let mut pool = LocalPool::new();
+    let mut spawner = pool.spawner();
+
+    let (server_stream, client_stream) = tokio::io::duplex(64);
+    let (server_websocket, client_websocket) = pool.run_until(futures::future::join(
+        tokio_tungstenite::accept_async(server_stream),
+        tokio_tungstenite::client_async("ws://localhost/", client_stream),
+    ));
+    let (client_websocket, _) = client_websocket.unwrap();
+
+    let server_frames = Rc::new(Cell::new(0));
+    let bootstrap: bootstrap::Client = capnp_rpc::new_client(impls::Bootstrap);
+    let server_network = network(
+        server_websocket.unwrap(),
+        Side::Server,
+        server_frames.clone(),
+    );
+    spawn(
+        &mut spawner,
+        RpcSystem::new(Box::new(server_network), Some(bootstrap.client)),
+    );
+
+    let client_frames = Rc::new(Cell::new(0));
+    let client_network = network(client_websocket, Side::Client, client_frames.clone());
+    let mut rpc_system = RpcSystem::new(Box::new(client_network), None);
+    let client: bootstrap::Client = rpc_system.bootstrap(Side::Server);
+    let disconnector = rpc_system.get_disconnector();
+    spawn(&mut spawner, rpc_system);
+
+    pool.run_until(async move {
+        let response = client.test_interface_request().send().promise.await?;
+        let cap = response.get()?.get_cap()?;
+        for i in 0..3 {
+            let mut request = cap.foo_request();
+            request.get().set_i(123);
+            request.get().set_j(true);
+            let response = request.send().promise.await?;
+            assert_eq!(response.get()?.get_x()?, "foo", "call {i}");
+        }
+        Ok::<(), capnp::Error>(())
+    })
+    .unwrap();
+    assert!(client_frames.get() >= 5);
+    assert!(server_frames.get() >= 5);
+
+    pool.run_until(disconnector).unwrap();
+}
@@ -178,6 +178,22 @@ where
     }
 }
 
+/// Hints about how the caller intends to use the results of a call, which the callee may use
+/// to avoid unnecessary work. See `Call.noPromisePipelining` and `Call.onlyPromisePipeline` in
+/// `rpc.capnp`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CallHints {
+    /// The caller will not make any pipelined calls on the results, so the callee need not
+    /// retain them after returning.
+    pub no_promise_pipelining: bool,
+
+    /// The caller will only make pipelined calls on the results and will never look at the
+    /// results themselves, so the callee may leave them out of its `Return`.
+    ///
+    /// `capnp-rpc` honors this hint when a peer sends it, but does not yet send it itself.
+    pub only_promise_pipeline: bool,
+}
+
 /// A method call that has not been sent yet.
 #[cfg(feature = "alloc")]
 pub struct Request<Params, Results> {
@@ -204,6 +220,11 @@ where
     pub fn set(&mut self, from: Params::Reader<'_>) -> crate::Result<()> {
         self.hook.get().set_as(from)
     }
+
+    /// Sets hints about how the results of this call will be used.
+    pub fn set_hints(&mut self, hints: CallHints) {
+        self.hook.set_hints(hints)
+    }
 }
 
 #[cfg(feature = "alloc")]
@@ -59,6 +59,10 @@ pub mod private;
 pub mod raw;
 pub mod schema;
 pub mod serialize;
+#[cfg(feature = "alloc")]
+pub mod serialize_checksummed;
+#[cfg(any(feature = "lz4", feature = "zstd"))]
+pub mod serialize_compressed;
 pub mod serialize_packed;
 pub(crate) mod stringify;
 pub mod struct_list;
@@ -237,6 +241,9 @@ pub enum ErrorKind {
     /// Cannot set AnyPointer field to a primitive value
     CannotSetAnyPointerFieldToAPrimitiveValue,
 
+    /// Checksum mismatch. Frame claimed checksum {expected}, but its contents have checksum {actual}
+    ChecksumMismatch(u32, u32),
+
     /// Don't know how to handle non-STRUCT inline composite.
     CantHandleNonStructInlineComposite,
 
@@ -306,6 +313,9 @@ pub enum ErrorKind {
     /// InlineComposite lists of non-STRUCT type are not supported.
     InlineCompositeListsOfNonStructTypeAreNotSupported,
 
+    /// Invalid frame header
+    InvalidFrameHeader,
+
     /// Too many or too few segments {segment_count}
     InvalidNumberOfSegments(usize),
 
@@ -411,6 +421,12 @@ pub enum ErrorKind {
     /// Tried to read from null arena
     TriedToReadFromNullArena,
 
+    /// Frame is truncated. Header claimed {expected} bytes, but only {available} bytes are present
+    TruncatedFrame(usize, usize),
+
+    /// Frame header is truncated. Only {available} of its {expected} bytes are present
+    TruncatedFrameHeader(usize, usize),
+
     /// type mismatch
     TypeMismatch,
 
@@ -561,6 +577,7 @@ impl core::fmt::Display for ErrorKind {
             Self::FourByteLengthTooBigForUSize => write!(fmt, "Cannot represent 4 byte length as `usize`. This may indicate that you are running on 8 or 16 bit platform or message is too large."),
             Self::FourByteSegmentLengthTooBigForUSize => write!(fmt, "Cannot represent 4 byte segment length as usize. This may indicate that you are running on 8 or 16 bit platform or segment is too large"),
             Self::CannotSetAnyPointerFieldToAPrimitiveValue => write!(fmt, "cannot set AnyPointer field to a primitive value"),
+            Self::ChecksumMismatch(expected, actual) => write!(fmt, "Checksum mismatch. Frame claimed checksum {expected:#010x}, but its contents have checksum {actual:#010x}"),
             Self::CantHandleNonStructInlineComposite => write!(fmt, "Don't know how to handle non-STRUCT inline composite."),
             Self::EmptyBuffer => write!(fmt, "empty buffer"),
             Self::EmptySlice => write!(fmt, "empty slice"),
@@ -580,6 +597,7 @@ impl core::fmt::Display for ErrorKind {
             Self::InlineCompositeListWithNonStructElementsNotSupported => write!(fmt, "InlineComposite list with non-STRUCT elements not supported."),
             Self::InlineCompositeListsElementsOverrunItsWordCount => write!(fmt, "InlineComposite list's elements overrun its word count."),
             Self::InlineCompositeListsOfNonStructTypeAreNotSupported => write!(fmt, "InlineComposite lists of non-STRUCT type are not supported."),
+            Self::InvalidFrameHeader => write!(fmt, "Invalid frame header"),
             Self::InvalidNumberOfSegments(segment_count) => write!(fmt, "Too many or too few segments {segment_count}"),
             Self::InvalidSegmentId(id) => write!(fmt, "Invalid segment id {id}"),
             Self::ListAnyPointerNotSupported => write!(fmt, "List(AnyPointer) not supported."),
@@ -615,6 +633,8 @@ impl core::fmt::Display for ErrorKind {
             Self::TextBlobMissingNULTerminator => write!(fmt, "Text blob missing NUL terminator."),
             Self::TextContainsNonUtf8Data(e) => write!(fmt, "Text contains non-utf8 data: {e}"),
             Self::TriedToReadFromNullArena => write!(fmt, "Tried to read from null arena"),
+            Self::TruncatedFrame(expected, available) => write!(fmt, "Frame is truncated. Header claimed {expected} bytes, but only {available} bytes are present"),
+            Self::TruncatedFrameHeader(expected, available) => write!(fmt, "Frame header is truncated. Only {available} of its {expected} bytes are present"),
             Self::TypeMismatch => write!(fmt, "type mismatch"),
             Self::UnalignedSegment => write!(fmt, "Detected unaligned segment. You must either ensure all of your segments are 8-byte aligned, or you must enable the \"unaligned\" feature in the capnp crate"),
             Self::UnexpectedFarPointer => write!(fmt, "Unexpected far pointer"),
@@ -668,6 +688,25 @@ impl<'a> core::ops::Deref for OutputSegments<'a> {
     }
 }
 
+impl<'a> OutputSegments<'a> {
+    /// Returns the segment table that precedes these segments in the standard stream framing.
+    pub fn segment_table(&self) -> serialize::SegmentTable {
+        serialize::SegmentTable::new(self)
+    }
+
+    /// Returns the segment table followed by each of the segments, as `IoSlice`s suitable for
+    /// passing to `std::io::Write::write_vectored()` or to a custom transport. `segment_table`
+    /// should come from `self.segment_table()`.
+    #[cfg(feature = "std")]
+    pub fn io_slices<'b>(
+        &'b self,
+        segment_table: &'b serialize::SegmentTable,
+    ) -> impl Iterator<Item = std::io::IoSlice<'b>> + 'b {
+        core::iter::once(std::io::IoSlice::new(segment_table))
+            .chain(self.iter().map(|segment| std::io::IoSlice::new(segment)))
+    }
+}
+
 impl message::ReaderSegments for OutputSegments<'_> {
     fn get_segment(&self, id: u32) -> Option<&[u8]> {
         match self {
@@ -23,7 +23,7 @@ use core::slice;
 use crate::message;
 use crate::message::Allocator;
 use crate::message::ReaderSegments;
-use crate::private::read_limiter::ReadLimiter;
+use crate::message::{ReadLimit, ReadLimiter};
 use crate::private::units::*;
 use crate::OutputSegments;
 use crate::{Error, ErrorKind, Result};
@@ -72,9 +72,9 @@ pub unsafe trait ReaderArena {
     //   layout::StructReader, layout::ListReader, etc. could drop their `cap_table` fields.
 }
 
-pub struct ReaderArenaImpl<S> {
+pub struct ReaderArenaImpl<S, L = ReadLimiter> {
     segments: S,
-    read_limiter: ReadLimiter,
+    read_limiter: L,
     nesting_limit: i32,
 }
 
@@ -83,6 +83,8 @@ fn _assert_sync() {
     fn _assert_sync<T: Sync>() {}
     fn _assert_reader<S: ReaderSegments + Sync>() {
         _assert_sync::<ReaderArenaImpl<S>>();
+        #[cfg(feature = "alloc")]
+        _assert_sync::<ReaderArenaImpl<S, message::SharedReadLimiter>>();
     }
 }
 
@@ -92,9 +94,25 @@ where
 {
     pub fn new(segments: S, options: message::ReaderOptions) -> Self {
         let limiter = ReadLimiter::new(options.traversal_limit_in_words);
+        Self::new_with_read_limiter(segments, options, limiter)
+    }
+}
+
+impl<S, L> ReaderArenaImpl<S, L>
+where
+    S: ReaderSegments,
+    L: ReadLimit,
+{
+    /// Like `new()`, but charges reads to `read_limiter` instead of to a limiter of its own.
+    /// `options.traversal_limit_in_words` is ignored.
+    pub fn new_with_read_limiter(
+        segments: S,
+        options: message::ReaderOptions,
+        read_limiter: L,
+    ) -> Self {
         Self {
             segments,
-            read_limiter: limiter,
+            read_limiter,
             nesting_limit: options.nesting_limit,
         }
     }
@@ -108,9 +126,10 @@ where
     }
 }
 
-unsafe impl<S> ReaderArena for ReaderArenaImpl<S>
+unsafe impl<S, L> ReaderArena for ReaderArenaImpl<S, L>
 where
     S: ReaderSegments,
+    L: ReadLimit,
 {
     fn get_segment(&self, id: u32) -> Result<(*const u8, u32)> {
         match self.segments.get_segment(id) {
@@ -22,7 +22,7 @@
 #![cfg(feature = "alloc")]
 
 use crate::any_pointer;
-use crate::capability::{Params, Promise, RemotePromise, Request, Results};
+use crate::capability::{CallHints, Params, Promise, RemotePromise, Request, Results};
 use crate::MessageSize;
 
 pub trait ResponseHook {
@@ -32,6 +32,11 @@ pub trait ResponseHook {
 pub trait RequestHook {
     fn get(&mut self) -> any_pointer::Builder<'_>;
     fn get_brand(&self) -> usize;
+
+    /// Sets hints about how the caller will use the results of this call. Implementations
+    /// that have no use for them may ignore them.
+    fn set_hints(&mut self, _hints: CallHints) {}
+
     fn send(self: alloc::boxed::Box<Self>) -> RemotePromise<any_pointer::Owned>;
     fn send_streaming(self: alloc::boxed::Box<Self>) -> Promise<(), crate::Error>;
     fn tail_send(
@@ -83,6 +88,15 @@ pub trait ClientHook {
 
     /// Repeatedly calls whenMoreResolved() until it returns nullptr.
     fn when_resolved(&self) -> Promise<(), crate::Error>;
+
+    /// If this capability wraps a file descriptor, returns it, so that the RPC system can pass it
+    /// along when the capability is sent over a transport that supports that, such as a Unix
+    /// domain socket. The descriptor remains owned by the capability. Promise capabilities
+    /// return `None` until they have resolved.
+    #[cfg(all(feature = "std", unix))]
+    fn get_fd(&self) -> Option<std::os::fd::RawFd> {
+        None
+    }
 }
 
 impl Clone for alloc::boxed::Box<dyn ClientHook> {
@@ -582,6 +582,9 @@ mod wire_helpers {
         //# reachable.
 
         match (*reff).kind() {
+            // A capability pointer has no object in the message. The capability stays
+            // in the cap table until the message is dropped.
+            WirePointerKind::Other if (*reff).is_capability() => {}
             WirePointerKind::Struct | WirePointerKind::List | WirePointerKind::Other => {
                 zero_object_helper(arena, segment_id, reff, WirePointer::mut_target(reff))
             }
@@ -18,17 +18,19 @@
 // LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
 // OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
 // THE SOFTWARE.
-
 #[cfg(feature = "sync_reader")]
-pub(crate) use sync::ReadLimiter;
+pub use sync::ReadLimiter;
 
 #[cfg(feature = "sync_reader")]
 mod sync {
     use crate::{Error, ErrorKind, Result};
     use core::sync::atomic::{AtomicUsize, Ordering};
 
-    pub(crate) struct ReadLimiter {
+    /// The limiter that a reader uses when it has not been given a shared one.
+    pub struct ReadLimiter {
         limit: AtomicUsize,
+        #[cfg(feature = "alloc")]
+        initial: usize,
         error_on_limit_exceeded: bool,
     }
 
@@ -37,10 +39,14 @@ mod sync {
             match limit {
                 Some(value) => Self {
                     limit: AtomicUsize::new(value),
+                    #[cfg(feature = "alloc")]
+                    initial: value,
                     error_on_limit_exceeded: true,
                 },
                 None => Self {
                     limit: AtomicUsize::new(usize::MAX),
+                    #[cfg(feature = "alloc")]
+                    initial: usize::MAX,
                     error_on_limit_exceeded: false,
                 },
             }
@@ -66,19 +72,42 @@ mod sync {
             }
             Ok(())
         }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn words_read(&self) -> usize {
+            self.initial
+                .wrapping_sub(self.limit.load(Ordering::Relaxed))
+        }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn words_remaining(&self) -> Option<usize> {
+            if self.error_on_limit_exceeded {
+                Some(self.limit.load(Ordering::Relaxed))
+            } else {
+                None
+            }
+        }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn reset(&self) {
+            self.limit.store(self.initial, Ordering::Relaxed);
+        }
     }
 }
 
 #[cfg(not(feature = "sync_reader"))]
-pub(crate) use unsync::ReadLimiter;
+pub use unsync::ReadLimiter;
 
 #[cfg(not(feature = "sync_reader"))]
 mod unsync {
     use crate::{Error, ErrorKind, Result};
     use core::cell::Cell;
 
-    pub(crate) struct ReadLimiter {
+    /// The limiter that a reader uses when it has not been given a shared one.
+    pub struct ReadLimiter {
         limit: Cell<usize>,
+        #[cfg(feature = "alloc")]
+        initial: usize,
         error_on_limit_exceeded: bool,
     }
 
@@ -87,10 +116,14 @@ mod unsync {
             match limit {
                 Some(value) => Self {
                     limit: Cell::new(value),
+                    #[cfg(feature = "alloc")]
+                    initial: value,
                     error_on_limit_exceeded: true,
                 },
                 None => Self {
                     limit: Cell::new(usize::MAX),
+                    #[cfg(feature = "alloc")]
+                    initial: usize::MAX,
                     error_on_limit_exceeded: false,
                 },
             }
@@ -109,5 +142,24 @@ mod unsync {
                 Ok(())
             }
         }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn words_read(&self) -> usize {
+            self.initial.wrapping_sub(self.limit.get())
+        }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn words_remaining(&self) -> Option<usize> {
+            if self.error_on_limit_exceeded {
+                Some(self.limit.get())
+            } else {
+                None
+            }
+        }
+
+        #[cfg(feature = "alloc")]
+        pub(crate) fn reset(&self) {
+            self.limit.set(self.initial);
+        }
     }
 }
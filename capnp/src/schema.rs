@@ -1,12 +1,12 @@
 //! Convenience wrappers of the datatypes defined in schema.capnp.
 
-use crate::dynamic_value;
-use crate::introspect::{self, RawBrandedStructSchema, RawEnumSchema};
+use crate::introspect::{self, RawBrandedStructSchema, RawEnumSchema, TypeVariant};
 use crate::private::layout;
-use crate::schema_capnp::{annotation, enumerant, field, node};
+use crate::schema_capnp::{annotation, enumerant, field, node, value};
 use crate::struct_list;
 use crate::traits::{IndexMove, ListIter, ShortListIter};
 use crate::Result;
+use crate::{dynamic_list, dynamic_struct, dynamic_value};
 
 /// A struct node, with generics applied.
 #[derive(Clone, Copy)]
@@ -171,6 +171,49 @@ impl Field {
         self.index
     }
 
+    /// Gets the default value of this field, as declared in the schema.
+    /// For a group field, returns a struct in which every field has its default value.
+    pub fn get_default_value(self) -> Result<dynamic_value::Reader<'static>> {
+        let slot = match self.proto.which()? {
+            field::Slot(s) => s,
+            field::Group(_) => {
+                let TypeVariant::Struct(schema) = self.ty.which() else {
+                    return Err(crate::Error::from_kind(
+                        crate::ErrorKind::GroupFieldButTypeIsNotStruct,
+                    ));
+                };
+                return Ok(dynamic_struct::Reader::new(
+                    layout::StructReader::new_default(),
+                    schema.into(),
+                )
+                .into());
+            }
+        };
+        let default_value = slot.get_default_value()?;
+        match (default_value.which()?, self.ty.which()) {
+            // If the type is a generic, then the default value
+            // is always an empty AnyPointer. Interpret it as the type's zero value.
+            (value::AnyPointer(a), TypeVariant::Text) => {
+                Ok(dynamic_value::Reader::Text(a.reader.get_text(None)?))
+            }
+            (value::AnyPointer(a), TypeVariant::Data) => {
+                Ok(dynamic_value::Reader::Data(a.reader.get_data(None)?))
+            }
+            (value::AnyPointer(a), TypeVariant::Struct(schema)) => {
+                Ok(dynamic_struct::Reader::new(a.reader.get_struct(None)?, schema.into()).into())
+            }
+            (value::AnyPointer(a), TypeVariant::List(element_type)) => {
+                Ok(dynamic_list::Reader::new(
+                    a.reader
+                        .get_list(element_type.expected_element_size(), None)?,
+                    element_type,
+                )
+                .into())
+            }
+            _ => dynamic_value::Reader::new(default_value, self.ty),
+        }
+    }
+
     pub fn get_annotations(self) -> Result<AnnotationList> {
         Ok(AnnotationList {
             annotations: self.proto.get_annotations()?,
@@ -567,6 +610,39 @@ mod tests {
         );
     }
 
+    #[test]
+    fn field_default_values() {
+        let crate::introspect::TypeVariant::Struct(struct_schema) =
+            crate::schema_capnp::field::Owned::introspect().which()
+        else {
+            panic!("Expected a struct schema");
+        };
+
+        let struct_schema = crate::schema::StructSchema::new(struct_schema);
+
+        let discriminant_value = struct_schema
+            .get_field_by_name("discriminantValue")
+            .unwrap()
+            .get_default_value()
+            .unwrap();
+        assert_eq!(discriminant_value.downcast::<u16>(), 0xffff);
+
+        let name = struct_schema
+            .get_field_by_name("name")
+            .unwrap()
+            .get_default_value()
+            .unwrap();
+        assert_eq!(name.downcast::<crate::text::Reader>(), "");
+
+        let slot: crate::dynamic_struct::Reader = struct_schema
+            .get_field_by_name("slot")
+            .unwrap()
+            .get_default_value()
+            .unwrap()
+            .downcast();
+        assert_eq!(slot.get_named("offset").unwrap().downcast::<u32>(), 0);
+    }
+
     #[test]
     fn fields_can_be_compared() {
         let crate::introspect::TypeVariant::Struct(struct_schema) =
@@ -176,6 +176,28 @@ pub struct OwnedSegments {
     segment_indices: alloc::vec::Vec<(usize, usize)>,
 
     owned_space: alloc::vec::Vec<crate::Word>,
+
+    // Released when the segments are dropped.
+    #[cfg(feature = "std")]
+    reservation: Option<message::QuotaReservation>,
+}
+
+#[cfg(all(feature = "alloc", feature = "std"))]
+impl OwnedSegments {
+    /// Charges the memory held by these segments against `quota` until they are dropped,
+    /// replacing any previous charge. Returns an `Overloaded` error if the quota is exhausted.
+    pub fn charge_to(&mut self, quota: &message::MemoryQuota) -> Result<()> {
+        self.reservation = None;
+        self.reservation = Some(quota.try_reserve(self.len())?);
+        Ok(())
+    }
+
+    /// Holds `reservation` until these segments are dropped, replacing any previous charge.
+    /// This is for readers that reserve memory before they know the exact size of the segments,
+    /// e.g. from a frame header.
+    pub fn set_reservation(&mut self, reservation: message::QuotaReservation) {
+        self.reservation = Some(reservation);
+    }
 }
 
 #[cfg(feature = "alloc")]
@@ -250,9 +272,24 @@ impl SegmentLengthsBuilder {
         OwnedSegments {
             segment_indices: self.segment_indices,
             owned_space,
+            #[cfg(feature = "std")]
+            reservation: None,
         }
     }
 
+    /// Like `into_owned_segments()`, but first reserves the memory from `quota`, returning an
+    /// `Overloaded` error instead of allocating if the quota is exhausted.
+    #[cfg(feature = "std")]
+    pub fn into_owned_segments_with_quota(
+        self,
+        quota: &message::MemoryQuota,
+    ) -> Result<OwnedSegments> {
+        let reservation = quota.try_reserve(self.total_words * BYTES_PER_WORD)?;
+        let mut segments = self.into_owned_segments();
+        segments.reservation = Some(reservation);
+        Ok(segments)
+    }
+
     /// Constructs a `SliceSegments`.
     /// `slice` contains the full message (including the segment header).
     pub fn into_slice_segments(
@@ -324,6 +361,123 @@ where
     )?))
 }
 
+/// Push-style decoder for a stream of messages, for use with event loops that hand out
+/// input in arbitrary chunks rather than providing a blocking `Read`.
+///
+/// Bytes fed to the decoder are copied directly into the final segment buffer of the message
+/// they belong to; only the segment table is buffered separately. The same
+/// `ReaderOptions` limits as in `read_message()` are enforced, as soon as the segment table
+/// has been received.
+///
+/// After an error, the position in the stream is lost and the decoder should be discarded.
+///
+/// ```
+/// let mut message = capnp::message::Builder::new_default();
+/// message.set_root::<capnp::text::Owned>("hello").unwrap();
+/// let bytes = capnp::serialize::write_message_to_words(&message);
+///
+/// let mut decoder = capnp::serialize::MessageDecoder::new(Default::default());
+/// let (first, second) = bytes.split_at(5);
+/// assert!(decoder.push(first).unwrap().is_empty());
+/// let messages = decoder.push(second).unwrap();
+/// assert_eq!(messages.len(), 1);
+/// assert_eq!(messages[0].get_root::<capnp::text::Reader>().unwrap(), "hello");
+/// assert!(decoder.is_at_message_boundary());
+/// ```
+#[cfg(feature = "alloc")]
+pub struct MessageDecoder {
+    options: message::ReaderOptions,
+
+    // Bytes of the segment table of the current message received so far.
+    segment_table: alloc::vec::Vec<u8>,
+
+    // The segments of the current message and how many bytes of them have been received,
+    // once the segment table is complete.
+    segments: Option<(OwnedSegments, usize)>,
+}
+
+#[cfg(feature = "alloc")]
+impl MessageDecoder {
+    pub fn new(options: message::ReaderOptions) -> Self {
+        Self {
+            options,
+            segment_table: alloc::vec::Vec::new(),
+            segments: None,
+        }
+    }
+
+    /// Consumes bytes from the front of `input` until either a message is complete or `input`
+    /// is exhausted. Returns the message in the former case and `None` in the latter.
+    pub fn next_message(
+        &mut self,
+        input: &mut &[u8],
+    ) -> Result<Option<message::Reader<OwnedSegments>>> {
+        loop {
+            if let Some((segments, received)) = &mut self.segments {
+                let n = core::cmp::min(segments[..].len() - *received, input.len());
+                segments[*received..*received + n].copy_from_slice(&input[..n]);
+                *received += n;
+                *input = &input[n..];
+                if *received < segments[..].len() {
+                    return Ok(None);
+                }
+                let (segments, _) = self.segments.take().unwrap();
+                return Ok(Some(message::Reader::new(segments, self.options)));
+            }
+
+            // The first four bytes determine the length of the segment table.
+            let needed = if self.segment_table.len() < 4 {
+                4
+            } else {
+                let segment_count = u32::from_le_bytes(self.segment_table[0..4].try_into().unwrap())
+                    .wrapping_add(1) as usize;
+                if segment_count >= SEGMENTS_COUNT_LIMIT || segment_count == 0 {
+                    return Err(Error::from_kind(ErrorKind::InvalidNumberOfSegments(
+                        segment_count,
+                    )));
+                }
+                (segment_count / 2 + 1) * BYTES_PER_WORD
+            };
+            if self.segment_table.len() < needed {
+                if input.is_empty() {
+                    return Ok(None);
+                }
+                let n = core::cmp::min(needed - self.segment_table.len(), input.len());
+                self.segment_table.extend_from_slice(&input[..n]);
+                *input = &input[n..];
+                continue;
+            }
+
+            let Some(segment_lengths_builder) =
+                read_segment_table(&mut &self.segment_table[..], self.options)?
+            else {
+                unreachable!("segment table is not empty")
+            };
+            self.segment_table.clear();
+            self.segments = Some((segment_lengths_builder.into_owned_segments(), 0));
+        }
+    }
+
+    /// Feeds `input` to the decoder and returns all of the messages that it completes.
+    /// Any trailing partial message is retained until more input arrives.
+    pub fn push(
+        &mut self,
+        mut input: &[u8],
+    ) -> Result<alloc::vec::Vec<message::Reader<OwnedSegments>>> {
+        let mut result = alloc::vec::Vec::new();
+        while let Some(message) = self.next_message(&mut input)? {
+            result.push(message);
+        }
+        Ok(result)
+    }
+
+    /// Returns true if no part of a message is currently buffered, i.e. if the stream could
+    /// cleanly end here.
+    pub fn is_at_message_boundary(&self) -> bool {
+        self.segment_table.is_empty() && self.segments.is_none()
+    }
+}
+
 /// Like `try_read_message()`, but does not allocate any memory.
 ///
 /// Stores the message in `buffer`. Returns a `BufferNotLargeEnough`
@@ -663,6 +817,126 @@ where
     Ok(())
 }
 
+/// The segment table that precedes a message's segments in the standard stream framing.
+///
+/// Together with the segments themselves, this is everything that `write_message()` writes,
+/// which makes it useful for transports that want to hand the pieces of a message to the
+/// operating system without first copying them into one buffer. See
+/// [`OutputSegments::io_slices()`](crate::OutputSegments::io_slices).
+pub struct SegmentTable {
+    // Holds the table when there are at most three segments.
+    inline: [u8; 16],
+    inline_len: usize,
+
+    #[cfg(feature = "alloc")]
+    heap: alloc::vec::Vec<u8>,
+}
+
+impl SegmentTable {
+    /// Computes the segment table for `segments`, which must contain at least one segment.
+    pub fn new<R>(segments: &R) -> Self
+    where
+        R: message::ReaderSegments + ?Sized,
+    {
+        let table_size = (segments.len() / 2 + 1) * BYTES_PER_WORD;
+        let mut result = Self {
+            inline: [0; 16],
+            inline_len: 0,
+            #[cfg(feature = "alloc")]
+            heap: alloc::vec::Vec::new(),
+        };
+        if table_size <= result.inline.len() {
+            let mut bytes = &mut result.inline[..table_size];
+            write_segment_table_internal(&mut bytes, segments)
+                .expect("Failed to write segment table.");
+            result.inline_len = table_size;
+        } else {
+            #[cfg(feature = "alloc")]
+            {
+                result.heap.resize(table_size, 0);
+                let mut bytes = &mut result.heap[..];
+                write_segment_table_internal(&mut bytes, segments)
+                    .expect("Failed to write segment table.");
+            }
+
+            #[cfg(not(feature = "alloc"))]
+            {
+                unreachable!("multi-segment message builders are not supported in no-alloc mode")
+            }
+        }
+        result
+    }
+
+    /// Returns the encoded table.
+    pub fn as_bytes(&self) -> &[u8] {
+        #[cfg(feature = "alloc")]
+        if self.inline_len == 0 {
+            return &self.heap;
+        }
+        &self.inline[..self.inline_len]
+    }
+}
+
+impl core::ops::Deref for SegmentTable {
+    type Target = [u8];
+    fn deref(&self) -> &[u8] {
+        self.as_bytes()
+    }
+}
+
+/// Like `write_message()`, but writes the segment table and all of the segments with
+/// gathered writes (`std::io::Write::write_vectored()`), so that a writer that supports
+/// vectored I/O, like a socket, can send the whole message in a single system call,
+/// without copying it.
+///
+/// The only source of errors from this function are `write.write_vectored()` calls.
+#[cfg(all(feature = "alloc", feature = "std"))]
+pub fn write_message_vectored<W, A>(write: W, message: &message::Builder<A>) -> Result<()>
+where
+    W: std::io::Write,
+    A: message::Allocator,
+{
+    let segments = message.get_segments_for_output();
+    let table = segments.segment_table();
+    let mut slices: alloc::vec::Vec<_> = segments.io_slices(&table).collect();
+    write_all_vectored(write, &mut slices)
+}
+
+/// Like `write_message_vectored()`, but takes a `ReaderSegments`, allowing it to be
+/// used on `message::Reader` objects (via `into_segments()`).
+#[cfg(all(feature = "alloc", feature = "std"))]
+pub fn write_message_segments_vectored<W, R>(write: W, segments: &R) -> Result<()>
+where
+    W: std::io::Write,
+    R: message::ReaderSegments,
+{
+    let table = SegmentTable::new(segments);
+    let mut slices = alloc::vec::Vec::with_capacity(segments.len() + 1);
+    slices.push(std::io::IoSlice::new(&table));
+    for i in 0..segments.len() {
+        slices.push(std::io::IoSlice::new(
+            segments.get_segment(i.try_into().unwrap()).unwrap(),
+        ));
+    }
+    write_all_vectored(write, &mut slices)
+}
+
+#[cfg(all(feature = "alloc", feature = "std"))]
+fn write_all_vectored<W>(mut write: W, mut slices: &mut [std::io::IoSlice<'_>]) -> Result<()>
+where
+    W: std::io::Write,
+{
+    while !slices.is_empty() {
+        match write.write_vectored(slices) {
+            Ok(0) => return Err(std::io::Error::from(std::io::ErrorKind::WriteZero).into()),
+            Ok(n) => std::io::IoSlice::advance_slices(&mut slices, n),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(())
+}
+
 /// Writes segments to `write`.
 fn write_segments<W, R: message::ReaderSegments + ?Sized>(write: &mut W, segments: &R) -> Result<()>
 where
@@ -1131,4 +1405,117 @@ pub mod test {
             1 + 1 + LIST_LENGTH_IN_WORDS
         )
     }
+
+    #[cfg(feature = "std")]
+    #[test]
+    fn write_message_vectored() {
+        // Accepts at most `max_write` bytes per call, to exercise partial writes.
+        struct ShortWriter {
+            buf: alloc::vec::Vec<u8>,
+            max_write: usize,
+        }
+
+        impl std::io::Write for ShortWriter {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                let n = buf.len().min(self.max_write);
+                self.buf.extend_from_slice(&buf[..n]);
+                Ok(n)
+            }
+
+            fn write_vectored(&mut self, bufs: &[std::io::IoSlice<'_>]) -> std::io::Result<usize> {
+                let mut written = 0;
+                for buf in bufs {
+                    let n = buf.len().min(self.max_write - written);
+                    self.buf.extend_from_slice(&buf[..n]);
+                    written += n;
+                }
+                Ok(written)
+            }
+
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        for segment_count in [1, 2, 3, 4, 5, 8] {
+            let mut m = message::Builder::new(
+                message::HeapAllocator::new()
+                    .first_segment_words(2)
+                    .allocation_strategy(message::AllocationStrategy::FixedSize),
+            );
+            while m.get_segments_for_output().len() < segment_count {
+                let root: crate::any_pointer::Builder = m.init_root();
+                let mut list: crate::text_list::Builder = root.initn_as(1);
+                list.set(0, "some text");
+            }
+
+            let expected = super::write_message_to_words(&m);
+            for max_write in [1, 7, 100, usize::MAX] {
+                let mut writer = ShortWriter {
+                    buf: vec![],
+                    max_write,
+                };
+                super::write_message_vectored(&mut writer, &m).unwrap();
+                assert_eq!(writer.buf, expected);
+
+                let mut writer = ShortWriter {
+                    buf: vec![],
+                    max_write,
+                };
+                super::write_message_segments_vectored(&mut writer, &m).unwrap();
+                assert_eq!(writer.buf, expected);
+            }
+        }
+    }
+
+    #[test]
+    fn message_decoder() {
+        let segments: [&[&[u8]]; 3] = [
+            &[&[]],
+            &[&[1, 0, 0, 0, 0, 0, 0, 0]],
+            &[
+                &[2, 0, 0, 0, 0, 0, 0, 0],
+                &[],
+                &[3, 0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 0, 0, 0, 0],
+                &[5, 0, 0, 0, 0, 0, 0, 0],
+                &[6, 0, 0, 0, 0, 0, 0, 0],
+            ],
+        ];
+        let mut stream = vec![];
+        for message_segments in segments {
+            stream.extend(flatten_segments(message_segments));
+        }
+
+        for chunk_size in 1..=stream.len() {
+            let mut decoder = super::MessageDecoder::new(message::ReaderOptions::new());
+            let mut messages = vec![];
+            for chunk in stream.chunks(chunk_size) {
+                messages.extend(decoder.push(chunk).unwrap());
+            }
+            assert!(decoder.is_at_message_boundary());
+            assert_eq!(messages.len(), segments.len());
+            for (message, expected) in messages.into_iter().zip(segments) {
+                let message_segments = message.into_segments();
+                assert_eq!(message_segments.len(), expected.len());
+                for (i, segment) in expected.iter().enumerate() {
+                    assert_eq!(message_segments.get_segment(i as u32).unwrap(), *segment);
+                }
+            }
+        }
+
+        let mut decoder = super::MessageDecoder::new(message::ReaderOptions::new());
+        assert_eq!(decoder.push(&stream[..12]).unwrap().len(), 1);
+        assert!(!decoder.is_at_message_boundary());
+    }
+
+    #[test]
+    fn message_decoder_limits() {
+        let mut decoder = super::MessageDecoder::new(message::ReaderOptions::new());
+        assert!(decoder.push(&[0xff, 0xff, 0xff, 0xff]).is_err());
+
+        let mut decoder = super::MessageDecoder::new(
+            *message::ReaderOptions::new().traversal_limit_in_words(Some(1)),
+        );
+        assert!(decoder.push(&[0, 0, 0, 0, 2, 0, 0, 0]).is_err());
+    }
 }
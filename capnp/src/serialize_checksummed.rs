@@ -0,0 +1,502 @@
+//! Reading and writing of messages using a checksummed framing, suitable for logs and other
+//! on-disk files that may be left with a partially written or corrupted tail.
+//!
+//! Each message is serialized with the
+//! [standard stream framing](https://capnproto.org/encoding.html#serialization-over-a-stream)
+//! and wrapped in a frame:
+//!
+//! | bytes            | contents                                                     |
+//! |------------------|--------------------------------------------------------------|
+//! | 0..4             | the magic bytes [`FRAME_MAGIC`]                              |
+//! | 4..8             | length `n` of the payload in bytes (u32, LE)                 |
+//! | 8..8+n           | the payload: segment table followed by the segments          |
+//! | 8+n..12+n        | CRC-32C of bytes 4..8+n (u32, LE)                            |
+//!
+//! The checksum covers the length field as well as the segment table and segments, so a reader
+//! can tell a damaged frame apart from a valid one, and reports the problem with
+//! [`ErrorKind::ChecksumMismatch`], [`ErrorKind::TruncatedFrame`] or
+//! [`ErrorKind::TruncatedFrameHeader`] rather than handing back
+//! garbage structure. [`MessageScanner`] additionally recovers from damaged frames by
+//! resynchronizing on the next occurrence of the magic bytes.
+
+use alloc::vec::Vec;
+
+use crate::io::{Read, Write};
+use crate::message;
+use crate::private::units::BYTES_PER_WORD;
+use crate::serialize::{self, OwnedSegments, SegmentTable, SEGMENTS_COUNT_LIMIT};
+use crate::{Error, ErrorKind, Result};
+
+/// The bytes that start every frame.
+pub const FRAME_MAGIC: [u8; 4] = [0xc3, 0x9e, 0x43, 0x5c];
+
+/// The number of bytes in a frame header (the magic bytes and the payload length).
+pub const FRAME_HEADER_BYTES: usize = 8;
+
+/// The number of bytes in a frame trailer (the checksum).
+pub const FRAME_TRAILER_BYTES: usize = 4;
+
+const CRC32C_TABLE: [u32; 256] = crc32c_table();
+
+const fn crc32c_table() -> [u32; 256] {
+    let mut table = [0; 256];
+    let mut i: u32 = 0;
+    while i < 256 {
+        let mut crc = i;
+        let mut j = 0;
+        while j < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0x82f6_3b78
+            } else {
+                crc >> 1
+            };
+            j += 1;
+        }
+        table[i as usize] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// Incremental CRC-32C (Castagnoli) computation.
+#[derive(Clone, Copy)]
+struct Crc32c(u32);
+
+impl Crc32c {
+    fn new() -> Self {
+        Self(!0)
+    }
+
+    fn update(&mut self, bytes: &[u8]) {
+        let mut crc = self.0;
+        for &b in bytes {
+            crc = CRC32C_TABLE[((crc ^ b as u32) & 0xff) as usize] ^ (crc >> 8);
+        }
+        self.0 = crc;
+    }
+
+    fn finish(self) -> u32 {
+        !self.0
+    }
+}
+
+/// Validates a frame header and returns the length of the payload in bytes.
+fn parse_header(
+    header: &[u8; FRAME_HEADER_BYTES],
+    options: message::ReaderOptions,
+) -> Result<usize> {
+    if header[0..4] != FRAME_MAGIC {
+        return Err(Error::from_kind(ErrorKind::InvalidFrameHeader));
+    }
+    let payload_len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    if payload_len < BYTES_PER_WORD || payload_len % BYTES_PER_WORD != 0 {
+        return Err(Error::from_kind(ErrorKind::InvalidFrameHeader));
+    }
+
+    // The payload includes the segment table, which does not count against the traversal limit.
+    if let Some(limit) = options.traversal_limit_in_words {
+        let max_table_words = SEGMENTS_COUNT_LIMIT / 2 + 1;
+        if payload_len / BYTES_PER_WORD > limit.saturating_add(max_table_words) {
+            return Err(Error::from_kind(ErrorKind::MessageTooLarge(
+                payload_len / BYTES_PER_WORD,
+            )));
+        }
+    }
+    Ok(payload_len)
+}
+
+/// Verifies the checksum of a frame and decodes its payload.
+fn decode_frame_body(
+    header: &[u8; FRAME_HEADER_BYTES],
+    payload: &[u8],
+    trailer: &[u8],
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>> {
+    let expected = u32::from_le_bytes(trailer.try_into().unwrap());
+    let mut crc = Crc32c::new();
+    crc.update(&header[4..8]);
+    crc.update(payload);
+    let actual = crc.finish();
+    if expected != actual {
+        return Err(Error::from_kind(ErrorKind::ChecksumMismatch(
+            expected, actual,
+        )));
+    }
+
+    let mut payload = payload;
+    let message = serialize::read_message(&mut payload, options)?;
+    if !payload.is_empty() {
+        return Err(Error::failed(alloc::format!(
+            "checksummed frame contains {} trailing bytes after the message",
+            payload.len()
+        )));
+    }
+    Ok(message)
+}
+
+/// Decodes the frame at the start of `bytes`, returning the message and the length of the frame.
+fn decode_frame(
+    bytes: &[u8],
+    options: message::ReaderOptions,
+) -> Result<(message::Reader<OwnedSegments>, usize)> {
+    let Some(header) = bytes.get(..FRAME_HEADER_BYTES) else {
+        return Err(Error::from_kind(ErrorKind::TruncatedFrameHeader(
+            FRAME_HEADER_BYTES,
+            bytes.len(),
+        )));
+    };
+    let header: &[u8; FRAME_HEADER_BYTES] = header.try_into().unwrap();
+    let payload_len = parse_header(header, options)?;
+    let frame_len = FRAME_HEADER_BYTES + payload_len + FRAME_TRAILER_BYTES;
+    if bytes.len() < frame_len {
+        return Err(Error::from_kind(ErrorKind::TruncatedFrame(
+            frame_len,
+            bytes.len(),
+        )));
+    }
+    let payload_end = FRAME_HEADER_BYTES + payload_len;
+    let message = decode_frame_body(
+        header,
+        &bytes[FRAME_HEADER_BYTES..payload_end],
+        &bytes[payload_end..frame_len],
+        options,
+    )?;
+    Ok((message, frame_len))
+}
+
+/// Reads from `read` until `buf` is full or the end of the stream is reached, returning the
+/// number of bytes read.
+fn read_up_to<R: Read>(read: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match read.read(&mut buf[filled..])? {
+            0 => break,
+            n => filled += n,
+        }
+    }
+    Ok(filled)
+}
+
+/// Reads a checksummed message from a stream with the provided options.
+///
+/// Returns [`ErrorKind::TruncatedFrameHeader`] or [`ErrorKind::TruncatedFrame`] if the stream
+/// ends partway through a frame, and
+/// [`ErrorKind::ChecksumMismatch`] if the frame was damaged.
+pub fn read_message<R>(
+    read: R,
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>>
+where
+    R: Read,
+{
+    match try_read_message(read, options)? {
+        Some(message) => Ok(message),
+        None => Err(Error::from_kind(ErrorKind::PrematureEndOfFile)),
+    }
+}
+
+/// Like `read_message()`, but returns None instead of an error if there are zero bytes left in
+/// `read`.
+pub fn try_read_message<R>(
+    mut read: R,
+    options: message::ReaderOptions,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: Read,
+{
+    let mut header = [0; FRAME_HEADER_BYTES];
+    match read_up_to(&mut read, &mut header)? {
+        0 => return Ok(None),
+        n if n < FRAME_HEADER_BYTES => {
+            return Err(Error::from_kind(ErrorKind::TruncatedFrameHeader(
+                FRAME_HEADER_BYTES,
+                n,
+            )))
+        }
+        _ => (),
+    }
+    let payload_len = parse_header(&header, options)?;
+
+    let mut rest = alloc::vec![0; payload_len + FRAME_TRAILER_BYTES];
+    let n = read_up_to(&mut read, &mut rest)?;
+    if n < rest.len() {
+        return Err(Error::from_kind(ErrorKind::TruncatedFrame(
+            FRAME_HEADER_BYTES + rest.len(),
+            FRAME_HEADER_BYTES + n,
+        )));
+    }
+    let (payload, trailer) = rest.split_at(payload_len);
+    Ok(Some(decode_frame_body(&header, payload, trailer, options)?))
+}
+
+/// Iterates over the checksummed messages in a byte slice, such as the contents of a log file,
+/// skipping over damaged frames.
+///
+/// Each damaged region produces a single `Err` item, after which the scanner resynchronizes on
+/// the next occurrence of [`FRAME_MAGIC`] and carries on. A partially written final frame shows
+/// up as an [`ErrorKind::TruncatedFrame`] or [`ErrorKind::TruncatedFrameHeader`] error as the
+/// last item.
+///
+/// ```
+/// use capnp::{message, serialize_checksummed};
+///
+/// let mut message = message::Builder::new_default();
+/// message.set_root::<capnp::text::Owned>("hello").unwrap();
+///
+/// let mut log = Vec::new();
+/// serialize_checksummed::write_message(&mut log, &message).unwrap();
+/// serialize_checksummed::write_message(&mut log, &message).unwrap();
+/// log.truncate(log.len() - 3); // the second write was interrupted
+///
+/// let mut scanner =
+///     serialize_checksummed::MessageScanner::new(&log, message::ReaderOptions::new());
+/// let first = scanner.next().unwrap().unwrap();
+/// assert_eq!(first.get_root::<capnp::text::Reader>().unwrap(), "hello");
+/// assert!(matches!(
+///     scanner.next(),
+///     Some(Err(capnp::Error { kind: capnp::ErrorKind::TruncatedFrame(_, _), .. }))
+/// ));
+/// assert!(scanner.next().is_none());
+/// ```
+pub struct MessageScanner<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    options: message::ReaderOptions,
+    resync: bool,
+}
+
+impl<'a> MessageScanner<'a> {
+    pub fn new(bytes: &'a [u8], options: message::ReaderOptions) -> Self {
+        Self {
+            bytes,
+            offset: 0,
+            options,
+            resync: false,
+        }
+    }
+
+    /// Returns the byte offset in the input of the frame that will be decoded next, or of the
+    /// frame that produced the most recent error.
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    /// Finds the first occurrence of `FRAME_MAGIC` at or after `from`.
+    fn find_magic(&self, from: usize) -> usize {
+        match self.bytes.get(from..) {
+            Some(rest) => rest
+                .windows(FRAME_MAGIC.len())
+                .position(|w| w == FRAME_MAGIC)
+                .map_or(self.bytes.len(), |p| from + p),
+            None => self.bytes.len(),
+        }
+    }
+}
+
+impl Iterator for MessageScanner<'_> {
+    type Item = Result<message::Reader<OwnedSegments>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.resync {
+            self.resync = false;
+            self.offset = self.find_magic(self.offset + 1);
+        }
+        if self.offset >= self.bytes.len() {
+            return None;
+        }
+        match decode_frame(&self.bytes[self.offset..], self.options) {
+            Ok((message, frame_len)) => {
+                self.offset += frame_len;
+                Some(Ok(message))
+            }
+            Err(e) => {
+                self.resync = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Writes the provided message to `write` in a checksummed frame.
+///
+/// For optimal performance, `write` should be a buffered writer. `flush()` will not be called on
+/// the writer.
+pub fn write_message<W, A>(write: W, message: &message::Builder<A>) -> Result<()>
+where
+    W: Write,
+    A: message::Allocator,
+{
+    write_message_segments(write, &message.get_segments_for_output())
+}
+
+/// Like `write_message()`, but takes a `ReaderSegments`, allowing it to be
+/// used on `message::Reader` objects (via `into_segments()`).
+pub fn write_message_segments<W, R>(mut write: W, segments: &R) -> Result<()>
+where
+    W: Write,
+    R: message::ReaderSegments,
+{
+    let table = SegmentTable::new(segments);
+    let segments: Vec<&[u8]> = (0..).map_while(|id| segments.get_segment(id)).collect();
+    let payload_len = table.len() + segments.iter().map(|s| s.len()).sum::<usize>();
+    let payload_len = u32::try_from(payload_len)
+        .map_err(|_| Error::from_kind(ErrorKind::MessageSizeOverflow))?
+        .to_le_bytes();
+
+    let mut crc = Crc32c::new();
+    crc.update(&payload_len);
+    crc.update(&table);
+    for segment in &segments {
+        crc.update(segment);
+    }
+
+    write.write_all(&FRAME_MAGIC)?;
+    write.write_all(&payload_len)?;
+    write.write_all(&table)?;
+    for segment in &segments {
+        write.write_all(segment)?;
+    }
+    write.write_all(&crc.finish().to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{
+        read_message, try_read_message, write_message, Crc32c, MessageScanner, FRAME_HEADER_BYTES,
+    };
+    use crate::{message, ErrorKind};
+
+    fn build_message(n: u32) -> message::Builder<message::HeapAllocator> {
+        let mut message = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(2)
+                .allocation_strategy(message::AllocationStrategy::FixedSize),
+        );
+        let root: crate::any_pointer::Builder = message.init_root();
+        let mut list: crate::primitive_list::Builder<u32> = root.initn_as(n);
+        for i in 0..n {
+            list.set(i, i * 7);
+        }
+        message
+    }
+
+    fn error_kind<T>(result: crate::Result<T>) -> ErrorKind {
+        match result {
+            Ok(_) => panic!("expected an error"),
+            Err(e) => e.kind,
+        }
+    }
+
+    fn list_len(message: &message::Reader<crate::serialize::OwnedSegments>) -> u32 {
+        message
+            .get_root::<crate::primitive_list::Reader<u32>>()
+            .unwrap()
+            .len()
+    }
+
+    #[test]
+    fn crc32c_check_value() {
+        let mut crc = Crc32c::new();
+        crc.update(b"123456789");
+        assert_eq!(crc.finish(), 0xe306_9283);
+    }
+
+    #[test]
+    fn round_trip() {
+        let mut buf = Vec::new();
+        for n in [1, 5, 40] {
+            write_message(&mut buf, &build_message(n)).unwrap();
+        }
+
+        let mut read = &buf[..];
+        for n in [1, 5, 40] {
+            let message = read_message(&mut read, message::ReaderOptions::new()).unwrap();
+            assert_eq!(list_len(&message), n);
+        }
+        assert!(try_read_message(&mut read, message::ReaderOptions::new())
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn detects_damage() {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &build_message(5)).unwrap();
+
+        let err = error_kind(read_message(&mut &buf[..3], message::ReaderOptions::new()));
+        assert_eq!(err, ErrorKind::TruncatedFrameHeader(FRAME_HEADER_BYTES, 3));
+        let mut scanner = MessageScanner::new(&buf[..3], message::ReaderOptions::new());
+        assert_eq!(
+            error_kind(scanner.next().unwrap()),
+            ErrorKind::TruncatedFrameHeader(FRAME_HEADER_BYTES, 3)
+        );
+
+        for len in [12, buf.len() - 1] {
+            let err = error_kind(read_message(
+                &mut &buf[..len],
+                message::ReaderOptions::new(),
+            ));
+            assert!(
+                matches!(err, ErrorKind::TruncatedFrame(_, actual) if actual == len),
+                "{err}"
+            );
+        }
+
+        let mut corrupted = buf.clone();
+        corrupted[20] ^= 0x10;
+        let err = error_kind(read_message(
+            &mut &corrupted[..],
+            message::ReaderOptions::new(),
+        ));
+        assert!(matches!(err, ErrorKind::ChecksumMismatch(_, _)));
+
+        let mut corrupted = buf.clone();
+        corrupted[0] ^= 0x01;
+        let err = error_kind(read_message(
+            &mut &corrupted[..],
+            message::ReaderOptions::new(),
+        ));
+        assert_eq!(err, ErrorKind::InvalidFrameHeader);
+
+        let options = *message::ReaderOptions::new().traversal_limit_in_words(Some(0));
+        let mut big = Vec::new();
+        write_message(&mut big, &build_message(10_000)).unwrap();
+        let err = error_kind(read_message(&mut &big[..], options));
+        assert!(matches!(err, ErrorKind::MessageTooLarge(_)));
+    }
+
+    #[test]
+    fn scanner_resynchronizes() {
+        let mut buf = alloc::vec![0xff; 5]; // garbage before the first frame
+        let mut starts = Vec::new();
+        for n in [1, 2, 3, 4] {
+            starts.push(buf.len());
+            write_message(&mut buf, &build_message(n)).unwrap();
+        }
+        // Damage the second frame and cut the last one short.
+        buf[starts[1] + 12] ^= 0x80;
+        buf.truncate(buf.len() - 2);
+
+        let mut scanner = MessageScanner::new(&buf, message::ReaderOptions::new());
+        assert_eq!(
+            error_kind(scanner.next().unwrap()),
+            ErrorKind::InvalidFrameHeader
+        );
+        assert_eq!(scanner.offset(), 0);
+        assert_eq!(list_len(&scanner.next().unwrap().unwrap()), 1);
+        assert!(matches!(
+            error_kind(scanner.next().unwrap()),
+            ErrorKind::ChecksumMismatch(_, _)
+        ));
+        assert_eq!(scanner.offset(), starts[1]);
+        assert_eq!(list_len(&scanner.next().unwrap().unwrap()), 3);
+        assert!(matches!(
+            error_kind(scanner.next().unwrap()),
+            ErrorKind::TruncatedFrame(_, _)
+        ));
+        assert_eq!(scanner.offset(), starts[3]);
+        assert!(scanner.next().is_none());
+    }
+}
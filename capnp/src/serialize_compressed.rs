@@ -0,0 +1,343 @@
+//! Reading and writing of messages using a compressed framing.
+//!
+//! Each message is serialized with the
+//! [standard stream framing](https://capnproto.org/encoding.html#serialization-over-a-stream),
+//! compressed as a whole, and preceded by a 12-byte frame header:
+//!
+//! | bytes  | contents                                              |
+//! |--------|-------------------------------------------------------|
+//! | 0      | compression algorithm (1 = LZ4 block, 2 = zstd)       |
+//! | 1..4   | reserved, must be zero                                |
+//! | 4..8   | length of the compressed payload in bytes (u32, LE)   |
+//! | 8..12  | length of the uncompressed message in bytes (u32, LE) |
+//!
+//! Unlike packing (see [`crate::serialize_packed`]), which only removes zero bytes, this is
+//! intended for archival storage and for links where bandwidth is scarce.
+//!
+//! The algorithms are enabled by the `lz4` and `zstd` features of the `capnp` crate.
+
+use alloc::string::ToString;
+use alloc::vec::Vec;
+
+use crate::io::{Read, Write};
+use crate::message;
+use crate::private::units::BYTES_PER_WORD;
+use crate::serialize::{self, OwnedSegments};
+use crate::{Error, ErrorKind, Result};
+
+/// The number of bytes in a frame header.
+pub const FRAME_HEADER_BYTES: usize = 12;
+
+/// A compression algorithm.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Compression {
+    /// LZ4 block compression. Fast, with a moderate compression ratio.
+    #[cfg(feature = "lz4")]
+    Lz4,
+
+    /// Zstandard compression at the given level. Level 0 selects zstd's default.
+    #[cfg(feature = "zstd")]
+    Zstd { level: i32 },
+}
+
+impl Compression {
+    fn id(self) -> u8 {
+        match self {
+            #[cfg(feature = "lz4")]
+            Self::Lz4 => 1,
+            #[cfg(feature = "zstd")]
+            Self::Zstd { .. } => 2,
+        }
+    }
+
+    fn from_id(id: u8) -> Result<Self> {
+        match id {
+            #[cfg(feature = "lz4")]
+            1 => Ok(Self::Lz4),
+            #[cfg(feature = "zstd")]
+            2 => Ok(Self::Zstd { level: 0 }),
+            _ => Err(Error::failed(alloc::format!(
+                "unsupported compression algorithm in frame header: {id}"
+            ))),
+        }
+    }
+}
+
+/// The decoded header of a compressed frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FrameHeader {
+    pub compression: Compression,
+
+    /// The number of bytes of compressed payload following the header.
+    pub compressed_len: usize,
+
+    /// The number of bytes in the message once decompressed.
+    pub uncompressed_len: usize,
+}
+
+impl FrameHeader {
+    /// Decodes and validates a frame header.
+    ///
+    /// Returns an error if the frame would decompress to a message larger than
+    /// `options.traversal_limit_in_words`, so that a malicious peer cannot make the reader
+    /// allocate excessive space.
+    pub fn parse(
+        bytes: &[u8; FRAME_HEADER_BYTES],
+        options: message::ReaderOptions,
+    ) -> Result<Self> {
+        let compression = Compression::from_id(bytes[0])?;
+        if bytes[1..4] != [0, 0, 0] {
+            return Err(Error::failed(
+                "reserved bytes of compressed frame header are not zero".to_string(),
+            ));
+        }
+        let compressed_len = u32::from_le_bytes(bytes[4..8].try_into().unwrap()) as usize;
+        let uncompressed_len = u32::from_le_bytes(bytes[8..12].try_into().unwrap()) as usize;
+
+        if uncompressed_len < BYTES_PER_WORD || uncompressed_len % BYTES_PER_WORD != 0 {
+            return Err(Error::failed(alloc::format!(
+                "invalid uncompressed message length in frame header: {uncompressed_len}"
+            )));
+        }
+        if let Some(limit) = options.traversal_limit_in_words {
+            if uncompressed_len / BYTES_PER_WORD > limit {
+                return Err(Error::from_kind(ErrorKind::MessageTooLarge(
+                    uncompressed_len / BYTES_PER_WORD,
+                )));
+            }
+        }
+        // Neither algorithm expands its input by more than a small fraction.
+        if compressed_len > uncompressed_len + uncompressed_len / 8 + 64 {
+            return Err(Error::failed(alloc::format!(
+                "invalid compressed payload length in frame header: {compressed_len}"
+            )));
+        }
+
+        Ok(Self {
+            compression,
+            compressed_len,
+            uncompressed_len,
+        })
+    }
+}
+
+/// Constructs a complete frame, including the header, containing the compressed message.
+pub fn encode_message<R>(segments: &R, compression: Compression) -> Result<Vec<u8>>
+where
+    R: message::ReaderSegments,
+{
+    let flat = serialize::write_message_segments_to_words(segments);
+    let uncompressed_len =
+        u32::try_from(flat.len()).map_err(|_| Error::from_kind(ErrorKind::MessageSizeOverflow))?;
+
+    let mut frame = Vec::new();
+    frame.extend_from_slice(&[compression.id(), 0, 0, 0, 0, 0, 0, 0]);
+    frame.extend_from_slice(&uncompressed_len.to_le_bytes());
+    match compression {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => {
+            frame.resize(
+                FRAME_HEADER_BYTES + lz4_flex::block::get_maximum_output_size(flat.len()),
+                0,
+            );
+            let n = lz4_flex::block::compress_into(&flat, &mut frame[FRAME_HEADER_BYTES..])
+                .map_err(|e| Error::failed(alloc::format!("LZ4 compression failed: {e}")))?;
+            frame.truncate(FRAME_HEADER_BYTES + n);
+        }
+        #[cfg(feature = "zstd")]
+        Compression::Zstd { level } => {
+            let compressed = zstd::bulk::compress(&flat, level)
+                .map_err(|e| Error::failed(alloc::format!("zstd compression failed: {e}")))?;
+            frame.extend_from_slice(&compressed);
+        }
+    }
+    let compressed_len = u32::try_from(frame.len() - FRAME_HEADER_BYTES)
+        .map_err(|_| Error::from_kind(ErrorKind::MessageSizeOverflow))?;
+    frame[4..8].copy_from_slice(&compressed_len.to_le_bytes());
+    Ok(frame)
+}
+
+/// Decompresses the payload of a frame whose header has already been parsed.
+pub fn decode_message(
+    header: &FrameHeader,
+    payload: &[u8],
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>> {
+    if payload.len() != header.compressed_len {
+        return Err(Error::from_kind(ErrorKind::MessageEndsPrematurely(
+            header.compressed_len,
+            payload.len(),
+        )));
+    }
+    let mut flat = alloc::vec![0u8; header.uncompressed_len];
+    let n = match header.compression {
+        #[cfg(feature = "lz4")]
+        Compression::Lz4 => lz4_flex::block::decompress_into(payload, &mut flat)
+            .map_err(|e| Error::failed(alloc::format!("LZ4 decompression failed: {e}")))?,
+        #[cfg(feature = "zstd")]
+        Compression::Zstd { .. } => zstd::bulk::decompress_to_buffer(payload, &mut flat)
+            .map_err(|e| Error::failed(alloc::format!("zstd decompression failed: {e}")))?,
+    };
+    if n != header.uncompressed_len {
+        return Err(Error::failed(alloc::format!(
+            "compressed frame decompressed to {n} bytes, but its header says {}",
+            header.uncompressed_len
+        )));
+    }
+
+    let mut flat = &flat[..];
+    let message = serialize::read_message(&mut flat, options)?;
+    if !flat.is_empty() {
+        return Err(Error::failed(
+            "compressed frame contains trailing bytes after the message".to_string(),
+        ));
+    }
+    Ok(message)
+}
+
+/// Reads a compressed message from a stream with the provided options.
+pub fn read_message<R>(
+    read: R,
+    options: message::ReaderOptions,
+) -> Result<message::Reader<OwnedSegments>>
+where
+    R: Read,
+{
+    match try_read_message(read, options)? {
+        Some(message) => Ok(message),
+        None => Err(Error::from_kind(ErrorKind::PrematureEndOfFile)),
+    }
+}
+
+/// Like `read_message()`, but returns None instead of an error if there are zero bytes left in
+/// `read`.
+pub fn try_read_message<R>(
+    mut read: R,
+    options: message::ReaderOptions,
+) -> Result<Option<message::Reader<OwnedSegments>>>
+where
+    R: Read,
+{
+    let mut header = [0; FRAME_HEADER_BYTES];
+    {
+        let n = read.read(&mut header[..])?;
+        if n == 0 {
+            // Clean EOF on message boundary
+            return Ok(None);
+        } else if n < FRAME_HEADER_BYTES {
+            read.read_exact(&mut header[n..])?;
+        }
+    }
+    let header = FrameHeader::parse(&header, options)?;
+    let mut payload = alloc::vec![0u8; header.compressed_len];
+    read.read_exact(&mut payload)?;
+    Ok(Some(decode_message(&header, &payload, options)?))
+}
+
+/// Writes the provided message to `write`, compressed with `compression`.
+///
+/// For optimal performance, `write` should be a buffered writer. `flush()` will not be called on
+/// the writer.
+pub fn write_message<W, A>(
+    write: W,
+    message: &message::Builder<A>,
+    compression: Compression,
+) -> Result<()>
+where
+    W: Write,
+    A: message::Allocator,
+{
+    write_message_segments(write, &message.get_segments_for_output(), compression)
+}
+
+/// Like `write_message()`, but takes a `ReaderSegments`, allowing it to be
+/// used on `message::Reader` objects (via `into_segments()`).
+pub fn write_message_segments<W, R>(
+    mut write: W,
+    segments: &R,
+    compression: Compression,
+) -> Result<()>
+where
+    W: Write,
+    R: message::ReaderSegments,
+{
+    write.write_all(&encode_message(segments, compression)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use alloc::vec::Vec;
+
+    use super::{read_message, try_read_message, write_message, Compression, FrameHeader};
+    use crate::message;
+
+    fn compressions() -> Vec<Compression> {
+        alloc::vec![
+            #[cfg(feature = "lz4")]
+            Compression::Lz4,
+            #[cfg(feature = "zstd")]
+            Compression::Zstd { level: 3 },
+        ]
+    }
+
+    fn build_message() -> message::Builder<message::HeapAllocator> {
+        let mut message = message::Builder::new(
+            message::HeapAllocator::new()
+                .first_segment_words(4)
+                .allocation_strategy(message::AllocationStrategy::FixedSize),
+        );
+        let root: crate::any_pointer::Builder = message.init_root();
+        let mut list: crate::text_list::Builder = root.initn_as(20);
+        for i in 0..20 {
+            list.set(i, "the same text, over and over");
+        }
+        message
+    }
+
+    #[test]
+    fn round_trip() {
+        let message = build_message();
+        let expected = crate::serialize::write_message_to_words(&message);
+        for compression in compressions() {
+            let mut buf = Vec::new();
+            write_message(&mut buf, &message, compression).unwrap();
+            write_message(&mut buf, &message, compression).unwrap();
+            assert!(buf.len() < 2 * expected.len());
+
+            let mut read = &buf[..];
+            for _ in 0..2 {
+                let reader = read_message(&mut read, message::ReaderOptions::new()).unwrap();
+                assert_eq!(
+                    crate::serialize::write_message_segments_to_words(&reader.into_segments()),
+                    expected
+                );
+            }
+            assert!(try_read_message(&mut read, message::ReaderOptions::new())
+                .unwrap()
+                .is_none());
+        }
+    }
+
+    #[test]
+    fn limits() {
+        let message = build_message();
+        for compression in compressions() {
+            let mut buf = Vec::new();
+            write_message(&mut buf, &message, compression).unwrap();
+
+            let options = *message::ReaderOptions::new().traversal_limit_in_words(Some(8));
+            assert!(read_message(&mut &buf[..], options).is_err());
+
+            // Truncated payload.
+            assert!(
+                read_message(&mut &buf[..buf.len() - 1], message::ReaderOptions::new()).is_err()
+            );
+
+            // Corrupted header.
+            let mut header: [u8; super::FRAME_HEADER_BYTES] = buf[..12].try_into().unwrap();
+            header[0] = 0xff;
+            assert!(FrameHeader::parse(&header, message::ReaderOptions::new()).is_err());
+        }
+    }
+}
@@ -452,6 +452,41 @@ where
     serialize::write_message(packed_write, message)
 }
 
+/// Writes a packed message into `buffer` and returns the number of bytes written.
+///
+/// Does not allocate, so it can be used together with `message::SingleSegmentAllocator` in
+/// `no_std` environments without `alloc`. Returns a `BufferNotLargeEnough` error if `buffer`
+/// is too small to hold the packed message.
+pub fn write_message_to_slice<A>(
+    buffer: &mut [u8],
+    message: &crate::message::Builder<A>,
+) -> Result<usize>
+where
+    A: crate::message::Allocator,
+{
+    let capacity = buffer.len();
+    let mut remaining = buffer;
+    write_message(SliceWrite(&mut remaining), message)?;
+    Ok(capacity - remaining.len())
+}
+
+/// Writes into a byte slice, reporting a full slice as `BufferNotLargeEnough` no matter which
+/// I/O features are enabled. The `Write` impls for `&mut [u8]` that come with `std` and
+/// `embedded-io` report it as a generic failure instead.
+struct SliceWrite<'a, 'b>(&'a mut &'b mut [u8]);
+
+impl Write for SliceWrite<'_, '_> {
+    fn write_all(&mut self, buf: &[u8]) -> Result<()> {
+        if buf.len() > self.0.len() {
+            return Err(Error::from_kind(ErrorKind::BufferNotLargeEnough));
+        }
+        let (a, b) = mem::take(self.0).split_at_mut(buf.len());
+        a.copy_from_slice(buf);
+        *self.0 = b;
+        Ok(())
+    }
+}
+
 #[cfg(feature = "alloc")]
 #[cfg(test)]
 mod tests {
@@ -650,3 +685,36 @@ mod tests {
         read_message(&mut &packed_buf[..], Default::default()).unwrap();
     }
 }
+
+#[cfg(test)]
+mod slice_tests {
+    use super::{read_message_no_alloc, write_message_to_slice};
+    use crate::message::{self, ReaderOptions, SingleSegmentAllocator};
+    use crate::{word, ErrorKind, Word};
+
+    // Needs neither `alloc` nor `std`, so it runs under every feature combination.
+    #[test]
+    fn write_message_to_slice_round_trip() {
+        let mut buffer = [word(0, 0, 0, 0, 0, 0, 0, 0); 8];
+        let mut msg = message::Builder::new(SingleSegmentAllocator::new(Word::words_to_bytes_mut(
+            &mut buffer[..],
+        )));
+        msg.set_root("hi").unwrap();
+
+        let mut too_small = [0u8; 4];
+        let err = write_message_to_slice(&mut too_small, &msg).unwrap_err();
+        assert_eq!(err.kind, ErrorKind::BufferNotLargeEnough);
+
+        let mut packed = [0u8; 64];
+        let len = write_message_to_slice(&mut packed, &msg).unwrap();
+        let mut read_buffer = [word(0, 0, 0, 0, 0, 0, 0, 0); 8];
+        let reader = read_message_no_alloc(
+            &mut &packed[..len],
+            Word::words_to_bytes_mut(&mut read_buffer),
+            ReaderOptions::new(),
+        )
+        .unwrap();
+        let s: crate::text::Reader = reader.get_root().unwrap();
+        assert_eq!("hi", s);
+    }
+}
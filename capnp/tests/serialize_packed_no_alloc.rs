@@ -0,0 +1,40 @@
+use capnp::{message, serialize_packed, Word};
+
+#[test]
+pub fn serialize_packed_round_trip_no_alloc() {
+    let mut buffer = [capnp::word(0, 0, 0, 0, 0, 0, 0, 0); 200];
+    let allocator =
+        message::SingleSegmentAllocator::new(capnp::Word::words_to_bytes_mut(&mut buffer[..]));
+    let mut msg = message::Builder::new(allocator);
+    msg.set_root("hello world!").unwrap();
+
+    let mut packed = [0u8; 256];
+    let len = serialize_packed::write_message_to_slice(&mut packed, &msg).unwrap();
+
+    // The packed encoding is smaller than the unpacked one.
+    assert!(len < capnp::serialize::compute_serialized_size_in_words(&msg) * 8);
+
+    let mut read_buffer = [capnp::word(0, 0, 0, 0, 0, 0, 0, 0); 256];
+    let reader = serialize_packed::read_message_no_alloc(
+        &mut &packed[..len],
+        Word::words_to_bytes_mut(&mut read_buffer),
+        message::ReaderOptions::new(),
+    )
+    .unwrap();
+
+    let s: capnp::text::Reader = reader.get_root().unwrap();
+    assert_eq!("hello world!", s);
+}
+
+#[test]
+pub fn serialize_packed_write_buffer_too_small() {
+    let mut buffer = [capnp::word(0, 0, 0, 0, 0, 0, 0, 0); 200];
+    let allocator =
+        message::SingleSegmentAllocator::new(capnp::Word::words_to_bytes_mut(&mut buffer[..]));
+    let mut msg = message::Builder::new(allocator);
+    msg.set_root("hello world!").unwrap();
+
+    let mut packed = [0u8; 8];
+    let err = serialize_packed::write_message_to_slice(&mut packed, &msg).unwrap_err();
+    assert_eq!(err.kind, capnp::ErrorKind::BufferNotLargeEnough);
+}
@@ -0,0 +1,53 @@
+// Copyright (c) 2013-2014 Sandstorm Development Group, Inc. and contributors
+// Licensed under the MIT License:
+//
+// Permission is hereby granted, free of charge, to any person obtaining a copy
+// of this software and associated documentation files (the "Software"), to deal
+// in the Software without restriction, including without limitation the rights
+// to use, copy, modify, merge, publish, distribute, sublicense, and/or sell
+// copies of the Software, and to permit persons to whom the Software is
+// furnished to do so, subject to the following conditions:
+//
+// The above copyright notice and this permission notice shall be included in
+// all copies or substantial portions of the Software.
+//
+// THE SOFTWARE IS PROVIDED "AS IS", WITHOUT WARRANTY OF ANY KIND, EXPRESS OR
+// IMPLIED, INCLUDING BUT NOT LIMITED TO THE WARRANTIES OF MERCHANTABILITY,
+// FITNESS FOR A PARTICULAR PURPOSE AND NONINFRINGEMENT. IN NO EVENT SHALL THE
+// AUTHORS OR COPYRIGHT HOLDERS BE LIABLE FOR ANY CLAIM, DAMAGES OR OTHER
+// LIABILITY, WHETHER IN AN ACTION OF CONTRACT, TORT OR OTHERWISE, ARISING FROM,
+// OUT OF OR IN CONNECTION WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN
+// THE SOFTWARE.
+
+//! # Cap'n Proto Schema Compatibility Checker
+//!
+//! Compares two serialized `CodeGeneratorRequest`s, as written by
+//! `capnpc::CompilerCommand::raw_code_generator_request_path()` or by
+//! `capnp compile -o-`, and reports incompatible changes.
+//!
+//! Usage: `capnpc-rust-compat OLD_REQUEST NEW_REQUEST`
+//!
+//! Exits with status 1 if any wire-incompatible change is found, and with status 2 if the
+//! requests cannot be read.
+
+pub fn main() {
+    let args: Vec<String> = ::std::env::args().collect();
+    if args.len() != 3 {
+        eprintln!("usage: {} OLD_REQUEST NEW_REQUEST", args[0]);
+        ::std::process::exit(2);
+    }
+
+    let incompatibilities = match ::capnpc::compat::check_files(&args[1], &args[2]) {
+        Ok(incompatibilities) => incompatibilities,
+        Err(e) => {
+            eprintln!("failed to check compatibility: {e}");
+            ::std::process::exit(2);
+        }
+    };
+    for incompatibility in &incompatibilities {
+        println!("{incompatibility}");
+    }
+    if incompatibilities.iter().any(|i| i.is_wire()) {
+        ::std::process::exit(1);
+    }
+}
@@ -207,6 +207,11 @@ pub struct GeneratorContext<'a> {
 
     /// Root path for referencing things in the `capnp` crate from the generated code.
     pub capnp_root: String,
+
+    /// Map from node ID to the doc comments of the node and its members, as reported
+    /// in the `sourceInfo` field of the code generator request.
+    pub source_info:
+        collections::hash_map::HashMap<u64, schema_capnp::node::source_info::Reader<'a>>,
 }
 
 impl<'a> GeneratorContext<'a> {
@@ -230,6 +235,7 @@ impl<'a> GeneratorContext<'a> {
             scope_map: collections::hash_map::HashMap::<u64, Vec<String>>::new(),
             node_parents: collections::hash_map::HashMap::new(),
             capnp_root: code_generation_command.capnp_root.clone(),
+            source_info: collections::hash_map::HashMap::new(),
         };
 
         let crates_provide = &code_generation_command.crates_provide_map;
@@ -239,6 +245,10 @@ impl<'a> GeneratorContext<'a> {
             ctx.node_parents.insert(node.get_id(), node.get_scope_id());
         }
 
+        for source_info in ctx.request.get_source_info()? {
+            ctx.source_info.insert(source_info.get_id(), source_info);
+        }
+
         // Fix up "anonymous" method params and results scopes.
         for node in ctx.request.get_nodes()? {
             if let Ok(schema_capnp::node::Interface(interface_reader)) = node.which() {
@@ -291,6 +301,30 @@ impl<'a> GeneratorContext<'a> {
         Ok(ctx)
     }
 
+    /// Returns `///` lines for the doc comment of a node, if it has one.
+    fn node_doc_comment(&self, id: u64) -> ::capnp::Result<Vec<FormattedText>> {
+        match self.source_info.get(&id) {
+            Some(source_info) if source_info.has_doc_comment() => {
+                Ok(doc_comment_lines(source_info.get_doc_comment()?.to_str()?))
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
+    /// Returns `///` lines for the doc comment of the member (field, enumerant, or method)
+    /// at `index` of a node, if it has one.
+    fn member_doc_comment(&self, id: u64, index: u32) -> ::capnp::Result<Vec<FormattedText>> {
+        let Some(source_info) = self.source_info.get(&id) else {
+            return Ok(Vec::new());
+        };
+        match source_info.get_members()?.try_get(index) {
+            Some(member) if member.has_doc_comment() => {
+                Ok(doc_comment_lines(member.get_doc_comment()?.to_str()?))
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+
     fn get_last_name(&self, id: u64) -> ::capnp::Result<&str> {
         match self.scope_map.get(&id) {
             None => Err(Error::failed(format!("node not found: {id}"))),
@@ -400,7 +434,7 @@ fn path_to_stem_string<P: AsRef<::std::path::Path>>(path: P) -> ::capnp::Result<
     }
 }
 
-fn camel_to_snake_case(s: &str) -> String {
+pub(crate) fn camel_to_snake_case(s: &str) -> String {
     let mut result_chars: Vec<char> = Vec::new();
     let mut first_char = true;
     for c in s.chars() {
@@ -413,6 +447,84 @@ fn camel_to_snake_case(s: &str) -> String {
     result_chars.into_iter().collect()
 }
 
+// Converts a schema doc comment into `///` lines. Indented blocks and unlabeled code
+// fences are marked as `text`, so that rustdoc does not try to run them as doctests.
+fn doc_comment_lines(comment: &str) -> Vec<FormattedText> {
+    let mut result = Vec::new();
+    let mut in_fence = false;
+    let mut in_indented_block = false;
+    for l in comment.trim_end().lines() {
+        let trimmed = l.trim_start();
+        if !in_indented_block && trimmed.starts_with("```") {
+            if !in_fence && trimmed == "```" {
+                result.push(Line(format!("/// {}text", l)));
+            } else {
+                result.push(Line(format!("/// {l}")));
+            }
+            in_fence = !in_fence;
+            continue;
+        }
+        if !in_fence {
+            let indented = l.starts_with("    ") || l.starts_with('\t');
+            if indented && !in_indented_block {
+                result.push(line("/// ```text"));
+                in_indented_block = true;
+            } else if !indented && in_indented_block && !trimmed.is_empty() {
+                result.push(line("/// ```"));
+                in_indented_block = false;
+            }
+        }
+        if l.is_empty() {
+            result.push(line("///"));
+        } else {
+            result.push(Line(format!("/// {l}")));
+        }
+    }
+    if in_fence || in_indented_block {
+        result.push(line("/// ```"));
+    }
+    result
+}
+
+#[test]
+fn test_doc_comment_lines() {
+    fn lines(comment: &str) -> Vec<String> {
+        doc_comment_lines(comment)
+            .into_iter()
+            .map(|ft| match ft {
+                Line(l) => l,
+                _ => panic!("expected a line"),
+            })
+            .collect()
+    }
+
+    assert_eq!(lines("Hello.\n"), vec!["/// Hello."]);
+    assert_eq!(
+        lines("First.\n\nSecond.\n"),
+        vec!["/// First.", "///", "/// Second."]
+    );
+    assert_eq!(
+        lines("Example:\n\n    foo @0 :Text;\n\nDone.\n"),
+        vec![
+            "/// Example:",
+            "///",
+            "/// ```text",
+            "///     foo @0 :Text;",
+            "///",
+            "/// ```",
+            "/// Done."
+        ]
+    );
+    assert_eq!(
+        lines("```\nfoo\n```\n"),
+        vec!["/// ```text", "/// foo", "/// ```"]
+    );
+    assert_eq!(
+        lines("```rust\nfoo()\n```"),
+        vec!["/// ```rust", "/// foo()", "/// ```"]
+    );
+}
+
 fn capitalize_first_letter(s: &str) -> String {
     let mut result_chars: Vec<char> = Vec::new();
     for c in s.chars() {
@@ -516,14 +628,23 @@ fn module_name(camel_case: &str) -> String {
 }
 
 // Annotation IDs, as defined in rust.capnp.
-const NAME_ANNOTATION_ID: u64 = 0xc2fe4c6d100166d0;
+pub(crate) const NAME_ANNOTATION_ID: u64 = 0xc2fe4c6d100166d0;
 const PARENT_MODULE_ANNOTATION_ID: u64 = 0xabee386cd1450364;
 const OPTION_ANNOTATION_ID: u64 = 0xabfef22c4ee1964e;
+const PRESENCE_ANNOTATION_ID: u64 = 0xd5c8ae1cc4e3b2a7;
+const DOC_ANNOTATION_ID: u64 = 0xde04542dd489bf9a;
+const DEPRECATED_ANNOTATION_ID: u64 = 0xc82ff400377f6136;
+const ATTRIBUTES_ANNOTATION_ID: u64 = 0x8e1edd27294ed701;
+const DERIVE_ANNOTATION_ID: u64 = 0x80ec389f8d276296;
+const VISIBILITY_ANNOTATION_ID: u64 = 0xb65bf0887402d71b;
+const NON_EXHAUSTIVE_ANNOTATION_ID: u64 = 0xb7f48bbb1449b808;
 
 // StreamResult type ID, as defined in stream.capnp.
 const STREAM_RESULT_ID: u64 = 0x995f9a3377c0b16e;
 
-fn name_annotation_value(annotation: schema_capnp::annotation::Reader<'_>) -> capnp::Result<&str> {
+pub(crate) fn name_annotation_value(
+    annotation: schema_capnp::annotation::Reader<'_>,
+) -> capnp::Result<&str> {
     if let schema_capnp::value::Text(t) = annotation.get_value()?.which()? {
         let name = t?.to_str()?;
         for c in name.chars() {
@@ -542,7 +663,7 @@ fn name_annotation_value(annotation: schema_capnp::annotation::Reader<'_>) -> ca
     }
 }
 
-fn get_field_name(field: schema_capnp::field::Reader<'_>) -> capnp::Result<&str> {
+pub(crate) fn get_field_name(field: schema_capnp::field::Reader<'_>) -> capnp::Result<&str> {
     for annotation in field.get_annotations()? {
         if annotation.get_id() == NAME_ANNOTATION_ID {
             return name_annotation_value(annotation);
@@ -551,7 +672,9 @@ fn get_field_name(field: schema_capnp::field::Reader<'_>) -> capnp::Result<&str>
     Ok(field.get_name()?.to_str()?)
 }
 
-fn get_enumerant_name(enumerant: schema_capnp::enumerant::Reader<'_>) -> capnp::Result<&str> {
+pub(crate) fn get_enumerant_name(
+    enumerant: schema_capnp::enumerant::Reader<'_>,
+) -> capnp::Result<&str> {
     for annotation in enumerant.get_annotations()? {
         if annotation.get_id() == NAME_ANNOTATION_ID {
             return name_annotation_value(annotation);
@@ -570,6 +693,86 @@ fn get_parent_module(annotation: schema_capnp::annotation::Reader) -> capnp::Res
         ))
     }
 }
+fn text_annotation_value<'a>(
+    annotation: schema_capnp::annotation::Reader<'a>,
+    annotation_name: &str,
+) -> capnp::Result<&'a str> {
+    if let schema_capnp::value::Text(t) = annotation.get_value()?.which()? {
+        Ok(t?.to_str()?)
+    } else {
+        Err(capnp::Error::failed(format!(
+            "expected rust.{annotation_name} annotation value to be of type Text"
+        )))
+    }
+}
+
+fn text_list_annotation_value<'a>(
+    annotation: schema_capnp::annotation::Reader<'a>,
+    annotation_name: &str,
+) -> capnp::Result<Vec<&'a str>> {
+    if let schema_capnp::value::List(l) = annotation.get_value()?.which()? {
+        let mut result = Vec::new();
+        for t in l.get_as::<capnp::text_list::Reader>()? {
+            result.push(t?.to_str()?);
+        }
+        Ok(result)
+    } else {
+        Err(capnp::Error::failed(format!(
+            "expected rust.{annotation_name} annotation value to be of type List(Text)"
+        )))
+    }
+}
+
+// Returns the `#[...]` lines requested by the `$Rust.doc`, `$Rust.deprecated` and
+// `$Rust.attributes` annotations on an item.
+fn item_attributes(
+    annotations: capnp::struct_list::Reader<schema_capnp::annotation::Owned>,
+) -> capnp::Result<Vec<FormattedText>> {
+    let mut result = Vec::new();
+    for annotation in annotations {
+        match annotation.get_id() {
+            DOC_ANNOTATION_ID => {
+                let doc = text_annotation_value(annotation, "doc")?;
+                result.push(Line(format!("#[doc = {doc:?}]")));
+            }
+            DEPRECATED_ANNOTATION_ID => {
+                let note = text_annotation_value(annotation, "deprecated")?;
+                if note.is_empty() {
+                    result.push(line("#[deprecated]"));
+                } else {
+                    result.push(Line(format!("#[deprecated = {note:?}]")));
+                }
+            }
+            ATTRIBUTES_ANNOTATION_ID => {
+                for attribute in text_list_annotation_value(annotation, "attributes")? {
+                    result.push(Line(format!("#[{attribute}]")));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(result)
+}
+
+// Returns the visibility requested by the `$Rust.visibility` annotation on an item,
+// defaulting to `pub`.
+fn item_visibility(
+    annotations: capnp::struct_list::Reader<schema_capnp::annotation::Owned>,
+) -> capnp::Result<&'static str> {
+    for annotation in annotations {
+        if annotation.get_id() == VISIBILITY_ANNOTATION_ID {
+            return match text_annotation_value(annotation, "visibility")? {
+                "pub" => Ok("pub"),
+                "pub(crate)" => Ok("pub(crate)"),
+                v => Err(capnp::Error::failed(format!(
+                    "unsupported rust.visibility annotation value: {v:?} (expected \"pub\" or \"pub(crate)\")"
+                ))),
+            };
+        }
+    }
+    Ok("pub")
+}
+
 #[derive(Clone, Copy)]
 enum NameKind {
     // convert camel case to snake case, and avoid Rust keywords
@@ -597,14 +800,38 @@ fn is_option_field(field: schema_capnp::field::Reader) -> capnp::Result<bool> {
     if enabled {
         let supported = match field.which()? {
             field::Which::Group(_) => false,
-            field::Which::Slot(field) => {
-                let ty = field.get_type()?;
-                ty.is_pointer()? && !matches!(ty.which()?, type_::Interface(_))
-            }
+            field::Which::Slot(field) => field.get_type()?.is_pointer()?,
         };
         if !supported {
             return Err(capnp::Error::failed(
-                "$Rust.option annotation only supported on pointer fields (support for optional interfaces isn't implemented yet)".to_string(),
+                "$Rust.option annotation only supported on pointer fields (use $Rust.presence for primitive union members)".to_string(),
+            ));
+        }
+    }
+
+    Ok(enabled)
+}
+
+fn is_presence_field(field: schema_capnp::field::Reader) -> capnp::Result<bool> {
+    use capnp::schema_capnp::*;
+
+    let enabled = field
+        .get_annotations()?
+        .iter()
+        .any(|a| a.get_id() == PRESENCE_ANNOTATION_ID);
+
+    if enabled {
+        let supported = field.get_discriminant_value() != field::NO_DISCRIMINANT
+            && match field.which()? {
+                field::Which::Group(_) => false,
+                field::Which::Slot(field) => {
+                    let ty = field.get_type()?;
+                    !ty.is_pointer()? && !matches!(ty.which()?, type_::Void(()))
+                }
+            };
+        if !supported {
+            return Err(capnp::Error::failed(
+                "$Rust.presence annotation only supported on non-void primitive and enum fields that are members of a union".to_string(),
             ));
         }
     }
@@ -754,14 +981,15 @@ pub fn getter_text(
                     fmt!(ctx, "::core::result::Result<{typ},{capnp}::NotInSchema>"),
                 ),
                 type_::AnyPointer(_) if !raw_type.is_parameter()? => (false, typ.clone()),
-                type_::Interface(_) => (
-                    true,
-                    fmt!(
-                        ctx,
-                        "{capnp}::Result<{}>",
-                        raw_type.type_string(ctx, Leaf::Client)?
-                    ),
-                ),
+                type_::Interface(_) => {
+                    let client = raw_type.type_string(ctx, Leaf::Client)?;
+                    let client = if should_get_option {
+                        format!("Option<{client}>")
+                    } else {
+                        client
+                    };
+                    (true, fmt!(ctx, "{capnp}::Result<{client}>"))
+                }
                 _ if raw_type.is_prim()? => (false, typ.clone()),
                 _ => (true, fmt!(ctx, "{capnp}::Result<{typ}>")),
             };
@@ -845,7 +1073,7 @@ pub fn getter_text(
                 }
 
                 (type_::Interface(_), value::Interface(_)) => {
-                    fmt!(ctx,"match self.{member}.get_pointer_field({offset}).get_capability() {{ ::core::result::Result::Ok(c) => ::core::result::Result::Ok({capnp}::capability::FromClientHook::new(c)), ::core::result::Result::Err(e) => ::core::result::Result::Err(e)}}")
+                    fmt!(ctx,"self.{member}.get_pointer_field({offset}).get_capability().map({capnp}::capability::FromClientHook::new)")
                 }
                 (type_::AnyPointer(_), value::AnyPointer(_)) => {
                     if !raw_type.is_parameter()? {
@@ -988,6 +1216,7 @@ fn generate_setter(
     discriminant_offset: u32,
     styled_name: &str,
     field: &schema_capnp::field::Reader,
+    attributes: &[FormattedText],
 ) -> ::capnp::Result<FormattedText> {
     use capnp::schema_capnp::*;
 
@@ -1196,6 +1425,7 @@ fn generate_setter(
 
                         let builder_type = typ.type_string(ctx, Leaf::Builder("'a"))?;
 
+                        result.extend(attributes.iter().cloned());
                         result.push(line("#[inline]"));
                         result.push(Line(format!(
                             "pub fn initn_{styled_name}(self, length: u32) -> {builder_type} {{"
@@ -1231,6 +1461,7 @@ fn generate_setter(
         } else {
             "".into()
         };
+        result.extend(attributes.iter().cloned());
         result.push(line("#[inline]"));
         result.push(Line(format!(
             "pub fn set_{styled_name}(&mut self, {setter_param}: {reader_type}) {return_type} {{"
@@ -1239,6 +1470,7 @@ fn generate_setter(
         result.push(line("}"));
     }
     if let Some(builder_type) = maybe_builder_type {
+        result.extend(attributes.iter().cloned());
         result.push(line("#[inline]"));
         let args = initter_params.join(", ");
         let mutable = if initter_mut { "mut " } else { "" };
@@ -1521,6 +1753,7 @@ fn generate_haser(
     styled_name: &str,
     field: &schema_capnp::field::Reader,
     is_reader: bool,
+    attributes: &[FormattedText],
 ) -> ::capnp::Result<FormattedText> {
     use capnp::schema_capnp::*;
 
@@ -1555,6 +1788,7 @@ fn generate_haser(
                         reg_field.get_offset()
                     )));
                 }
+                result.extend(attributes.iter().cloned());
                 result.push(line("#[inline]"));
                 result.push(Line(format!("pub fn has_{styled_name}(&self) -> bool {{")));
                 result.push(indent(interior));
@@ -1567,9 +1801,74 @@ fn generate_haser(
     Ok(Branch(result))
 }
 
+// Generates a getter that returns `None` when the (primitive) union member
+// `field` is not the active member of its union.
+fn generate_presence_getter(
+    ctx: &GeneratorContext,
+    discriminant_offset: u32,
+    styled_name: &str,
+    field: &schema_capnp::field::Reader,
+    is_reader: bool,
+    attributes: &[FormattedText],
+) -> ::capnp::Result<FormattedText> {
+    use capnp::schema_capnp::*;
+
+    let member = if is_reader { "reader" } else { "builder" };
+    let field::Slot(reg_field) = field.which()? else {
+        return Err(Error::failed("expected a slot field".to_string()));
+    };
+    let raw_type = reg_field.get_type()?;
+    let inner_type = raw_type.type_string(ctx, Leaf::Owned)?;
+    let (_, get, _) = getter_text(ctx, field, is_reader, false)?;
+    let (result_type, some, none) = if let type_::Enum(_) = raw_type.which()? {
+        (
+            fmt!(
+                ctx,
+                "::core::result::Result<::core::option::Option<{inner_type}>,{capnp}::NotInSchema>"
+            ),
+            Branch(vec![
+                get,
+                indent(line(".map(::core::option::Option::Some)")),
+            ]),
+            "::core::result::Result::Ok(::core::option::Option::None)",
+        )
+    } else {
+        (
+            format!("::core::option::Option<{inner_type}>"),
+            Branch(vec![
+                line("::core::option::Option::Some("),
+                indent(get),
+                line(")"),
+            ]),
+            "::core::option::Option::None",
+        )
+    };
+
+    Ok(Branch(vec![
+        Branch(attributes.to_vec()),
+        line("#[inline]"),
+        Line(format!(
+            "pub fn get_{styled_name}(self) -> {result_type} {{"
+        )),
+        indent(vec![
+            Line(format!(
+                "if self.{member}.get_data_field::<u16>({}) == {} {{",
+                discriminant_offset as usize,
+                field.get_discriminant_value() as usize
+            )),
+            indent(some),
+            line("} else {"),
+            indent(line(none)),
+            line("}"),
+        ]),
+        line("}"),
+    ]))
+}
+
 fn generate_pipeline_getter(
     ctx: &GeneratorContext,
     field: schema_capnp::field::Reader,
+    attributes: &[FormattedText],
 ) -> ::capnp::Result<FormattedText> {
     use capnp::schema_capnp::{field, type_};
 
@@ -1586,6 +1885,7 @@ fn generate_pipeline_getter(
 
             let the_mod = ctx.get_qualified_module(group.get_type_id());
             Ok(Branch(vec![
+                Branch(attributes.to_vec()),
                 Line(format!(
                     "pub fn get_{}(&self) -> {}::Pipeline{} {{",
                     camel_to_snake_case(name),
@@ -1604,6 +1904,7 @@ fn generate_pipeline_getter(
             match typ.which()? {
                 type_::Struct(_) | type_::AnyPointer(_) => {
                     Ok(Branch(vec![
+                        Branch(attributes.to_vec()),
                         Line(format!("pub fn get_{}(&self) -> {} {{", camel_to_snake_case(name), typ.type_string(ctx, Leaf::Pipeline)?)),
                         indent(Line(fmt!(ctx,"{capnp}::capability::FromTypelessPipeline::new(self._typeless.get_pointer_field({}))", reg_field.get_offset()))),
                         line("}")
@@ -1611,6 +1912,7 @@ fn generate_pipeline_getter(
                 }
                 type_::Interface(_) => {
                     Ok(Branch(vec![
+                        Branch(attributes.to_vec()),
                         Line(format!("pub fn get_{}(&self) -> {} {{", camel_to_snake_case(name), typ.type_string(ctx, Leaf::Client)?)),
                         indent(Line(fmt!(ctx,"{capnp}::capability::FromClientHook::new(self._typeless.get_pointer_field({}).as_cap())", reg_field.get_offset()))),
                         line("}")
@@ -2004,17 +2306,24 @@ fn generate_node(
         }
         node::Struct(struct_reader) => {
             let params = node_reader.parameters_texts(ctx);
+            let struct_doc = ctx.node_doc_comment(node_id)?;
             output.push(BlankLine);
 
+            output.append(&mut item_attributes(node_reader.get_annotations()?)?);
+            let vis = item_visibility(node_reader.get_annotations()?)?;
+            if vis != "pub" {
+                // The items inside the module stay `pub`.
+                output.push(line("#[allow(unreachable_pub)]"));
+            }
             let is_generic = node_reader.get_is_generic();
             if is_generic {
                 output.push(Line(format!(
-                    "pub mod {} {{ /* {} */",
+                    "{vis} mod {} {{ /* {} */",
                     node_name,
                     params.expanded_list.join(",")
                 )));
             } else {
-                output.push(Line(format!("pub mod {node_name} {{")));
+                output.push(Line(format!("{vis} mod {node_name} {{")));
             }
             let bracketed_params = if params.params.is_empty() {
                 "".to_string()
@@ -2064,12 +2373,15 @@ fn generate_node(
 
             let mut has_pointer_field = false;
             let fields = struct_reader.get_fields()?;
-            for field in fields {
+            for (field_index, field) in fields.into_iter().enumerate() {
                 let name = get_field_name(field)?;
                 let styled_name = camel_to_snake_case(name);
 
                 let discriminant_value = field.get_discriminant_value();
                 let is_union_field = discriminant_value != field::NO_DISCRIMINANT;
+                let is_presence = is_presence_field(field)?;
+                let mut field_attributes = ctx.member_doc_comment(node_id, field_index as u32)?;
+                field_attributes.append(&mut item_attributes(field.get_annotations()?)?);
 
                 match field.which()? {
                     field::Slot(s) => match s.get_type()?.which()? {
@@ -2085,12 +2397,13 @@ fn generate_node(
                 }
 
                 if !is_union_field {
-                    pipeline_impl_interior.push(generate_pipeline_getter(ctx, field)?);
+                    pipeline_impl_interior.push(generate_pipeline_getter(ctx, field, &field_attributes)?);
                     let (ty, get, default_decl) = getter_text(ctx, &field, true, true)?;
                     if let Some(default) = default_decl {
                         private_mod_interior.push(default.clone());
                     }
                     reader_members.push(Branch(vec![
+                        Branch(field_attributes.clone()),
                         line("#[inline]"),
                         Line(format!("pub fn get_{styled_name}(self) {ty} {{")),
                         indent(get),
@@ -2099,12 +2412,31 @@ fn generate_node(
 
                     let (ty_b, get_b, _) = getter_text(ctx, &field, false, true)?;
                     builder_members.push(Branch(vec![
+                        Branch(field_attributes.clone()),
                         line("#[inline]"),
                         Line(format!("pub fn get_{styled_name}(self) {ty_b} {{")),
                         indent(get_b),
                         line("}"),
                     ]));
                 } else {
+                    if is_presence {
+                        reader_members.push(generate_presence_getter(
+                            ctx,
+                            discriminant_offset,
+                            &styled_name,
+                            &field,
+                            true,
+                            &field_attributes,
+                        )?);
+                        builder_members.push(generate_presence_getter(
+                            ctx,
+                            discriminant_offset,
+                            &styled_name,
+                            &field,
+                            false,
+                            &field_attributes,
+                        )?);
+                    }
                     union_fields.push(field);
                 }
 
@@ -2113,6 +2445,7 @@ fn generate_node(
                     discriminant_offset,
                     &styled_name,
                     &field,
+                    &field_attributes,
                 )?);
 
                 reader_members.push(generate_haser(
@@ -2120,12 +2453,14 @@ fn generate_node(
                     &styled_name,
                     &field,
                     true,
+                    &field_attributes,
                 )?);
                 builder_members.push(generate_haser(
                     discriminant_offset,
                     &styled_name,
                     &field,
                     false,
+                    &field_attributes,
                 )?);
 
                 if let Ok(field::Group(group)) = field.which() {
@@ -2216,6 +2551,7 @@ fn generate_node(
                     ])
                 }),
                 BlankLine,
+                Branch(struct_doc.clone()),
                 (if !is_generic {
                     Line(fmt!(ctx,"pub struct Reader<'a> {{ reader: {capnp}::private::layout::StructReader<'a> }}"))
                 } else {
@@ -2315,6 +2651,7 @@ fn generate_node(
                 indent(reader_members),
                 line("}"),
                 BlankLine,
+                Branch(struct_doc.clone()),
                 (if !is_generic {
                     Line(fmt!(ctx,"pub struct Builder<'a> {{ builder: {capnp}::private::layout::StructBuilder<'a> }}"))
                 } else {
@@ -2440,6 +2777,8 @@ fn generate_node(
             let mut match_branches = Vec::new();
             let enumerants = enum_reader.get_enumerants()?;
             for (ii, enumerant) in enumerants.into_iter().enumerate() {
+                members.append(&mut ctx.member_doc_comment(node_id, ii as u32)?);
+                members.append(&mut item_attributes(enumerant.get_annotations()?)?);
                 let enumerant = capitalize_first_letter(get_enumerant_name(enumerant)?);
                 members.push(Line(format!("{enumerant} = {ii},")));
                 match_branches.push(Line(format!(
@@ -2451,10 +2790,31 @@ fn generate_node(
                 "n => ::core::result::Result::Err({capnp}::NotInSchema(n)),"
             )));
 
+            let mut derives = vec!["Clone", "Copy", "Debug", "PartialEq", "Eq"];
+            let mut enum_attributes = ctx.node_doc_comment(node_id)?;
+            enum_attributes.append(&mut item_attributes(node_reader.get_annotations()?)?);
+            for annotation in node_reader.get_annotations()? {
+                match annotation.get_id() {
+                    DERIVE_ANNOTATION_ID => {
+                        for derive in text_list_annotation_value(annotation, "derive")? {
+                            if !derives.contains(&derive) {
+                                derives.push(derive);
+                            }
+                        }
+                    }
+                    NON_EXHAUSTIVE_ANNOTATION_ID => {
+                        enum_attributes.push(line("#[non_exhaustive]"));
+                    }
+                    _ => {}
+                }
+            }
+            let vis = item_visibility(node_reader.get_annotations()?)?;
+
             output.push(Branch(vec![
+                Branch(enum_attributes),
                 line("#[repr(u16)]"),
-                line("#[derive(Clone, Copy, Debug, PartialEq, Eq)]"),
-                Line(format!("pub enum {last_name} {{")),
+                Line(format!("#[derive({})]", derives.join(", "))),
+                Line(format!("{vis} enum {last_name} {{")),
                 indent(members),
                 line("}"),
             ]));
@@ -2560,6 +2920,9 @@ fn generate_node(
             let methods = interface.get_methods()?;
             for (ordinal, method) in methods.into_iter().enumerate() {
                 let name = method.get_name()?.to_str()?;
+                let method_doc = ctx.member_doc_comment(node_id, ordinal as u32)?;
+                let mut method_attributes = method_doc.clone();
+                method_attributes.append(&mut item_attributes(method.get_annotations()?)?);
 
                 let param_id = method.get_param_struct_type();
                 let param_node = &ctx.node_map[&param_id];
@@ -2625,6 +2988,7 @@ fn generate_node(
                         results_ty_params,
                         result_type
                     )));
+                    server_interior.push(Branch(method_doc.clone()));
                     server_interior.push(
                         Line(fmt!(ctx,
                                   "fn {}(self: {capnp}::capability::Rc<Self>, _: {}Params<{}>, _: {}Results<{}>) -> impl ::core::future::Future<Output = Result<(), {capnp}::Error>> + 'static {{ ::core::future::ready(Err({capnp}::Error::unimplemented(\"method {}::Server::{} not implemented\".to_string()))) }}",
@@ -2634,6 +2998,7 @@ fn generate_node(
                                   node_name, module_name(name)
                         )));
 
+                    client_impl_interior.push(Branch(method_attributes));
                     client_impl_interior.push(Line(fmt!(
                         ctx,
                         "pub fn {}_request(&self) -> {capnp}::capability::Request<{},{}> {{",
@@ -2654,6 +3019,7 @@ fn generate_node(
 
                                   module_name(name))));
 
+                    server_interior.push(Branch(method_doc.clone()));
                     server_interior.push(
                         Line(fmt!(ctx,
                                   "fn {}(self: {capnp}::capability::Rc<Self>, _: {}Params<{}>) -> impl ::core::future::Future<Output = Result<(), {capnp}::Error>> + 'static {{ ::core::future::ready(Err({capnp}::Error::unimplemented(\"method {}::Server::{} not implemented\".to_string()))) }}",
@@ -2661,6 +3027,7 @@ fn generate_node(
                                   capitalize_first_letter(name), params_ty_params,
                                   node_name, module_name(name)
                         )));
+                    client_impl_interior.push(Branch(method_attributes));
                     client_impl_interior.push(Line(fmt!(
                         ctx,
                         "pub fn {}_request(&self) -> {capnp}::capability::StreamingRequest<{}> {{",
@@ -2673,8 +3040,6 @@ fn generate_node(
 
                     client_impl_interior.push(line("}"));
                 }
-
-                method.get_annotations()?;
             }
 
             let mut base_dispatch_arms = Vec::new();
@@ -2724,7 +3089,9 @@ fn generate_node(
                 }
             };
 
+            let interface_doc = ctx.node_doc_comment(node_id)?;
             mod_interior.push(BlankLine);
+            mod_interior.push(Branch(interface_doc.clone()));
             mod_interior.push(Line(format!("pub struct Client{bracketed_params} {{")));
             mod_interior.push(indent(Line(fmt!(
                 ctx,
@@ -2839,6 +3206,7 @@ fn generate_node(
             ]));
 
             mod_interior.push(Branch(vec![
+                Branch(interface_doc),
                 Line(format!(
                     "pub trait Server<{}> {} {} {{",
                     params.params, server_base, params.where_clause
@@ -2942,53 +3310,62 @@ fn generate_node(
             mod_interior.push(Branch(vec![Branch(nested_output)]));
 
             output.push(BlankLine);
+            output.append(&mut item_attributes(node_reader.get_annotations()?)?);
+            let vis = item_visibility(node_reader.get_annotations()?)?;
+            if vis != "pub" {
+                // The items inside the module stay `pub`.
+                output.push(line("#[allow(unreachable_pub)]"));
+            }
             if is_generic {
                 output.push(Line(format!(
-                    "pub mod {} {{ /* ({}) */",
+                    "{vis} mod {} {{ /* ({}) */",
                     node_name,
                     params.expanded_list.join(",")
                 )));
             } else {
-                output.push(Line(format!("pub mod {node_name} {{")));
+                output.push(Line(format!("{vis} mod {node_name} {{")));
             }
             output.push(indent(mod_interior));
             output.push(line("}"));
         }
 
         node::Const(c) => {
-            let styled_name = ctx.get_last_name(node_id)?.to_ascii_uppercase();
+            let last_name = ctx.get_last_name(node_id)?;
+            let styled_name = last_name.to_ascii_uppercase();
+            let vis = item_visibility(node_reader.get_annotations()?)?;
+            let attributes = item_attributes(node_reader.get_annotations()?)?;
 
             let typ = c.get_type()?;
             let formatted_text = match (typ.which()?, c.get_value()?.which()?) {
                 (type_::Void(()), value::Void(())) => {
-                    Line(format!("pub const {styled_name}: () = ();"))
+                    Line(format!("{vis} const {styled_name}: () = ();"))
                 }
                 (type_::Bool(()), value::Bool(b)) => {
-                    Line(format!("pub const {styled_name}: bool = {b};"))
+                    Line(format!("{vis} const {styled_name}: bool = {b};"))
                 }
                 (type_::Int8(()), value::Int8(i)) => {
-                    Line(format!("pub const {styled_name}: i8 = {i};"))
+                    Line(format!("{vis} const {styled_name}: i8 = {i};"))
                 }
                 (type_::Int16(()), value::Int16(i)) => {
-                    Line(format!("pub const {styled_name}: i16 = {i};"))
+                    Line(format!("{vis} const {styled_name}: i16 = {i};"))
                 }
                 (type_::Int32(()), value::Int32(i)) => {
-                    Line(format!("pub const {styled_name}: i32 = {i};"))
+                    Line(format!("{vis} const {styled_name}: i32 = {i};"))
                 }
                 (type_::Int64(()), value::Int64(i)) => {
-                    Line(format!("pub const {styled_name}: i64 = {i};"))
+                    Line(format!("{vis} const {styled_name}: i64 = {i};"))
                 }
                 (type_::Uint8(()), value::Uint8(i)) => {
-                    Line(format!("pub const {styled_name}: u8 = {i};"))
+                    Line(format!("{vis} const {styled_name}: u8 = {i};"))
                 }
                 (type_::Uint16(()), value::Uint16(i)) => {
-                    Line(format!("pub const {styled_name}: u16 = {i};"))
+                    Line(format!("{vis} const {styled_name}: u16 = {i};"))
                 }
                 (type_::Uint32(()), value::Uint32(i)) => {
-                    Line(format!("pub const {styled_name}: u32 = {i};"))
+                    Line(format!("{vis} const {styled_name}: u32 = {i};"))
                 }
                 (type_::Uint64(()), value::Uint64(i)) => {
-                    Line(format!("pub const {styled_name}: u64 = {i};"))
+                    Line(format!("{vis} const {styled_name}: u64 = {i};"))
                 }
 
                 (type_::Float32(()), value::Float32(f)) => {
@@ -3003,7 +3380,7 @@ fn generate_node(
                         }
                         _ => format!("{f:e}"),
                     };
-                    Line(format!("pub const {styled_name}: f32 = {literal};"))
+                    Line(format!("{vis} const {styled_name}: f32 = {literal};"))
                 }
 
                 (type_::Float64(()), value::Float64(f)) => {
@@ -3018,7 +3395,7 @@ fn generate_node(
                         }
                         _ => format!("{f:e}"),
                     };
-                    Line(format!("pub const {styled_name}: f64 = {literal};"))
+                    Line(format!("{vis} const {styled_name}: f64 = {literal};"))
                 }
 
                 (type_::Enum(e), value::Enum(v)) => {
@@ -3031,7 +3408,7 @@ fn generate_node(
                                         capitalize_first_letter(get_enumerant_name(enumerant)?);
                                     let type_string = typ.type_string(ctx, Leaf::Owned)?;
                                     Line(format!(
-                                        "pub const {}: {} = {}::{};",
+                                        "{vis} const {}: {} = {}::{};",
                                         styled_name, &type_string, &type_string, variant
                                     ))
                                 } else {
@@ -3056,18 +3433,18 @@ fn generate_node(
                 }
 
                 (type_::Text(()), value::Text(t)) => Line(format!(
-                    "pub const {styled_name}: &str = {:?};",
+                    "{vis} const {styled_name}: &str = {:?};",
                     t?.to_str()?
                 )),
                 (type_::Data(()), value::Data(d)) => {
-                    Line(format!("pub const {styled_name}: &[u8] = &{:?};", d?))
+                    Line(format!("{vis} const {styled_name}: &[u8] = &{:?};", d?))
                 }
 
                 (type_::List(_), value::List(v)) => {
-                    generate_pointer_constant(ctx, &styled_name, typ, v)?
+                    generate_pointer_constant(ctx, vis, &attributes, last_name, typ, v)?
                 }
                 (type_::Struct(_), value::Struct(v)) => {
-                    generate_pointer_constant(ctx, &styled_name, typ, v)?
+                    generate_pointer_constant(ctx, vis, &attributes, last_name, typ, v)?
                 }
 
                 (type_::Interface(_t), value::Interface(())) => {
@@ -3082,6 +3459,10 @@ fn generate_node(
                 }
             };
 
+            // Pointer constants attach the attributes themselves.
+            if !matches!(typ.which()?, type_::List(_) | type_::Struct(_)) {
+                output.push(Branch(attributes));
+            }
             output.push(formatted_text);
         }
 
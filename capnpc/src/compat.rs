@@ -0,0 +1,1036 @@
+//! Checks that a new version of a schema is compatible with an old version.
+//!
+//! The check compares the nodes of two `CodeGeneratorRequest`s, matching them by ID,
+//! and reports every change that would prevent old and new code from exchanging
+//! messages ([`IncompatibilityKind::Wire`]) or that would break Rust code written
+//! against the old generated code ([`IncompatibilityKind::Source`]).
+//!
+//! A request can be saved during a build with
+//! [`crate::CompilerCommand::raw_code_generator_request_path`]. For example, a build
+//! script can compare the current schema against a checked-in request:
+//!
+//! ```ignore
+//! fn main() {
+//!     let out_dir = std::path::PathBuf::from(std::env::var("OUT_DIR").unwrap());
+//!     capnpc::CompilerCommand::new()
+//!         .file("schema/foo.capnp")
+//!         .raw_code_generator_request_path(out_dir.join("request.bin"))
+//!         .run()
+//!         .expect("schema compiler command");
+//!
+//!     let incompatibilities =
+//!         capnpc::compat::check_files("schema/released.bin", out_dir.join("request.bin"))
+//!             .expect("compatibility check");
+//!     for incompatibility in &incompatibilities {
+//!         println!("cargo:warning={incompatibility}");
+//!     }
+//!     assert!(!incompatibilities.iter().any(|i| i.is_wire()));
+//! }
+//! ```
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use capnp::schema_capnp::{code_generator_request, field, node, type_, value};
+use capnp::Result;
+
+use crate::codegen::{
+    get_enumerant_name, get_field_name, name_annotation_value, NAME_ANNOTATION_ID,
+};
+use crate::codegen_types::RustTypeInfo;
+use crate::convert_io_err;
+
+/// The kind of breakage caused by a schema change.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum IncompatibilityKind {
+    /// Messages written with one version of the schema cannot be correctly read
+    /// with the other.
+    Wire,
+
+    /// The encoding is unchanged, but code using the old generated code will
+    /// no longer compile, e.g. because something was renamed.
+    Source,
+}
+
+/// A single incompatible change between two versions of a schema.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Incompatibility {
+    pub kind: IncompatibilityKind,
+
+    /// The location of the change, e.g. `foo.capnp:Foo.bar`.
+    pub path: String,
+
+    /// A description of the change.
+    pub description: String,
+}
+
+impl Incompatibility {
+    /// Returns true if this is a wire-incompatible change.
+    pub fn is_wire(&self) -> bool {
+        self.kind == IncompatibilityKind::Wire
+    }
+}
+
+impl std::fmt::Display for Incompatibility {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let kind = match self.kind {
+            IncompatibilityKind::Wire => "wire-incompatible",
+            IncompatibilityKind::Source => "source-incompatible",
+        };
+        write!(f, "{kind} change at {}: {}", self.path, self.description)
+    }
+}
+
+/// Compares two versions of a schema, given as `CodeGeneratorRequest`s, and returns
+/// all incompatible changes. Nodes that only exist in `new` are additions and are
+/// always compatible.
+pub fn check(
+    old: code_generator_request::Reader,
+    new: code_generator_request::Reader,
+) -> Result<Vec<Incompatibility>> {
+    let mut checker = Checker {
+        old_nodes: HashMap::new(),
+        new_nodes: HashMap::new(),
+        result: Vec::new(),
+    };
+    for node in old.get_nodes()? {
+        checker.old_nodes.insert(node.get_id(), node);
+    }
+    for node in new.get_nodes()? {
+        checker.new_nodes.insert(node.get_id(), node);
+    }
+
+    let mut old_ids: Vec<u64> = checker.old_nodes.keys().copied().collect();
+    old_ids.sort_unstable();
+    for id in old_ids {
+        checker.check_node(id)?;
+    }
+    Ok(checker.result)
+}
+
+/// Like [`check`], but reads the requests from files containing serialized
+/// (unpacked) `CodeGeneratorRequest` messages.
+pub fn check_files<P1, P2>(old: P1, new: P2) -> Result<Vec<Incompatibility>>
+where
+    P1: AsRef<Path>,
+    P2: AsRef<Path>,
+{
+    let options = *capnp::message::ReaderOptions::new().traversal_limit_in_words(None);
+    let old_message = capnp::serialize::read_message(
+        std::io::BufReader::new(std::fs::File::open(old).map_err(convert_io_err)?),
+        options,
+    )?;
+    let new_message = capnp::serialize::read_message(
+        std::io::BufReader::new(std::fs::File::open(new).map_err(convert_io_err)?),
+        options,
+    )?;
+    check(old_message.get_root()?, new_message.get_root()?)
+}
+
+struct Checker<'a> {
+    old_nodes: HashMap<u64, node::Reader<'a>>,
+    new_nodes: HashMap<u64, node::Reader<'a>>,
+    result: Vec<Incompatibility>,
+}
+
+impl<'a> Checker<'a> {
+    fn report(&mut self, kind: IncompatibilityKind, path: &str, description: String) {
+        self.result.push(Incompatibility {
+            kind,
+            path: path.to_string(),
+            description,
+        });
+    }
+
+    fn check_node(&mut self, id: u64) -> Result<()> {
+        let old = self.old_nodes[&id];
+        if let node::Struct(old_struct) = old.which()? {
+            // A group's ID is derived from its name, so groups are matched and checked
+            // through the fields of their parent instead.
+            if old_struct.get_is_group() {
+                return Ok(());
+            }
+        }
+        let path = old.get_display_name()?.to_string()?;
+        let Some(&new) = self.new_nodes.get(&id) else {
+            if !matches!(old.which()?, node::File(()) | node::Annotation(_)) {
+                self.report(
+                    IncompatibilityKind::Source,
+                    &path,
+                    "node was removed".to_string(),
+                );
+            }
+            return Ok(());
+        };
+
+        let new_path = rust_path(new)?;
+        if rust_path(old)? != new_path && !matches!(old.which()?, node::File(())) {
+            self.report(
+                IncompatibilityKind::Source,
+                &path,
+                format!("node was renamed or moved to {new_path}"),
+            );
+        }
+
+        match (old.which()?, new.which()?) {
+            (node::File(()), node::File(())) => {}
+            (node::Struct(old_struct), node::Struct(new_struct)) => {
+                self.check_struct(&path, old_struct, new_struct)?
+            }
+            (node::Enum(old_enum), node::Enum(new_enum)) => {
+                let old_enumerants = old_enum.get_enumerants()?;
+                let new_enumerants = new_enum.get_enumerants()?;
+                for (index, old_enumerant) in old_enumerants.iter().enumerate() {
+                    let name = old_enumerant.get_name()?.to_str()?;
+                    match new_enumerants.try_get(index as u32) {
+                        None => self.report(
+                            IncompatibilityKind::Wire,
+                            &path,
+                            format!("enumerant {name} @{index} was removed"),
+                        ),
+                        Some(new_enumerant) => {
+                            // Compare the names in the generated code, which `$Rust.name`
+                            // may override.
+                            let name = get_enumerant_name(old_enumerant)?;
+                            let new_name = get_enumerant_name(new_enumerant)?;
+                            if name != new_name {
+                                self.report(
+                                    IncompatibilityKind::Source,
+                                    &path,
+                                    format!(
+                                        "enumerant @{index} was renamed from {name} to {new_name}"
+                                    ),
+                                );
+                            }
+                        }
+                    }
+                }
+            }
+            (node::Interface(old_interface), node::Interface(new_interface)) => {
+                self.check_interface(&path, old_interface, new_interface)?
+            }
+            (node::Const(old_const), node::Const(new_const)) => {
+                let old_type = old_const.get_type()?;
+                let new_type = new_const.get_type()?;
+                if self.compare_types(old_type, new_type)? != TypeChange::None {
+                    self.report(
+                        IncompatibilityKind::Source,
+                        &path,
+                        format!(
+                            "type changed from {} to {}",
+                            self.old_type_name(old_type)?,
+                            self.new_type_name(new_type)?
+                        ),
+                    );
+                } else if !values_equal(old_const.get_value()?, new_const.get_value()?)? {
+                    self.report(
+                        IncompatibilityKind::Source,
+                        &path,
+                        "value changed".to_string(),
+                    );
+                }
+            }
+            (node::Annotation(_), node::Annotation(_)) => {}
+            _ => self.report(
+                IncompatibilityKind::Wire,
+                &path,
+                "kind of node changed".to_string(),
+            ),
+        }
+        Ok(())
+    }
+
+    fn check_struct(
+        &mut self,
+        path: &str,
+        old: node::struct_::Reader<'a>,
+        new: node::struct_::Reader<'a>,
+    ) -> Result<()> {
+        if old.get_is_group() != new.get_is_group() {
+            self.report(
+                IncompatibilityKind::Wire,
+                path,
+                "changed between group and struct".to_string(),
+            );
+            return Ok(());
+        }
+        // A group shares its parent's size, which is checked with the parent.
+        if !old.get_is_group()
+            && (new.get_data_word_count() < old.get_data_word_count()
+                || new.get_pointer_count() < old.get_pointer_count())
+        {
+            self.report(
+                IncompatibilityKind::Wire,
+                path,
+                "struct size decreased".to_string(),
+            );
+        }
+        if old.get_discriminant_count() > 0
+            && old.get_discriminant_offset() != new.get_discriminant_offset()
+        {
+            self.report(
+                IncompatibilityKind::Wire,
+                path,
+                "union discriminant moved".to_string(),
+            );
+        }
+
+        let new_fields = new.get_fields()?;
+        for old_field in old.get_fields()? {
+            let name = old_field.get_name()?.to_str()?;
+            let field_path = format!("{path}.{name}");
+            let new_field = match old_field.get_ordinal().which()? {
+                field::ordinal::Explicit(ordinal) => new_fields.iter().find(|f| {
+                    matches!(f.get_ordinal().which(), Ok(field::ordinal::Explicit(o)) if o == ordinal)
+                }),
+                // Groups do not have ordinals, and renaming a group changes its ID. Match
+                // them by the lowest ordinal among their members instead.
+                field::ordinal::Implicit(()) => {
+                    let ordinal = first_ordinal(&self.old_nodes, old_field)?;
+                    let mut found = None;
+                    for f in new_fields {
+                        if matches!(f.get_ordinal().which()?, field::ordinal::Implicit(()))
+                            && first_ordinal(&self.new_nodes, f)? == ordinal
+                        {
+                            found = Some(f);
+                            break;
+                        }
+                    }
+                    found
+                }
+            };
+            let Some(new_field) = new_field else {
+                self.report(
+                    IncompatibilityKind::Wire,
+                    &field_path,
+                    "field was removed".to_string(),
+                );
+                continue;
+            };
+
+            let new_name = get_field_name(new_field)?;
+            if get_field_name(old_field)? != new_name {
+                self.report(
+                    IncompatibilityKind::Source,
+                    &field_path,
+                    format!("field was renamed to {new_name}"),
+                );
+            }
+
+            let old_discriminant = old_field.get_discriminant_value();
+            let new_discriminant = new_field.get_discriminant_value();
+            if old_discriminant != new_discriminant {
+                let description = if old_discriminant == field::NO_DISCRIMINANT {
+                    "field was moved into a union".to_string()
+                } else if new_discriminant == field::NO_DISCRIMINANT {
+                    "field was moved out of a union".to_string()
+                } else {
+                    format!(
+                        "union discriminant changed from {old_discriminant} to {new_discriminant}"
+                    )
+                };
+                self.report(IncompatibilityKind::Wire, &field_path, description);
+            }
+
+            match (old_field.which()?, new_field.which()?) {
+                (field::Slot(old_slot), field::Slot(new_slot)) => {
+                    let old_type = old_slot.get_type()?;
+                    let new_type = new_slot.get_type()?;
+                    let change = self.compare_types(old_type, new_type)?;
+                    if change != TypeChange::None {
+                        let kind = if change == TypeChange::Source {
+                            IncompatibilityKind::Source
+                        } else {
+                            IncompatibilityKind::Wire
+                        };
+                        let description = format!(
+                            "type changed from {} to {}",
+                            self.old_type_name(old_type)?,
+                            self.new_type_name(new_type)?
+                        );
+                        self.report(kind, &field_path, description);
+                    }
+                    if change != TypeChange::Wire && old_slot.get_offset() != new_slot.get_offset()
+                    {
+                        self.report(
+                            IncompatibilityKind::Wire,
+                            &field_path,
+                            "field offset changed".to_string(),
+                        );
+                    }
+                    if change == TypeChange::None
+                        && !values_equal(
+                            old_slot.get_default_value()?,
+                            new_slot.get_default_value()?,
+                        )?
+                    {
+                        self.report(
+                            IncompatibilityKind::Wire,
+                            &field_path,
+                            "default value changed".to_string(),
+                        );
+                    }
+                }
+                (field::Group(old_group), field::Group(new_group)) => {
+                    let old_id = old_group.get_type_id();
+                    let new_id = new_group.get_type_id();
+                    match (self.old_nodes.get(&old_id), self.new_nodes.get(&new_id)) {
+                        (Some(old_node), Some(new_node)) => {
+                            match (old_node.which()?, new_node.which()?) {
+                                (node::Struct(old_struct), node::Struct(new_struct)) => {
+                                    self.check_struct(&field_path, old_struct, new_struct)?
+                                }
+                                _ => self.report(
+                                    IncompatibilityKind::Wire,
+                                    &field_path,
+                                    "group is not a struct".to_string(),
+                                ),
+                            }
+                        }
+                        _ => self.report(
+                            IncompatibilityKind::Wire,
+                            &field_path,
+                            "group node is missing".to_string(),
+                        ),
+                    }
+                }
+                _ => self.report(
+                    IncompatibilityKind::Wire,
+                    &field_path,
+                    "changed between group and non-group field".to_string(),
+                ),
+            }
+        }
+        Ok(())
+    }
+
+    fn check_interface(
+        &mut self,
+        path: &str,
+        old: node::interface::Reader<'a>,
+        new: node::interface::Reader<'a>,
+    ) -> Result<()> {
+        let new_superclasses = new.get_superclasses()?;
+        for superclass in old.get_superclasses()? {
+            let id = superclass.get_id();
+            if !new_superclasses.iter().any(|s| s.get_id() == id) {
+                self.report(
+                    IncompatibilityKind::Wire,
+                    path,
+                    format!("superclass {} was removed", self.old_node_name(id)?),
+                );
+            }
+        }
+
+        let new_methods = new.get_methods()?;
+        for (ordinal, old_method) in old.get_methods()?.iter().enumerate() {
+            let name = old_method.get_name()?.to_str()?;
+            let method_path = format!("{path}.{name}");
+            let Some(new_method) = new_methods.try_get(ordinal as u32) else {
+                self.report(
+                    IncompatibilityKind::Wire,
+                    &method_path,
+                    "method was removed".to_string(),
+                );
+                continue;
+            };
+            let new_name = new_method.get_name()?.to_str()?;
+            if name != new_name {
+                self.report(
+                    IncompatibilityKind::Source,
+                    &method_path,
+                    format!("method was renamed to {new_name}"),
+                );
+            }
+            if old_method.get_param_struct_type() != new_method.get_param_struct_type() {
+                self.report(
+                    IncompatibilityKind::Wire,
+                    &method_path,
+                    "parameter type changed".to_string(),
+                );
+            }
+            if old_method.get_result_struct_type() != new_method.get_result_struct_type() {
+                self.report(
+                    IncompatibilityKind::Wire,
+                    &method_path,
+                    "result type changed".to_string(),
+                );
+            }
+        }
+        Ok(())
+    }
+
+    fn compare_types(&self, old: type_::Reader, new: type_::Reader) -> Result<TypeChange> {
+        let change = match (old.which()?, new.which()?) {
+            (type_::Void(()), type_::Void(()))
+            | (type_::Bool(()), type_::Bool(()))
+            | (type_::Int8(()), type_::Int8(()))
+            | (type_::Int16(()), type_::Int16(()))
+            | (type_::Int32(()), type_::Int32(()))
+            | (type_::Int64(()), type_::Int64(()))
+            | (type_::Uint8(()), type_::Uint8(()))
+            | (type_::Uint16(()), type_::Uint16(()))
+            | (type_::Uint32(()), type_::Uint32(()))
+            | (type_::Uint64(()), type_::Uint64(()))
+            | (type_::Float32(()), type_::Float32(()))
+            | (type_::Float64(()), type_::Float64(()))
+            | (type_::Text(()), type_::Text(()))
+            | (type_::Data(()), type_::Data(())) => TypeChange::None,
+            (type_::List(old_list), type_::List(new_list)) => {
+                self.compare_types(old_list.get_element_type()?, new_list.get_element_type()?)?
+            }
+            (type_::Enum(old_enum), type_::Enum(new_enum)) => {
+                same_id(old_enum.get_type_id(), new_enum.get_type_id())
+            }
+            (type_::Struct(old_struct), type_::Struct(new_struct)) => {
+                same_id(old_struct.get_type_id(), new_struct.get_type_id())
+            }
+            (type_::Interface(old_interface), type_::Interface(new_interface)) => {
+                same_id(old_interface.get_type_id(), new_interface.get_type_id())
+            }
+            (type_::AnyPointer(_), type_::AnyPointer(_)) => TypeChange::None,
+            // Replacing an AnyPointer with a specific pointer type, or vice versa,
+            // keeps the encoding but changes the generated accessors.
+            (type_::AnyPointer(_), _) | (_, type_::AnyPointer(_))
+                if old.is_pointer()? && new.is_pointer()? =>
+            {
+                TypeChange::Source
+            }
+            _ => TypeChange::Wire,
+        };
+        Ok(change)
+    }
+
+    fn old_node_name(&self, id: u64) -> Result<String> {
+        node_name(&self.old_nodes, id)
+    }
+
+    fn old_type_name(&self, ty: type_::Reader) -> Result<String> {
+        type_name(&self.old_nodes, ty)
+    }
+
+    fn new_type_name(&self, ty: type_::Reader) -> Result<String> {
+        type_name(&self.new_nodes, ty)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum TypeChange {
+    None,
+    Source,
+    Wire,
+}
+
+fn same_id(old: u64, new: u64) -> TypeChange {
+    if old == new {
+        TypeChange::None
+    } else {
+        TypeChange::Wire
+    }
+}
+
+// Returns the display name of `node`, with its last component replaced by its `$Rust.name`
+// annotation, if it has one.
+fn rust_path(node: node::Reader) -> Result<String> {
+    let path = node.get_display_name()?.to_str()?;
+    for annotation in node.get_annotations()? {
+        if annotation.get_id() == NAME_ANNOTATION_ID {
+            let prefix_len = node.get_display_name_prefix_length() as usize;
+            return Ok(format!(
+                "{}{}",
+                &path[..prefix_len],
+                name_annotation_value(annotation)?
+            ));
+        }
+    }
+    Ok(path.to_string())
+}
+
+// Returns the lowest ordinal of `field`, or of the members of `field` if it is a group.
+fn first_ordinal(nodes: &HashMap<u64, node::Reader>, field: field::Reader) -> Result<Option<u16>> {
+    if let field::ordinal::Explicit(ordinal) = field.get_ordinal().which()? {
+        return Ok(Some(ordinal));
+    }
+    let field::Group(group) = field.which()? else {
+        return Ok(None);
+    };
+    let Some(node) = nodes.get(&group.get_type_id()) else {
+        return Ok(None);
+    };
+    let node::Struct(struct_) = node.which()? else {
+        return Ok(None);
+    };
+    let mut first = None;
+    for member in struct_.get_fields()? {
+        if let Some(ordinal) = first_ordinal(nodes, member)? {
+            first = Some(first.map_or(ordinal, |f: u16| f.min(ordinal)));
+        }
+    }
+    Ok(first)
+}
+
+fn node_name(nodes: &HashMap<u64, node::Reader>, id: u64) -> Result<String> {
+    match nodes.get(&id) {
+        Some(node) => Ok(node.get_display_name()?.to_string()?),
+        None => Ok(format!("@0x{id:x}")),
+    }
+}
+
+fn type_name(nodes: &HashMap<u64, node::Reader>, ty: type_::Reader) -> Result<String> {
+    let name = match ty.which()? {
+        type_::Void(()) => "Void".to_string(),
+        type_::Bool(()) => "Bool".to_string(),
+        type_::Int8(()) => "Int8".to_string(),
+        type_::Int16(()) => "Int16".to_string(),
+        type_::Int32(()) => "Int32".to_string(),
+        type_::Int64(()) => "Int64".to_string(),
+        type_::Uint8(()) => "UInt8".to_string(),
+        type_::Uint16(()) => "UInt16".to_string(),
+        type_::Uint32(()) => "UInt32".to_string(),
+        type_::Uint64(()) => "UInt64".to_string(),
+        type_::Float32(()) => "Float32".to_string(),
+        type_::Float64(()) => "Float64".to_string(),
+        type_::Text(()) => "Text".to_string(),
+        type_::Data(()) => "Data".to_string(),
+        type_::List(l) => format!("List({})", type_name(nodes, l.get_element_type()?)?),
+        type_::Enum(e) => node_name(nodes, e.get_type_id())?,
+        type_::Struct(s) => node_name(nodes, s.get_type_id())?,
+        type_::Interface(i) => node_name(nodes, i.get_type_id())?,
+        type_::AnyPointer(_) => "AnyPointer".to_string(),
+    };
+    Ok(name)
+}
+
+fn values_equal(old: value::Reader, new: value::Reader) -> Result<bool> {
+    let equal = match (old.which()?, new.which()?) {
+        (value::Void(()), value::Void(())) => true,
+        (value::Bool(a), value::Bool(b)) => a == b,
+        (value::Int8(a), value::Int8(b)) => a == b,
+        (value::Int16(a), value::Int16(b)) => a == b,
+        (value::Int32(a), value::Int32(b)) => a == b,
+        (value::Int64(a), value::Int64(b)) => a == b,
+        (value::Uint8(a), value::Uint8(b)) => a == b,
+        (value::Uint16(a), value::Uint16(b)) => a == b,
+        (value::Uint32(a), value::Uint32(b)) => a == b,
+        (value::Uint64(a), value::Uint64(b)) => a == b,
+        (value::Float32(a), value::Float32(b)) => a.to_bits() == b.to_bits(),
+        (value::Float64(a), value::Float64(b)) => a.to_bits() == b.to_bits(),
+        (value::Enum(a), value::Enum(b)) => a == b,
+        (value::Interface(()), value::Interface(())) => true,
+        (value::Text(_), value::Text(_))
+        | (value::Data(_), value::Data(_))
+        | (value::List(_), value::List(_))
+        | (value::Struct(_), value::Struct(_))
+        | (value::AnyPointer(_), value::AnyPointer(_)) => {
+            canonical_words(old)? == canonical_words(new)?
+        }
+        _ => false,
+    };
+    Ok(equal)
+}
+
+// Returns the canonical encoding of a pointer-typed value.
+fn canonical_words(value: value::Reader) -> Result<Vec<u8>> {
+    let pointer = capnp::raw::get_struct_pointer_section(value).get(0);
+    let mut message = capnp::message::Builder::new_default();
+    message.set_root_canonical(pointer)?;
+    Ok(message.get_segments_for_output()[0].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check, IncompatibilityKind, NAME_ANNOTATION_ID};
+    use capnp::message;
+    use capnp::schema_capnp::{annotation, code_generator_request, field, node};
+    use capnp::struct_list;
+
+    // Builds a request containing a single struct `test.capnp:Foo` with one
+    // UInt32 field per `(name, ordinal, offset)` entry.
+    fn struct_request(fields: &[(&str, u16, u32)]) -> message::Builder<message::HeapAllocator> {
+        request(1, |mut nodes| {
+            let mut node = nodes.reborrow().get(0);
+            node.set_id(0xabcd);
+            node.set_display_name("test.capnp:Foo");
+            let mut struct_ = node.init_struct();
+            struct_.set_data_word_count(1);
+            let mut field_list = struct_.init_fields(fields.len() as u32);
+            for (index, &(name, ordinal, offset)) in fields.iter().enumerate() {
+                set_uint32_field(
+                    field_list.reborrow().get(index as u32),
+                    name,
+                    ordinal,
+                    offset,
+                    field::NO_DISCRIMINANT,
+                );
+            }
+        })
+    }
+
+    // Builds a request from `node_count` nodes filled in by `build`.
+    fn request(
+        node_count: u32,
+        build: impl FnOnce(struct_list::Builder<node::Owned>),
+    ) -> message::Builder<message::HeapAllocator> {
+        let mut message = message::Builder::new_default();
+        build(
+            message
+                .init_root::<code_generator_request::Builder>()
+                .init_nodes(node_count),
+        );
+        message
+    }
+
+    fn set_uint32_field(
+        mut field: field::Builder,
+        name: &str,
+        ordinal: u16,
+        offset: u32,
+        discriminant: u16,
+    ) {
+        field.set_name(name);
+        field.set_discriminant_value(discriminant);
+        field.reborrow().init_ordinal().set_explicit(ordinal);
+        let mut slot = field.init_slot();
+        slot.set_offset(offset);
+        slot.reborrow().init_type().set_uint32(());
+        slot.init_default_value().set_uint32(0);
+    }
+
+    fn set_name_annotation(mut annotation: annotation::Builder, name: &str) {
+        annotation.set_id(NAME_ANNOTATION_ID);
+        annotation.init_value().set_text(name);
+    }
+
+    fn kinds(
+        old: &message::Builder<message::HeapAllocator>,
+        new: &message::Builder<message::HeapAllocator>,
+    ) -> Vec<(IncompatibilityKind, String)> {
+        check(
+            old.get_root_as_reader().unwrap(),
+            new.get_root_as_reader().unwrap(),
+        )
+        .unwrap()
+        .into_iter()
+        .map(|i| (i.kind, i.path))
+        .collect()
+    }
+
+    #[test]
+    fn identical() {
+        let old = struct_request(&[("a", 0, 0), ("b", 1, 1)]);
+        assert!(kinds(&old, &old).is_empty());
+    }
+
+    #[test]
+    fn added_field() {
+        let old = struct_request(&[("a", 0, 0)]);
+        let new = struct_request(&[("a", 0, 0), ("b", 1, 1)]);
+        assert!(kinds(&old, &new).is_empty());
+    }
+
+    #[test]
+    fn renamed_field() {
+        let old = struct_request(&[("a", 0, 0)]);
+        let new = struct_request(&[("c", 0, 0)]);
+        assert_eq!(
+            kinds(&old, &new),
+            vec![(IncompatibilityKind::Source, "test.capnp:Foo.a".to_string())]
+        );
+    }
+
+    #[test]
+    fn removed_and_moved_fields() {
+        let old = struct_request(&[("a", 0, 0), ("b", 1, 1)]);
+        let new = struct_request(&[("b", 1, 0)]);
+        assert_eq!(
+            kinds(&old, &new),
+            vec![
+                (IncompatibilityKind::Wire, "test.capnp:Foo.a".to_string()),
+                (IncompatibilityKind::Wire, "test.capnp:Foo.b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn changed_type_and_default() {
+        let old = struct_request(&[("a", 0, 0), ("b", 1, 1)]);
+        let mut new = struct_request(&[("a", 0, 0), ("b", 1, 1)]);
+        {
+            let request = new.get_root::<code_generator_request::Builder>().unwrap();
+            let node = request.get_nodes().unwrap().get(0);
+            let node::Struct(struct_) = node.which().unwrap() else {
+                panic!()
+            };
+            let mut fields = struct_.get_fields().unwrap();
+            let field::Slot(slot) = fields.reborrow().get(0).which().unwrap() else {
+                panic!()
+            };
+            slot.get_type().unwrap().set_int32(());
+            let field::Slot(slot) = fields.get(1).which().unwrap() else {
+                panic!()
+            };
+            slot.get_default_value().unwrap().set_uint32(7);
+        }
+        let result = check(
+            old.get_root_as_reader().unwrap(),
+            new.get_root_as_reader().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(result.len(), 2);
+        assert!(result.iter().all(|i| i.is_wire()));
+        assert_eq!(
+            result[0].description,
+            "type changed from UInt32 to Int32".to_string()
+        );
+        assert_eq!(result[1].description, "default value changed".to_string());
+    }
+
+    #[test]
+    fn source_change_keeps_offset_check() {
+        let pointer_request = |any_pointer: bool, offset: u32| {
+            request(1, |mut nodes| {
+                let mut node = nodes.reborrow().get(0);
+                node.set_id(0xabcd);
+                node.set_display_name("test.capnp:Foo");
+                let mut struct_ = node.init_struct();
+                struct_.set_pointer_count(2);
+                let mut field = struct_.init_fields(1).get(0);
+                field.set_name("p");
+                field.set_discriminant_value(field::NO_DISCRIMINANT);
+                field.reborrow().init_ordinal().set_explicit(0);
+                let mut slot = field.init_slot();
+                slot.set_offset(offset);
+                let mut type_ = slot.reborrow().init_type();
+                if any_pointer {
+                    type_
+                        .init_any_pointer()
+                        .init_unconstrained()
+                        .set_any_kind(());
+                } else {
+                    type_.set_text(());
+                }
+                slot.init_default_value();
+            })
+        };
+        let old = pointer_request(true, 0);
+        assert_eq!(
+            kinds(&old, &pointer_request(false, 0)),
+            vec![(IncompatibilityKind::Source, "test.capnp:Foo.p".to_string())]
+        );
+        assert_eq!(
+            kinds(&old, &pointer_request(false, 1)),
+            vec![
+                (IncompatibilityKind::Source, "test.capnp:Foo.p".to_string()),
+                (IncompatibilityKind::Wire, "test.capnp:Foo.p".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn union_changes() {
+        let union_request = |discriminants: [u16; 2], discriminant_offset: u32| {
+            request(1, |mut nodes| {
+                let mut node = nodes.reborrow().get(0);
+                node.set_id(0xabcd);
+                node.set_display_name("test.capnp:Foo");
+                let mut struct_ = node.init_struct();
+                struct_.set_data_word_count(1);
+                struct_.set_discriminant_count(2);
+                struct_.set_discriminant_offset(discriminant_offset);
+                let mut fields = struct_.init_fields(2);
+                set_uint32_field(fields.reborrow().get(0), "a", 0, 0, discriminants[0]);
+                set_uint32_field(fields.get(1), "b", 1, 0, discriminants[1]);
+            })
+        };
+        let old = union_request([0, 1], 2);
+        assert!(kinds(&old, &union_request([0, 1], 2)).is_empty());
+        assert_eq!(
+            kinds(&old, &union_request([1, 0], 2)),
+            vec![
+                (IncompatibilityKind::Wire, "test.capnp:Foo.a".to_string()),
+                (IncompatibilityKind::Wire, "test.capnp:Foo.b".to_string()),
+            ]
+        );
+        assert_eq!(
+            kinds(&old, &union_request([0, field::NO_DISCRIMINANT], 3)),
+            vec![
+                (IncompatibilityKind::Wire, "test.capnp:Foo".to_string()),
+                (IncompatibilityKind::Wire, "test.capnp:Foo.b".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn enumerant_changes() {
+        let enum_request = |names: &[&str]| {
+            request(1, |mut nodes| {
+                let mut node = nodes.reborrow().get(0);
+                node.set_id(0xabcd);
+                node.set_display_name("test.capnp:E");
+                let mut enumerants = node.init_enum().init_enumerants(names.len() as u32);
+                for (index, name) in names.iter().enumerate() {
+                    let mut enumerant = enumerants.reborrow().get(index as u32);
+                    enumerant.set_name(name);
+                    enumerant.set_code_order(index as u16);
+                }
+            })
+        };
+        let old = enum_request(&["x", "y", "z"]);
+        assert!(kinds(&old, &enum_request(&["x", "y", "z", "w"])).is_empty());
+        let result = check(
+            old.get_root_as_reader().unwrap(),
+            enum_request(&["x", "w"]).get_root_as_reader().unwrap(),
+        )
+        .unwrap();
+        assert_eq!(result.len(), 2);
+        assert_eq!(result[0].kind, IncompatibilityKind::Source);
+        assert_eq!(
+            result[0].description,
+            "enumerant @1 was renamed from y to w"
+        );
+        assert_eq!(result[1].kind, IncompatibilityKind::Wire);
+        assert_eq!(result[1].description, "enumerant z @2 was removed");
+    }
+
+    #[test]
+    fn interface_method_changes() {
+        let interface_request = |methods: &[(&str, u64)]| {
+            request(1, |mut nodes| {
+                let mut node = nodes.reborrow().get(0);
+                node.set_id(0xabcd);
+                node.set_display_name("test.capnp:I");
+                let mut method_list = node.init_interface().init_methods(methods.len() as u32);
+                for (index, &(name, param_type)) in methods.iter().enumerate() {
+                    let mut method = method_list.reborrow().get(index as u32);
+                    method.set_name(name);
+                    method.set_code_order(index as u16);
+                    method.set_param_struct_type(param_type);
+                    method.set_result_struct_type(0x100);
+                }
+            })
+        };
+        let old = interface_request(&[("foo", 1), ("bar", 2), ("baz", 3)]);
+        assert!(kinds(
+            &old,
+            &interface_request(&[("foo", 1), ("bar", 2), ("baz", 3)])
+        )
+        .is_empty());
+        assert_eq!(
+            kinds(&old, &interface_request(&[("foo", 4), ("qux", 2)])),
+            vec![
+                (IncompatibilityKind::Wire, "test.capnp:I.foo".to_string()),
+                (IncompatibilityKind::Source, "test.capnp:I.bar".to_string()),
+                (IncompatibilityKind::Wire, "test.capnp:I.baz".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn renamed_group() {
+        // `Foo` has a field `a @0` and a group holding `b @1` and `c @2`. The ID of the
+        // group node is derived from its name, as the schema compiler does.
+        let group_request = |group_name: &str, b_offset: u32| {
+            let group_id = if group_name == "grp" { 0x1234 } else { 0x5678 };
+            request(2, |mut nodes| {
+                let mut node = nodes.reborrow().get(0);
+                node.set_id(0xabcd);
+                node.set_display_name("test.capnp:Foo");
+                let mut struct_ = node.init_struct();
+                struct_.set_data_word_count(2);
+                let mut fields = struct_.init_fields(2);
+                set_uint32_field(fields.reborrow().get(0), "a", 0, 0, field::NO_DISCRIMINANT);
+                let mut group_field = fields.get(1);
+                group_field.set_name(group_name);
+                group_field.set_discriminant_value(field::NO_DISCRIMINANT);
+                group_field.reborrow().init_ordinal().set_implicit(());
+                group_field.init_group().set_type_id(group_id);
+
+                let mut node = nodes.get(1);
+                node.set_id(group_id);
+                node.set_display_name(format!("test.capnp:Foo.{group_name}"));
+                node.set_scope_id(0xabcd);
+                let mut struct_ = node.init_struct();
+                struct_.set_data_word_count(2);
+                struct_.set_is_group(true);
+                let mut fields = struct_.init_fields(2);
+                set_uint32_field(
+                    fields.reborrow().get(0),
+                    "b",
+                    1,
+                    b_offset,
+                    field::NO_DISCRIMINANT,
+                );
+                set_uint32_field(fields.get(1), "c", 2, 2, field::NO_DISCRIMINANT);
+            })
+        };
+        let old = group_request("grp", 1);
+        assert!(kinds(&old, &group_request("grp", 1)).is_empty());
+        assert_eq!(
+            kinds(&old, &group_request("renamed", 1)),
+            vec![(
+                IncompatibilityKind::Source,
+                "test.capnp:Foo.grp".to_string()
+            )]
+        );
+        assert_eq!(
+            kinds(&old, &group_request("renamed", 3)),
+            vec![
+                (
+                    IncompatibilityKind::Source,
+                    "test.capnp:Foo.grp".to_string()
+                ),
+                (
+                    IncompatibilityKind::Wire,
+                    "test.capnp:Foo.grp.b".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn rust_name_changes() {
+        let named_request = |struct_name: Option<&str>, field_name: Option<&str>| {
+            let mut message = struct_request(&[("a", 0, 0)]);
+            {
+                let request = message
+                    .get_root::<code_generator_request::Builder>()
+                    .unwrap();
+                let mut node = request.get_nodes().unwrap().get(0);
+                node.set_display_name_prefix_length("test.capnp:".len() as u32);
+                if let Some(name) = struct_name {
+                    set_name_annotation(node.reborrow().init_annotations(1).get(0), name);
+                }
+                let node::Struct(struct_) = node.which().unwrap() else {
+                    panic!()
+                };
+                if let Some(name) = field_name {
+                    let field = struct_.get_fields().unwrap().get(0);
+                    set_name_annotation(field.init_annotations(1).get(0), name);
+                }
+            }
+            message
+        };
+        let old = named_request(None, None);
+        assert!(kinds(&old, &named_request(None, Some("a"))).is_empty());
+        assert!(kinds(&old, &named_request(Some("Foo"), None)).is_empty());
+        assert_eq!(
+            kinds(&old, &named_request(Some("Bar"), Some("b"))),
+            vec![
+                (IncompatibilityKind::Source, "test.capnp:Foo".to_string()),
+                (IncompatibilityKind::Source, "test.capnp:Foo.a".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn removed_node() {
+        let old = struct_request(&[("a", 0, 0)]);
+        let mut new = message::Builder::new_default();
+        new.init_root::<code_generator_request::Builder>()
+            .init_nodes(0);
+        assert_eq!(
+            kinds(&old, &new),
+            vec![(IncompatibilityKind::Source, "test.capnp:Foo".to_string())]
+        );
+    }
+}
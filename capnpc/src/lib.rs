@@ -64,6 +64,7 @@
 
 pub mod codegen;
 pub mod codegen_types;
+pub mod compat;
 mod pointer_constants;
 
 use std::{
@@ -21,7 +21,7 @@
 use capnp::{any_pointer, message};
 
 use crate::codegen::FormattedText::{Branch, Indent, Line};
-use crate::codegen::{fmt, indent, line, FormattedText, GeneratorContext};
+use crate::codegen::{camel_to_snake_case, fmt, indent, line, FormattedText, GeneratorContext};
 use crate::codegen_types::{Leaf, RustTypeInfo};
 use capnp::schema_capnp::type_;
 
@@ -95,14 +95,19 @@ pub(crate) fn node_word_array_declaration(
 
 pub(crate) fn generate_pointer_constant(
     ctx: &GeneratorContext,
-    styled_name: &str,
+    vis: &str,
+    attributes: &[FormattedText],
+    name: &str,
     typ: type_::Reader,
     value: any_pointer::Reader,
 ) -> ::capnp::Result<FormattedText> {
+    let styled_name = name.to_ascii_uppercase();
     Ok(Branch(vec![
+        Branch(attributes.to_vec()),
         Line(fmt!(
             ctx,
-            "pub static {}: {capnp}::constant::Reader<{}> = {{",
+            "{} static {}: {capnp}::constant::Reader<{}> = {{",
+            vis,
             styled_name,
             typ.type_string(ctx, Leaf::Owned)?
         )),
@@ -123,5 +128,15 @@ pub(crate) fn generate_pointer_constant(
             )),
         ]))),
         line("};"),
+        Branch(attributes.to_vec()),
+        Line(fmt!(
+            ctx,
+            "{} fn get_{}() -> {capnp}::Result<{}> {{",
+            vis,
+            camel_to_snake_case(name),
+            typ.type_string(ctx, Leaf::Reader("'static"))?
+        )),
+        indent(Line(format!("{styled_name}.get()"))),
+        line("}"),
     ]))
 }
@@ -50,6 +50,49 @@ fn test_defaults_builder() {
     test_util::dynamic_check_test_message_builder(root.downcast());
 }
 
+#[test]
+fn test_field_default_values() {
+    use crate::test_capnp::test_defaults;
+    use capnp::introspect::{Introspect, TypeVariant};
+
+    let TypeVariant::Struct(raw) = test_defaults::Owned::introspect().which() else {
+        panic!("expected a struct schema");
+    };
+    let schema = capnp::schema::StructSchema::new(raw);
+
+    let int32_default = schema
+        .get_field_by_name("int32Field")
+        .unwrap()
+        .get_default_value()
+        .unwrap();
+    assert_eq!(int32_default.downcast::<i32>(), -12345678);
+
+    let text_default = schema
+        .get_field_by_name("textField")
+        .unwrap()
+        .get_default_value()
+        .unwrap();
+    assert_eq!(text_default.downcast::<capnp::text::Reader<'_>>(), "foo");
+
+    let struct_default: test_all_types::Reader<'_> = schema
+        .get_field_by_name("structField")
+        .unwrap()
+        .get_default_value()
+        .unwrap()
+        .downcast_struct::<test_all_types::Owned>();
+    assert_eq!(struct_default.get_int8_field(), -12);
+    assert_eq!(struct_default.get_text_field().unwrap(), "baz");
+
+    let list_default: dynamic_list::Reader<'_> = schema
+        .get_field_by_name("int16List")
+        .unwrap()
+        .get_default_value()
+        .unwrap()
+        .downcast();
+    assert_eq!(list_default.len(), 2);
+    assert_eq!(list_default.get(0).unwrap().downcast::<i16>(), 11111);
+}
+
 #[test]
 fn test_unions() {
     use crate::test_capnp::test_union;
@@ -818,9 +818,142 @@ mod tests {
         assert!(unset_reader.get_any().is_none());
         assert!(set_reader.get_any().is_some());
 
+        assert!(unset_reader.get_iface()?.is_none());
+
+        Ok(())
+    }
+
+    // A capability that can be stored in a message but not called.
+    struct StubCap(std::rc::Rc<()>);
+
+    impl capnp::private::capability::ClientHook for StubCap {
+        fn add_ref(&self) -> Box<dyn capnp::private::capability::ClientHook> {
+            Box::new(StubCap(self.0.clone()))
+        }
+        fn new_call(
+            &self,
+            _interface_id: u64,
+            _method_id: u16,
+            _size_hint: Option<capnp::MessageSize>,
+        ) -> capnp::capability::Request<capnp::any_pointer::Owned, capnp::any_pointer::Owned>
+        {
+            unimplemented!()
+        }
+        fn call(
+            &self,
+            _interface_id: u64,
+            _method_id: u16,
+            _params: Box<dyn capnp::private::capability::ParamsHook>,
+            _results: Box<dyn capnp::private::capability::ResultsHook>,
+        ) -> capnp::capability::Promise<(), capnp::Error> {
+            unimplemented!()
+        }
+        fn get_brand(&self) -> usize {
+            0
+        }
+        fn get_ptr(&self) -> usize {
+            std::rc::Rc::as_ptr(&self.0) as usize
+        }
+        fn get_resolved(&self) -> Option<Box<dyn capnp::private::capability::ClientHook>> {
+            None
+        }
+        fn when_more_resolved(
+            &self,
+        ) -> Option<
+            capnp::capability::Promise<
+                Box<dyn capnp::private::capability::ClientHook>,
+                capnp::Error,
+            >,
+        > {
+            None
+        }
+        fn when_resolved(&self) -> capnp::capability::Promise<(), capnp::Error> {
+            capnp::capability::Promise::ok(())
+        }
+    }
+
+    #[test]
+    fn test_field_get_option_interface() -> capnp::Result<()> {
+        use crate::test_capnp::{test_field_get_option, test_interface};
+        use capnp::capability::FromClientHook;
+        use capnp::traits::ImbueMut;
+
+        let cap = StubCap(std::rc::Rc::new(()));
+        let cap_ptr = capnp::private::capability::ClientHook::get_ptr(&cap);
+
+        let mut message = message::Builder::new_default();
+        let mut cap_table = Vec::new();
+        let mut root = message.init_root::<test_field_get_option::Builder<'_>>();
+        root.imbue_mut(&mut cap_table);
+
+        assert!(root.reborrow().get_iface()?.is_none());
+        root.set_iface(test_interface::Client::new(Box::new(cap)));
+
+        let client = root.reborrow().get_iface()?.expect("is some");
+        assert_eq!(client.client.hook.get_ptr(), cap_ptr);
+        let client = root.reborrow_as_reader().get_iface()?.expect("is some");
+        assert_eq!(client.client.hook.get_ptr(), cap_ptr);
+
+        // Clearing the pointer makes the getters return `None` again.
+        let dynamic: capnp::dynamic_value::Builder<'_> = root.reborrow().into();
+        dynamic
+            .downcast::<capnp::dynamic_struct::Builder<'_>>()
+            .clear_named("iface")?;
+        assert!(root.reborrow().get_iface()?.is_none());
+        assert!(root.into_reader().get_iface()?.is_none());
+
         Ok(())
     }
 
+    #[test]
+    fn test_field_presence() -> capnp::Result<()> {
+        use crate::test_capnp::{test_field_presence, TestEnum};
+
+        let mut message = message::Builder::new_default();
+        let mut root = message.init_root::<test_field_presence::Builder<'_>>();
+
+        assert_eq!(root.reborrow().get_count().get_value(), None);
+        assert_eq!(root.reborrow().get_color().get_some(), Ok(None));
+
+        root.reborrow().get_count().set_value(17);
+        root.reborrow().get_color().set_some(TestEnum::Garply);
+        assert_eq!(root.reborrow().get_count().get_value(), Some(17));
+        assert_eq!(
+            root.reborrow().get_color().get_some(),
+            Ok(Some(TestEnum::Garply))
+        );
+
+        {
+            let reader = root.reborrow_as_reader();
+            assert_eq!(reader.get_count().get_value(), Some(17));
+            assert_eq!(reader.get_color().get_some(), Ok(Some(TestEnum::Garply)));
+        }
+
+        root.reborrow().get_count().set_unset(());
+        assert_eq!(root.reborrow_as_reader().get_count().get_value(), None);
+
+        Ok(())
+    }
+
+    #[test]
+    #[allow(deprecated)]
+    fn test_rust_attributes() {
+        use crate::test_capnp::{test_rust_attributes, TestDerivedEnum};
+
+        let mut message = message::Builder::new_default();
+        let mut root = message.init_root::<test_rust_attributes::Builder<'_>>();
+        root.set_old_field(3);
+        root.set_new_field(4);
+        let reader = root.into_reader();
+        assert_eq!(reader.get_old_field(), 3);
+        assert_eq!(reader.get_new_field(), 4);
+
+        let mut set = std::collections::HashSet::new();
+        set.insert(TestDerivedEnum::Foo);
+        assert!(set.contains(&TestDerivedEnum::Foo));
+        assert!(TestDerivedEnum::Foo < TestDerivedEnum::Bar);
+    }
+
     #[test]
     fn test_generic_one_parameter() {
         use crate::test_capnp::brand_once;
@@ -1201,6 +1334,25 @@ mod tests {
         assert_eq!(struct_list.get(2).get_text_field().unwrap(), "structlist 3");
     }
 
+    #[test]
+    fn test_pointer_constant_accessors() {
+        use crate::test_capnp::test_constants;
+
+        let struct_const = test_constants::get_struct_const().unwrap();
+        assert_eq!(struct_const.get_int8_field(), -12);
+        assert_eq!(struct_const.get_text_field().unwrap(), "baz");
+
+        let int32_list = test_constants::get_int32_list_const().unwrap();
+        assert_eq!(int32_list.len(), 2);
+        assert_eq!(int32_list.get(0), 111111111);
+
+        let data_list = test_constants::get_data_list_const().unwrap();
+        assert_eq!(data_list.get(1).unwrap(), b"exhausted");
+
+        let struct_list = test_constants::get_struct_list_const().unwrap();
+        assert_eq!(struct_list.get(2).get_text_field().unwrap(), "structlist 3");
+    }
+
     #[test]
     fn test_float_constants() {
         use crate::test_capnp::test_float_consts;
@@ -2006,6 +2158,19 @@ mod tests {
         let _ = foo.get_any_pointer_field();
     }
 
+    // Field attributes also apply to pipeline getters, so each of these calls must warn.
+    #[allow(unused)]
+    #[expect(deprecated)]
+    fn pipeline_deprecated_struct(foo: crate::test_capnp::test_rust_attributes::Pipeline) {
+        let _ = foo.get_old_struct();
+    }
+
+    #[allow(unused)]
+    #[expect(deprecated)]
+    fn pipeline_deprecated_cap(foo: crate::test_capnp::test_rust_attributes::Pipeline) {
+        let _ = foo.get_old_cap();
+    }
+
     #[test]
     fn set_with_caveats() {
         use crate::test_capnp::test_all_types;